@@ -0,0 +1,78 @@
+//! Entry points for benchmarks, behind the `bench-support` feature. Programs
+//! are parsed, compiled and evaluated without any I/O, so only the
+//! interpreter is measured.
+
+use std::fmt;
+
+use crate::compiler::compile_program;
+use crate::error::RuntimeError;
+use crate::interpreter::Expr;
+use crate::parser::error::Error;
+
+/// Arithmetic on Church numerals: (3 + 4) * 5.
+pub const CHURCH_ARITHMETIC: &str = "\
+Plus = \\m. \\n. \\f. \\x. m f (n f x)
+Mult = \\m. \\n. \\f. m (n f)
+Mult (Plus 3 4) 5
+";
+
+/// Exponentiation of Church numerals: 2 ^ 8.
+pub const CHURCH_POWER: &str = "\
+Exp = \\b. \\e. e b
+Exp 2 8
+";
+
+/// Sums a Church encoded list by folding over it.
+pub const LIST_FOLD: &str = "\
+Nil  = \\c. \\n. n
+Cons = \\h. \\t. \\c. \\n. c h (t c n)
+Plus = \\m. \\n. \\f. \\x. m f (n f x)
+Sum  = \\l. l Plus 0
+Sum (Cons 1 (Cons 2 (Cons 3 (Cons 4 Nil))))
+";
+
+/// Every canonical program, by name.
+pub const PROGRAMS: &[(&str, &str)] = &[
+    ("church_arithmetic", CHURCH_ARITHMETIC),
+    ("church_power", CHURCH_POWER),
+    ("list_fold", LIST_FOLD),
+];
+
+#[derive(Debug)]
+pub enum RunError {
+    Compile(Error),
+    Runtime(RuntimeError),
+}
+
+impl fmt::Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RunError::Compile(err) => write!(f, "{}", err),
+            RunError::Runtime(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+/// Compiles and evaluates a whole program, returning its result.
+pub fn run_program(source: &str) -> Result<Expr, RunError> {
+    let mut executable = compile_program(source).map_err(RunError::Compile)?;
+    executable.normalize = true;
+    executable.eval().map_err(RunError::Runtime)?;
+    Ok(executable.expr)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_programs_evaluate() {
+        let expected = [35, 256, 10];
+        for (&(name, source), &value) in PROGRAMS.iter().zip(expected.iter()) {
+            match run_program(source) {
+                Ok(expr) => assert_eq!(expr.as_church_numeral(), Some(value), "{}", name),
+                Err(err) => panic!("{} failed: {}", name, err),
+            }
+        }
+    }
+}
@@ -1,51 +1,139 @@
-use std::collections::{ HashMap, HashSet, VecDeque };
-use std::rc::Rc;
+use std::collections::{ HashMap, HashSet };
 
+use crate::shared::Rc;
 use crate::span::Span;
 use crate::parser;
-use crate::parser::{ Result, Parser };
+use crate::parser::{ Result, Parser, Spanned };
 use crate::parser::ast;
+use crate::parser::tokens;
 use crate::parser::error::Error;
-use crate::interpreter::{ Expr, Executable, Macro };
+use crate::error::OpenMacro;
+use crate::interpreter::{ Assoc, Expr, Executable, Fixity, Intrinsic, Macro, SourceMap, VarName };
+
+/// Words that can't be used as macro names, since they are, or will be,
+/// keywords of the language. The keywords that declare operators,
+/// `parser::INFIX_KEYWORDS`, are reserved as well.
+pub const RESERVED_WORDS: &[&str] = &["def", "let", "in", "fix", "import", "case", "priv"];
+
+fn check_macro_name(name: &tokens::Var) -> Result<()> {
+    let name_str = name.name.as_str();
+    if RESERVED_WORDS.contains(&name_str) || parser::INFIX_KEYWORDS.contains(&name_str) {
+        Err(Error::new(
+            name.span,
+            format!("`{}` is a reserved keyword and can't be used as a macro name", name.name),
+        ))
+    } else if name.is_wildcard() {
+        Err(Error::new(name.span, "`_` can't be used as a macro name"))
+    } else {
+        Ok(())
+    }
+}
+
+/// Highest precedence an operator can be declared with.
+pub const MAX_PRECEDENCE: u64 = 9;
+
+/// Options that change how a program is compiled.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CompileOptions {
+    /// Report macros that are never used, directly or through other macros,
+    /// by the program's expression.
+    pub strict: bool,
+    /// Biggest number literal allowed, since each one compiles to a Church
+    /// numeral as big as the number. Defaults to `DEFAULT_MAX_NUMERAL`.
+    pub max_numeral: Option<u64>,
+}
+
+/// Biggest number literal allowed by default.
+pub const DEFAULT_MAX_NUMERAL: u64 = 1024;
 
 pub fn compile_program(s: &str) -> Result<Executable> {
+    compile_program_with(s, CompileOptions::default())
+}
+
+pub fn compile_program_with(s: &str, options: CompileOptions) -> Result<Executable> {
     let stream = parser::ParseStream::from(s);
     let ast = ast::Program::parse(&stream)?;
 
-    let literals = alloc_prog_literals(&ast);
+    let mut literals = HashSet::new();
 
     // TODO: Allow loop macros like A refer to B and B refer to A
     let mut macros = HashMap::new();
-    for (i, stmt) in ast.stmts.iter().enumerate() {
-        let mut compiler = Compiler::new(&literals, &macros);
+    let mut compiler = Compiler::new(&mut literals, &mut macros);
+    compiler.max_numeral = options.max_numeral.unwrap_or(DEFAULT_MAX_NUMERAL);
+    // Blank lines and comments never make it into `ast.stmts`, so the
+    // expression only has to be the last of the statements.
+    let mut compiled: Option<(Expr, &ast::Expr)> = None;
+    for stmt in ast.stmts.iter() {
+        if let Some((_, expr)) = &compiled {
+            return Err(Error::new(stmt.span(), "expected the expression to be the last statement")
+                .with_note(expr.span(), "the expression is here"));
+        }
+        if let (StmtReturn::Expr(compiled_expr), ast::Stmt::Expr(expr)) = (compiler.compile_stmt(stmt)?, stmt) {
+            compiled = Some((compiled_expr, expr));
+        }
+    }
+
+    let (compiled, expr) = match compiled {
+        Some(compiled) => compiled,
+        None           => return Err(Error::new(stream.scope, "Expected an expression")),
+    };
+    if options.strict {
+        check_unused_macros(&ast, &compiled, &macros)?;
+    }
+    let mut executable = Executable::new(compiled, macros, literals);
+    let mut source_map = SourceMap::new(expr.span());
+    map_expr_spans(expr, &executable.expr, &executable.macros, &mut source_map);
+    executable.source_map = source_map;
+    Ok(executable)
+}
+
+/// Compiles every statement of a program, going on after errors instead of
+/// stopping at the first one. Returns the macros that were defined, in order,
+/// along with all the errors found. The expression at the end, if any, is
+/// only checked for errors.
+pub fn compile_program_collect(s: &str) -> (Vec<Rc<Macro>>, Vec<Error>) {
+    let mut errors = Vec::new();
+    let mut stmts = Vec::new();
+    for stmt in parser::StmtStream::new(0, s) {
         match stmt {
-            ast::Stmt::Macro(mac) => {
-                let compiled = compiler.compile_expr(&mac.value)?;
-                if let Some((key, _)) = macros.get_key_value(&mac.name.name) {
-                    let name_ptr = std::ptr::NonNull::from(key.as_ref());
-                    let new_macro = Rc::new(Macro::new(compiled, name_ptr));
-
-                    // Now we know the key is present. There should be a way to
-                    // get a mutable reference to the value and an imutable 
-                    // reference to the key at the same time.
-                    let prev = macros.get_mut(&mac.name.name).unwrap();
-                    drop(std::mem::replace(prev, new_macro));
-                } else {
-                    let name = mac.name.name.clone();
-                    let name_ptr = std::ptr::NonNull::from(name.as_ref());
-                    let new_macro = Rc::new(Macro::new(compiled, name_ptr));
-                    macros.insert(name, new_macro);
-                }
-            },
-            ast::Stmt::Expr(expr) => {
-                assert!(i == ast.stmts.len() - 1);
-                let compiled = compiler.compile_expr(expr)?;
-                return Ok(Executable::new(compiled, macros, literals));
-            }
+            Ok(stmt) => stmts.push(stmt),
+            Err(err) => errors.push(err),
         }
     }
 
-    Err(Error::new(stream.scope, "Expected an expression"))
+    let mut literals = HashSet::new();
+    let mut macros = HashMap::new();
+    let mut compiler = Compiler::new(&mut literals, &mut macros);
+    let mut defined = Vec::new();
+    for stmt in stmts.iter() {
+        match compiler.compile_stmt(stmt) {
+            Ok(StmtReturn::Macro(name)) => defined.push(compiler.macros[&name].clone()),
+            Ok(StmtReturn::Expr(_))     => (),
+            Err(err)                    => errors.push(err),
+        }
+    }
+    (defined, errors)
+}
+
+/// Runs every check the compiler has over a program without evaluating it,
+/// returning all the errors found, sorted by where they start. Programs that
+/// end in an expression are also checked for unused macros, like a strict
+/// compilation would, while libraries of definitions are only compiled.
+pub fn check_program(s: &str) -> Vec<Error> {
+    let (_, mut errors) = compile_program_collect(s);
+    if errors.is_empty() {
+        let stream = parser::ParseStream::from(s);
+        let ends_in_expr = ast::Program::parse(&stream)
+            .is_ok_and(|prog| matches!(prog.stmts.last(), Some(ast::Stmt::Expr(_))));
+        if ends_in_expr {
+            let strict = CompileOptions { strict: true, ..CompileOptions::default() };
+            if let Err(err) = compile_program_with(s, strict) {
+                errors.push(err);
+            }
+        }
+    }
+    errors.sort_by_key(|err| err.cover_span());
+    errors
 }
 
 pub enum StmtReturn {
@@ -59,74 +147,296 @@ pub fn compile_stmt(
     macros: &mut HashMap<String, Rc<Macro>>
 ) -> Result<StmtReturn>
 {
-    let stream = parser::ParseStream::from(s);
-    let stmt = ast::Stmt::parse(&stream)?;
-
-    alloc_stmt_literals(&stmt, literals);
-
-    let mut compiler = Compiler::new(literals, &macros);
-    match stmt {
-        ast::Stmt::Macro(mac) => {
-            let compiled = compiler.compile_expr(&mac.value)?;
-            let name = mac.name.name.clone();
-            let name_ptr = std::ptr::NonNull::from(name.as_ref());
-            macros.insert(name, Rc::new(Macro::new(compiled, name_ptr)));
-            Ok(StmtReturn::Macro(mac.name.name.to_owned()))
-        },
-        ast::Stmt::Expr(expr) => {
-            Ok(StmtReturn::Expr(compiler.compile_expr(&expr)?))
+    let pieces = parser::split_stmts(s);
+    if pieces.len() == 1 {
+        let stream = parser::ParseStream::from(s);
+        let stmt = ast::Stmt::parse(&stream)?;
+        return Compiler::new(literals, macros).compile_stmt(&stmt);
+    }
+
+    // Several statements separated by `;`. They are compiled in order, so
+    // the definitions are available to the expression at the end.
+    let mut stmts: Vec<ast::Stmt> = Vec::new();
+    for (start, piece) in pieces {
+        let content = parser::skip_blank(piece);
+        if content.is_empty() {
+            continue;
         }
+        if let Some(ast::Stmt::Expr(expr)) = stmts.last() {
+            let offset = start + piece.len() - content.len();
+            let span = Span::new(offset, offset + content.trim_end().len());
+            return Err(Error::new(span, "expected the expression to be the last statement")
+                .with_note(expr.span(), "the expression is here"));
+        }
+        let stream = parser::ParseStream::new(Span::new(start, start + piece.len()), piece);
+        stmts.push(ast::Stmt::parse(&stream)?);
     }
+
+    let mut compiler = Compiler::new(literals, macros);
+    let mut last = Err(Error::new(Span::new(0, s.len()), "Expected a statement"));
+    for stmt in stmts.iter() {
+        last = Ok(compiler.compile_stmt(stmt)?);
+    }
+    last
 }
 
-// Traverses the entire AST and finds all string literals in the program and
-// copies them into a vector.
-pub fn alloc_prog_literals(prog: &ast::Program) -> HashSet<Rc<String>> {
-    let mut literals = HashSet::new();
+/// Compiles an already parsed expression, resolving macro references in
+/// `macros`. String literals are interned in `literals`.
+pub fn compile_expr(
+    expr: &ast::Expr,
+    literals: &mut HashSet<Rc<String>>,
+    macros: &mut HashMap<String, Rc<Macro>>
+) -> Result<Expr>
+{
+    Compiler::new(literals, macros).compile_expr(expr)
+}
+
+// Reports every macro definition that can't be reached from `expr`. Only the
+// last definition of each name is considered, since the others are replaced.
+fn check_unused_macros(
+    prog: &ast::Program,
+    expr: &Expr,
+    macros: &HashMap<String, Rc<Macro>>
+) -> Result<()>
+{
+    let mut reachable: Vec<Rc<Macro>> = Vec::new();
+    let mut stack = expr.dependencies();
+    while let Some(mac) = stack.pop() {
+        if !reachable.iter().any(|seen| Rc::ptr_eq(seen, &mac)) {
+            stack.extend(mac.expr.dependencies());
+            reachable.push(mac);
+        }
+    }
 
+    let mut last_defs = HashMap::new();
     for stmt in prog.stmts.iter() {
-        alloc_stmt_literals(stmt, &mut literals);
+        match stmt {
+            ast::Stmt::Macro(mac)   => last_defs.insert(mac.name.name.as_str(), mac.name.span),
+            ast::Stmt::Infix(infix) => last_defs.insert(infix.op.symbol.as_str(), infix.op.span),
+            ast::Stmt::Expr(_)      => None,
+        };
     }
 
-    literals
+    let mut unused: Vec<_> = last_defs.into_iter()
+        .filter(|(name, _)| !reachable.iter().any(|mac| Rc::ptr_eq(mac, &macros[*name])))
+        .collect();
+    unused.sort_by_key(|&(_, span)| span);
+
+    let mut err: Option<Error> = None;
+    for (name, span) in unused {
+        let msg = format!("macro `{}` is never used", name);
+        match err.as_mut() {
+            Some(err) => err.push(span, msg),
+            None      => err = Some(Error::new(span, msg)),
+        }
+    }
+    err.map_or(Ok(()), Err)
 }
 
-fn alloc_stmt_literals(stmt: &ast::Stmt, literals: &mut HashSet<Rc<String>>) {
-    let mut expr_queue = VecDeque::new();
+/// Defines a macro, replacing any previous definition with the same name.
+/// The body must be closed, otherwise nothing is defined and the free
+/// variables are returned in the error.
+///
+/// The body is compiled before the macro exists, so it can only refer to
+/// macros defined earlier, even when it uses its own name. This means the
+/// `Rc<Macro>`s never form a cycle, and dropping the environment frees all of
+/// them. Recursive macros will need `Weak` back references to keep it so.
+pub fn define_macro(
+    macros: &mut HashMap<String, Rc<Macro>>,
+    name: &str,
+    expr: Expr,
+) -> std::result::Result<Rc<Macro>, OpenMacro> {
+    insert_macro(macros, Macro::new(expr, name))
+}
 
-    let expr = match stmt {
-        ast::Stmt::Macro(mac) => &mac.value,
-        ast::Stmt::Expr(expr) => &expr,
-    };
-    expr_queue.push_back(expr);
-
-    while let Some(expr) = expr_queue.pop_front() {
-        match expr {
-            ast::Expr::Lambda(lambda) => expr_queue.push_back(lambda.expr.as_ref()),
-            ast::Expr::Close(close)   => alloc_close_literals(&close, literals, &mut expr_queue),
-            ast::Expr::Appl(appl)     => {
-                alloc_close_literals(&appl.lhs, literals, &mut expr_queue);
-                alloc_close_literals(&appl.rhs, literals, &mut expr_queue);
+fn insert_macro(macros: &mut HashMap<String, Rc<Macro>>, mac: Macro) -> std::result::Result<Rc<Macro>, OpenMacro> {
+    if !mac.expr.is_closed() {
+        let mut free_vars: Vec<usize> = mac.expr.free_vars().into_iter().collect();
+        free_vars.sort_unstable();
+        return Err(OpenMacro { name: mac.name().to_owned(), free_vars });
+    }
+
+    let new_macro = Rc::new(mac);
+    macros.insert(new_macro.name().to_owned(), Rc::clone(&new_macro));
+    Ok(new_macro)
+}
+
+// Splits a chain of applications like `f a b c`, which the parser nests as
+// `((f a) b) c` using groupings with no delimiter, into all of its parts in
+// order, `[f, a, b, c]`.
+fn appl_spine(appl: &ast::Appl) -> Vec<&ast::Close> {
+    let mut spine = vec![&appl.rhs];
+    let mut head = &appl.lhs;
+    while let ast::Close::Grouping(
+        box ast::Expr::Appl(inner),
+        tokens::Group { delim: tokens::Delimiter::None, .. },
+    ) = head {
+        spine.push(&inner.rhs);
+        head = &inner.lhs;
+    }
+    spine.push(head);
+    spine.reverse();
+    spine
+}
+
+/// Parses and compiles a single expression. Since the environment is empty,
+/// macros can't be referenced this way; use `compile_program` or
+/// `compile_stmt` for that. Names that are not bound by any lambda are free
+/// variables instead, so `(\a. a) x` evaluates to `x`.
+impl std::str::FromStr for Expr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Expr> {
+        let mut literals = HashSet::new();
+        let mut macros = HashMap::new();
+        let stream = parser::ParseStream::from(s);
+        let stmt = ast::Stmt::parse(&stream)?;
+        let mut compiler = Compiler::new(&mut literals, &mut macros);
+        // There can't be more parameters than characters, so the ids of free
+        // variables never clash with the ones of parameters.
+        compiler.free_var_base = Some(s.len());
+        match compiler.compile_stmt(&stmt)? {
+            StmtReturn::Expr(expr)  => Ok(expr),
+            StmtReturn::Macro(_)    => {
+                Err(Error::new(Span::from(s), "expected an expression, found a macro definition"))
             },
         }
     }
 }
 
-#[inline]
-fn alloc_close_literals<'a>(
-    close: &'a ast::Close,
-    literals: &mut HashSet<Rc<String>>,
-    expr_queue: &mut VecDeque<&'a ast::Expr>
-) {
-    match close {
-        ast::Close::Grouping(e, _)  => expr_queue.push_back(e.as_ref()),
-        ast::Close::Literal(lit) => {
-            literals.insert(Rc::new(lit.content.clone()));
+// Walks the AST and the expression compiled from it side by side, recording
+// the span each compiled node came from. The macros are needed for the
+// fixity of operators.
+fn map_expr_spans(ast: &ast::Expr, expr: &Expr, macros: &HashMap<String, Rc<Macro>>, source_map: &mut SourceMap) {
+    source_map.insert(expr, ast.span());
+    match (ast, expr) {
+        (ast::Expr::Lambda(lambda), Expr::Lambda { expr, .. }) => {
+            // Each extra parameter compiled to one more nested lambda.
+            let mut body: &Expr = expr;
+            for _ in 1..lambda.params.vars.len() {
+                source_map.insert(body, lambda.span());
+                match body {
+                    Expr::Lambda { expr, .. } => body = expr,
+                    _                         => return,
+                }
+            }
+            map_expr_spans(&lambda.expr, body, macros, source_map);
         },
-        ast::Close::Var(_)       => (),
+        (ast::Expr::Close(close), _) => map_close_spans(close, expr, macros, source_map),
+        (ast::Expr::Appl(appl), _)   => map_spine_spans(&appl_spine(appl), expr, macros, source_map),
+        _ => (),
     }
 }
 
+// Maps the parts of an application. Operators reorder them, so the spine is
+// split at the operator that was applied last, which is the one with the
+// lowest precedence, the same way `resolve_operators` grouped it.
+fn map_spine_spans(spine: &[&ast::Close], expr: &Expr, macros: &HashMap<String, Rc<Macro>>, source_map: &mut SourceMap) {
+    let span = spine[0].span().merge(spine[spine.len() - 1].span());
+    source_map.insert(expr, span);
+
+    let mut root: Option<(usize, Fixity)> = None;
+    for (i, close) in spine.iter().enumerate() {
+        let fixity = match close {
+            ast::Close::Operator(op) => match macros.get(&op.symbol).and_then(|mac| mac.fixity()) {
+                Some(fixity) => fixity,
+                None         => return,
+            },
+            _                        => continue,
+        };
+        root = match root {
+            Some((_, curr)) if curr.precedence < fixity.precedence => root,
+            // Operators of the same precedence group the same way.
+            Some((_, curr)) if curr.precedence == fixity.precedence && curr.assoc == Assoc::Right => root,
+            _ => Some((i, fixity)),
+        };
+    }
+
+    match (root, expr) {
+        (Some((i, _)), Expr::Appl { f, arg: rhs }) => {
+            // The operator applied to its left operand.
+            source_map.insert(f, spine[0].span().merge(spine[i].span()));
+            if let Expr::Appl { f: op, arg: lhs } = f.as_ref() {
+                source_map.insert(op, spine[i].span());
+                map_spine_spans(&spine[..i], lhs, macros, source_map);
+                map_spine_spans(&spine[i + 1..], rhs, macros, source_map);
+            }
+        },
+        (None, _) if spine.len() == 1 => map_close_spans(spine[0], expr, macros, source_map),
+        (None, Expr::Appl { f, arg }) => {
+            map_close_spans(spine[spine.len() - 1], arg, macros, source_map);
+            map_spine_spans(&spine[..spine.len() - 1], f, macros, source_map);
+        },
+        _ => (),
+    }
+}
+
+fn map_close_spans(close: &ast::Close, expr: &Expr, macros: &HashMap<String, Rc<Macro>>, source_map: &mut SourceMap) {
+    source_map.insert(expr, close.span());
+    if let ast::Close::Grouping(e, _) = close {
+        map_expr_spans(e, expr, macros, source_map);
+    }
+}
+
+// Applies the operators in order of precedence, with precedence climbing:
+// `lhs` is combined with every operator of precedence at least
+// `min_precedence`, whose right operand takes all the operators after it
+// that bind tighter.
+fn resolve_operators<'a, O, I>(
+    mut lhs: Expr,
+    operands: &mut O,
+    operators: &mut std::iter::Peekable<I>,
+    min_precedence: u8,
+) -> Result<Expr>
+where
+    O: Iterator<Item = Expr>,
+    I: Iterator<Item = (&'a tokens::Operator, (Rc<Macro>, Fixity))>,
+{
+    while let Some((_, (_, fixity))) = operators.peek() {
+        if fixity.precedence < min_precedence {
+            break;
+        }
+        let (op, (mac, fixity)) = operators.next().unwrap();
+        let next_min = match fixity.assoc {
+            Assoc::Right => fixity.precedence,
+            _            => fixity.precedence + 1,
+        };
+        let rhs = resolve_operators(operands.next().unwrap(), operands, operators, next_min)?;
+        lhs = Expr::Appl {
+            f: Expr::Appl { f: Expr::MacroRef(mac).into(), arg: lhs.into() }.into(),
+            arg: rhs.into(),
+        };
+
+        // Whatever comes next with the same precedence must group the same way.
+        if let Some((next, (_, next_fixity))) = operators.peek() {
+            if next_fixity.precedence == fixity.precedence
+                && (fixity.assoc == Assoc::None || next_fixity.assoc != fixity.assoc)
+            {
+                return Err(Error::new(
+                    next.span,
+                    format!("`{}` can't be chained with `{}` without parenthesis", op.symbol, next.symbol),
+                ));
+            }
+        }
+    }
+    Ok(lhs)
+}
+
+// Levenshtein distance between `a` and `b`, counted in characters.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut curr = vec![i + 1];
+        for (j, &cb) in b.iter().enumerate() {
+            let substitution = prev[j] + if ca == cb { 0 } else { 1 };
+            curr.push(substitution.min(prev[j + 1] + 1).min(curr[j] + 1));
+        }
+        prev = curr;
+    }
+    prev[b.len()]
+}
+
 /*
  * The 'expr lifetime is used for references that point into some ast::Expr, this
  * may be a pointer to the string in a Literal token, for example.
@@ -135,48 +445,115 @@ fn alloc_close_literals<'a>(
  * strings in the program. 'lit must outlive 'expr and 'mac because 'mac points
  * into 'lit and 'expr will usualy be used to create get a refenrece into 'lit.
  */
-struct Compiler<'expr, 'lit> {
-    literals: &'lit HashSet<Rc<String>>,
-    macros: &'lit HashMap<String, Rc<Macro>>,
+pub struct Compiler<'expr, 'lit> {
+    literals: &'lit mut HashSet<Rc<String>>,
+    macros: &'lit mut HashMap<String, Rc<Macro>>,
     var_name_to_id: HashMap<&'expr str, (usize, Span)>,
+    // Number of `_` parameters in scope. They have no name to be inserted into
+    // `var_name_to_id`, but still take up a variable id.
+    wildcards: usize,
+    max_numeral: u64,
+    // When set, names that are not in scope and don't refer to a macro are
+    // compiled to free variables, with ids from this one on, instead of
+    // being an error.
+    free_var_base: Option<usize>,
+    free_vars: HashMap<&'expr str, usize>,
 }
 
 impl<'expr, 'lit> Compiler<'expr, 'lit> {
-    fn new(literals: &'lit HashSet<Rc<String>>, macros: &'lit HashMap<String, Rc<Macro>>) -> Compiler<'expr, 'lit> {
-        Compiler { literals, macros, var_name_to_id: HashMap::new() }
+    pub fn new(literals: &'lit mut HashSet<Rc<String>>, macros: &'lit mut HashMap<String, Rc<Macro>>) -> Compiler<'expr, 'lit> {
+        Compiler {
+            literals,
+            macros,
+            var_name_to_id: HashMap::new(),
+            wildcards: 0,
+            max_numeral: DEFAULT_MAX_NUMERAL,
+            free_var_base: None,
+            free_vars: HashMap::new(),
+        }
+    }
+
+    /// Forgets the variables in scope, which may have been left behind by an
+    /// expression that failed to compile. Literals and macros are kept.
+    pub fn reset_scope(&mut self) {
+        self.var_name_to_id.clear();
+        self.wildcards = 0;
+        self.free_vars.clear();
     }
 
-    fn compile_expr(&mut self, expr: &'expr ast::Expr) -> Result<Expr> {
-        let mut new_var = None;
+    /// Compiles a statement, defining the macro if it is a definition. The
+    /// same compiler can be used for many statements.
+    pub fn compile_stmt(&mut self, stmt: &'expr ast::Stmt) -> Result<StmtReturn> {
+        self.reset_scope();
+        match stmt {
+            ast::Stmt::Macro(mac) => {
+                check_macro_name(&mac.name)?;
+                let compiled = self.compile_expr(&mac.value)?;
+                insert_macro(self.macros, Macro::new(compiled, mac.name.name.as_str()).with_private(mac.is_private()))
+                    .map_err(|err| self.open_macro_error(mac.value.span(), err))?;
+                Ok(StmtReturn::Macro(mac.name.name.to_owned()))
+            },
+            ast::Stmt::Infix(infix) => {
+                let assoc = Assoc::from_keyword(&infix.keyword.name)
+                    .ok_or_else(|| Error::new(infix.keyword.span, "expected `infixl`, `infixr` or `infix`"))?;
+                if infix.precedence.value > MAX_PRECEDENCE {
+                    return Err(Error::new(
+                        infix.precedence.span,
+                        format!("the precedence of an operator goes from 0 to {}", MAX_PRECEDENCE),
+                    ));
+                }
+                let fixity = Fixity { assoc, precedence: infix.precedence.value as u8 };
+                let compiled = self.compile_expr(&infix.value)?;
+                insert_macro(self.macros, Macro::new(compiled, infix.op.symbol.as_str()).with_fixity(fixity))
+                    .map_err(|err| self.open_macro_error(infix.value.span(), err))?;
+                Ok(StmtReturn::Macro(infix.op.symbol.to_owned()))
+            },
+            ast::Stmt::Expr(expr) => {
+                Ok(StmtReturn::Expr(self.compile_expr(expr)?))
+            }
+        }
+    }
+
+    pub fn compile_expr(&mut self, expr: &'expr ast::Expr) -> Result<Expr> {
+        let mut new_vars = Vec::new();
+        let mut new_wildcards = 0;
         let compiled = match expr {
             ast::Expr::Lambda(lambda) => {
-                let param = self.var_name_to_id.len();
-                if let Some(&(_, span)) = self.var_name_to_id.get(&lambda.var.name.as_str()) {
-                    let mut err = Error::new(lambda.var.span, "identifier is already in scope");
-                    err.push(span, "first defined here");
-                    return Err(err);
+                let mut params = Vec::new();
+                for var in lambda.params.vars.iter() {
+                    let param = self.var_name_to_id.len() + self.wildcards;
+                    if var.is_wildcard() {
+                        self.wildcards += 1;
+                        new_wildcards += 1;
+                        params.push((param, VarName::default()));
+                        continue;
+                    }
+                    if let Some(&(_, span)) = self.var_name_to_id.get(&var.name.as_str()) {
+                        return Err(Error::new(var.span, "identifier is already in scope")
+                            .with_note(span, "first defined here"));
+                    }
+                    self.var_name_to_id.insert(&var.name, (param, var.span));
+                    new_vars.push(&var.name);
+                    params.push((param, VarName(Some(var.name.as_str().into()))));
                 }
-                self.var_name_to_id.insert(&lambda.var.name, (param, lambda.var.span));
-                new_var = Some(&lambda.var.name);
 
-                Expr::Lambda {
-                    param,
-                    expr: self.compile_expr(&lambda.expr)?.into()
-                }
+                // Multiple parameters are just nested lambdas.
+                let body = self.compile_expr(&lambda.expr)?;
+                params.into_iter()
+                    .rev()
+                    .fold(body, |expr, (param, name)| Expr::Lambda { param, expr: expr.into(), name })
             },
             ast::Expr::Close(close) => self.compile_close(&close)?,
-            ast::Expr::Appl(appl)   => {
-                Expr::Appl {
-                    f:   self.compile_close(&appl.lhs)?.into(),
-                    arg: self.compile_close(&appl.rhs)?.into(),
-                }
-            },
+            // The head and all arguments are compiled into a single
+            // left-nested chain, unless there are operators between them.
+            ast::Expr::Appl(appl)   => self.compile_operators(&appl_spine(appl))?,
         };
 
-        // If a variable has been added to scope, remove it here, where the scope is no more.
-        if let Some(name) = new_var {
+        // If variables have been added to scope, remove them here, where the scope is no more.
+        for name in new_vars {
             self.var_name_to_id.remove(&name.as_ref());
         }
+        self.wildcards -= new_wildcards;
 
         Ok(compiled)
     }
@@ -184,33 +561,642 @@ impl<'expr, 'lit> Compiler<'expr, 'lit> {
     fn compile_close(&mut self, close: &'expr ast::Close) -> Result<Expr> {
         Ok(match close {
             ast::Close::Grouping(e, _) => self.compile_expr(e.as_ref())?,
+            ast::Close::Var(var) if var.is_wildcard() => {
+                return Err(Error::new(var.span, "`_` discards its argument and can't be referenced"));
+            },
             ast::Close::Var(var)    => {
                 match self.var_name_to_id.get(&var.name.as_str()) {
                     Some(&(var_id, _)) => Expr::Var(var_id),
                     None          => {
-                        let mac = self.macros
-                            .get(&var.name)
-                            .ok_or_else(|| Error::new(var.span, "use of undeclared variable or macro"))?;
-
-                        Expr::MacroRef(Rc::clone(mac))
+                        match self.macros.get(&var.name) {
+                            Some(mac) => Expr::MacroRef(Rc::clone(mac)),
+                            None      => match Intrinsic::from_name(&var.name) {
+                                Some(intrinsic) => Expr::Intrinsic(intrinsic),
+                                None            => Expr::Var(self.free_var(var)?),
+                            },
+                        }
                     },
                 }
             },
             ast::Close::Literal(lit) => {
-                let s = self.literals.get(&lit.content).unwrap();
+                // Literals are interned as they are found, so equal literals
+                // share the same allocation.
+                let s = self.literals.get_or_insert_with(&lit.content, |s| Rc::new(s.clone()));
                 Expr::Literal(Rc::clone(s))
             },
+            ast::Close::Number(num) => self.compile_numeral(num)?,
+            ast::Close::Operator(op) => Expr::MacroRef(self.get_operator(op)?.0),
         })
     }
+
+    fn get_operator(&self, op: &tokens::Operator) -> Result<(Rc<Macro>, Fixity)> {
+        match self.macros.get(&op.symbol) {
+            Some(mac) => match mac.fixity() {
+                Some(fixity) => Ok((Rc::clone(mac), fixity)),
+                None         => Err(Error::new(op.span, format!("`{}` is not an operator", op.symbol))),
+            },
+            None => Err(Error::new(
+                op.span,
+                format!("use of undeclared operator `{}`, declare it with `infixl`, `infixr` or `infix`", op.symbol),
+            )),
+        }
+    }
+
+    // Compiles the parts of an application, where the operands between
+    // operators are applications themselves, like `f a + g b`.
+    fn compile_operators(&mut self, spine: &[&'expr ast::Close]) -> Result<Expr> {
+        let mut operands = Vec::new();
+        let mut operators = Vec::new();
+        let mut operand: Option<Expr> = None;
+        for close in spine.iter() {
+            match close {
+                ast::Close::Operator(op) => {
+                    let lhs = operand.take()
+                        .ok_or_else(|| Error::new(op.span, format!("expected an operand before `{}`", op.symbol)))?;
+                    operands.push(lhs);
+                    operators.push((op, self.get_operator(op)?));
+                },
+                _ => {
+                    let compiled = self.compile_close(close)?;
+                    operand = Some(match operand {
+                        Some(f) => Expr::Appl { f: f.into(), arg: compiled.into() },
+                        None    => compiled,
+                    });
+                },
+            }
+        }
+        match operand {
+            Some(rhs) => operands.push(rhs),
+            None      => {
+                // There is at least one operator, otherwise the loop would
+                // have set an operand.
+                let (op, _) = operators.last().unwrap();
+                return Err(Error::new(op.span, format!("expected an operand after `{}`", op.symbol)));
+            },
+        }
+
+        let mut operands = operands.into_iter();
+        let mut operators = operators.into_iter().peekable();
+        let first = operands.next().unwrap();
+        resolve_operators(first, &mut operands, &mut operators, 0)
+    }
+    // Gets the id of a name that is not in scope, if free variables are
+    // allowed. The same name always gets the same id.
+    fn free_var(&mut self, var: &'expr tokens::Var) -> Result<usize> {
+        let base = self.free_var_base.ok_or_else(|| self.undeclared_error(var))?;
+        let next = base + self.free_vars.len();
+        Ok(*self.free_vars.entry(&var.name).or_insert(next))
+    }
+
+    // The error for a macro whose body uses names that are not in scope,
+    // which only compiles when free variables are allowed.
+    fn open_macro_error(&self, span: Span, err: OpenMacro) -> Error {
+        let names: Vec<String> = err.free_vars.iter()
+            .map(|v| match self.free_vars.iter().find(|(_, id)| *id == v) {
+                Some((name, _)) => format!("`{}`", name),
+                None            => v.to_string(),
+            })
+            .collect();
+        Error::new(span, format!("the body of macro `{}` uses the free variables {}", err.name, names.join(", ")))
+    }
+
+    fn undeclared_error(&self, var: &tokens::Var) -> Error {
+        match self.suggest_macro(&var.name) {
+            Some(name) => Error::new(
+                var.span,
+                format!("use of undeclared variable or macro, did you mean `{}`?", name),
+            ),
+            None => Error::new(var.span, "use of undeclared variable or macro"),
+        }
+    }
+
+    // Finds the defined macro with the closest name, if it is close enough to
+    // likely be a typo. Ties are broken alphabetically.
+    fn suggest_macro(&self, name: &str) -> Option<&str> {
+        let max_distance = std::cmp::max(1, name.chars().count() / 3);
+        self.macros.keys()
+            .map(|candidate| (edit_distance(name, candidate), candidate.as_str()))
+            .filter(|&(distance, _)| distance <= max_distance)
+            .min()
+            .map(|(_, candidate)| candidate)
+    }
+
+    // Numbers are Church numerals, \f. \x. f (f (... x)).
+    fn compile_numeral(&mut self, num: &tokens::Number) -> Result<Expr> {
+        if num.value > self.max_numeral {
+            return Err(Error::new(
+                num.span,
+                format!("number {} is bigger than the maximum of {}", num.value, self.max_numeral),
+            ).with_note(
+                num.span,
+                "bigger numbers can be built from smaller ones, with something like `Mult 1000 1000`",
+            ));
+        }
+
+        let f = self.var_name_to_id.len() + self.wildcards;
+        let x = f + 1;
+        let mut body = Expr::Var(x);
+        for _ in 0..num.value {
+            body = Expr::Appl { f: Expr::Var(f).into(), arg: body.into() };
+        }
+        Ok(Expr::lam(f, Expr::lam(x, body)))
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::shared_expr::SubExpr;
 
     #[test]
     fn test_compilation() {
         let input = "(\\a. a a) (\\a. a a)";
         assert!(compile_program(input).is_ok());
     }
+
+    #[test]
+    fn test_wildcard_param() {
+        let k = compile_program("\\a. \\_. a").unwrap();
+        assert_eq!(k.expr.as_church_bool(), Some(true));
+
+        let skip = compile_program("\\_ a. a").unwrap();
+        assert_eq!(skip.expr.as_church_bool(), Some(false));
+
+        let err = match compile_program("\\_. _") {
+            Ok(_)    => panic!("`_` should not be referenceable"),
+            Err(err) => err,
+        };
+        assert_eq!(err.messages[0].span, Span::new(4, 5));
+
+        assert!(compile_program("_ = \\a. a\n_").is_err());
+    }
+
+    #[test]
+    fn test_reserved_macro_name() {
+        let mut literals = HashSet::new();
+        let mut macros = HashMap::new();
+        let err = match compile_stmt("let = \\a. a", &mut literals, &mut macros) {
+            Ok(_)    => panic!("`let` should be rejected"),
+            Err(err) => err,
+        };
+        assert_eq!(err.messages[0].span, Span::new(0, 3));
+        assert!(err.messages[0].message.contains("reserved keyword"), "{}", err);
+        assert!(macros.is_empty());
+
+        assert!(compile_program("def = \\a. a\n(\\a. a) (\\b. b)").is_err());
+        assert!(compile_stmt("infixr = \\a. a", &mut literals, &mut macros).is_err());
+        assert!(compile_stmt("letter = \\a. a", &mut literals, &mut macros).is_ok());
+    }
+
+    #[test]
+    fn test_define_open_macro() {
+        let mut macros = HashMap::new();
+        let open = Expr::lam(0, Expr::app(Expr::Var(3), Expr::Var(1)));
+        let err = define_macro(&mut macros, "Open", open).unwrap_err();
+        assert_eq!(err, OpenMacro { name: "Open".to_owned(), free_vars: vec![1, 3] });
+        assert_eq!(err.to_string(), "The body of macro `Open` has free variables: 1, 3");
+        assert!(macros.is_empty());
+
+        // Free variables only compile when parsing a lone expression, and
+        // can't be used by a macro even then.
+        let err = "Open = \\a. a x y".parse::<Expr>().unwrap_err();
+        assert!(err.to_string().contains("the body of macro `Open` uses the free variables `x`, `y`"), "{}", err);
+    }
+
+    #[test]
+    fn test_literal_interning() {
+        let executable = compile_program("(\\a. a) (\"x\")").unwrap();
+        assert_eq!(executable.literals.len(), 1);
+
+        let executable = compile_program("(\\a. \\b. a) \"x\" (\"x\")").unwrap();
+        assert_eq!(executable.literals.len(), 1);
+        let (_, args) = executable.expr.flatten_appl();
+        match args[..] {
+            [Expr::Literal(a), Expr::Literal(b)] => assert!(Rc::ptr_eq(a, b)),
+            _ => panic!("unexpected expression {}", executable.expr),
+        }
+    }
+
+    #[test]
+    fn test_strict_unused_macros() {
+        let input = r#"
+            True  = \a. \b. a
+            False = \a. \b. b
+            Not   = \p. p False True
+            Id    = \a. a
+
+            Not True
+        "#;
+        assert!(compile_program(input).is_ok());
+
+        let strict = CompileOptions { strict: true, ..CompileOptions::default() };
+        let err = match compile_program_with(input, strict) {
+            Ok(_)    => panic!("should report the unused macro"),
+            Err(err) => err,
+        };
+        assert_eq!(err.messages.len(), 1);
+        assert!(err.messages[0].message.contains("`Id`"), "{}", err);
+        assert_eq!(&input[err.messages[0].span.into_range()], "Id");
+    }
+
+    #[test]
+    fn test_macro_application_chain() {
+        let input = r#"
+            Two   = \f. \x. f (f x)
+            Three = \f. \x. f (f (f x))
+            Plus  = \m. \n. \f. \x. m f (n f x)
+
+            Plus Two Three
+        "#;
+        let executable = compile_program(input).unwrap();
+        assert_eq!(executable.expr.to_string(), "Plus Two Three");
+
+        let plus = Expr::MacroRef(Rc::clone(&executable.macros["Plus"]));
+        let two = Expr::MacroRef(Rc::clone(&executable.macros["Two"]));
+        let three = Expr::MacroRef(Rc::clone(&executable.macros["Three"]));
+        assert_eq!(executable.expr, Expr::Appl {
+            f: SubExpr::new(Expr::Appl { f: SubExpr::new(plus), arg: SubExpr::new(two) }),
+            arg: SubExpr::new(three),
+        });
+
+        let spans = &executable.source_map;
+        if let Expr::Appl { f, arg } = &executable.expr {
+            assert_eq!(&input[spans.get(f).unwrap().into_range()], "Plus Two");
+            assert_eq!(&input[spans.get(arg).unwrap().into_range()], "Three");
+        }
+    }
+
+    #[test]
+    fn test_reused_compiler() {
+        let sources = ["Id = \\a. a", "K = \\a b. a", "\\x. y", "K Id", "\\x _. x \"s\" Id"];
+        let stmts: Vec<ast::Stmt> = sources.iter()
+            .map(|s| ast::Stmt::parse(&parser::ParseStream::from(*s)).unwrap())
+            .collect();
+
+        let mut literals = HashSet::new();
+        let mut macros = HashMap::new();
+        let mut compiler = Compiler::new(&mut literals, &mut macros);
+        let reused: Vec<_> = stmts.iter()
+            .map(|stmt| match compiler.compile_stmt(stmt) {
+                Ok(StmtReturn::Expr(expr)) => Some(expr),
+                _                          => None,
+            })
+            .collect();
+
+        let mut literals = HashSet::new();
+        let mut macros = HashMap::new();
+        let fresh: Vec<_> = sources.iter()
+            .map(|s| match compile_stmt(s, &mut literals, &mut macros) {
+                Ok(StmtReturn::Expr(expr)) => Some(expr),
+                _                          => None,
+            })
+            .collect();
+
+        assert_eq!(reused, fresh);
+        assert_eq!(reused.iter().filter(|expr| expr.is_some()).count(), 2);
+    }
+
+    #[test]
+    fn test_macro_environment_is_dropped() {
+        let input = r#"
+            Id   = \a. a
+            K    = \a. \b. Id a
+            Id   = \a. K Id a
+            Flip = \f. \a. \b. f b a
+
+            Flip K Id
+        "#;
+        let executable = compile_program(input).unwrap();
+        let mut weak: Vec<_> = executable.macros.values().map(Rc::downgrade).collect();
+
+        // The first `Id` is replaced, but `K` still refers to it.
+        let k = &executable.macros["K"];
+        weak.extend(k.expr.dependencies().iter().map(Rc::downgrade));
+        assert_eq!(weak.len(), 4);
+
+        drop(executable);
+        assert!(weak.iter().all(|mac| mac.upgrade().is_none()));
+    }
+
+    #[test]
+    fn test_number_literals() {
+        let numeral = |s: &str| compile_program(s).map(|executable| executable.expr.as_church_numeral());
+        assert_eq!(numeral("0").ok(), Some(Some(0)));
+        assert_eq!(numeral("0xA").ok(), Some(Some(10)));
+        assert_eq!(numeral("1_0").ok(), Some(Some(10)));
+        assert_eq!(numeral("0x1_0").ok(), Some(Some(16)));
+
+        let mut expr = compile_program("(\\n. \\f. \\x. f (n f x)) 0xA").unwrap().expr;
+        assert_eq!(expr.eval_to_normal_form().unwrap().as_church_numeral(), Some(11));
+
+        let err = match compile_program("\\a. a 1_025") {
+            Ok(_)    => panic!("1025 is bigger than the default maximum"),
+            Err(err) => err,
+        };
+        assert_eq!(err.messages[0].span, Span::new(6, 11));
+        assert!(err.messages[0].message.contains("maximum of 1024"), "{}", err);
+
+        let options = CompileOptions { max_numeral: Some(2000), ..CompileOptions::default() };
+        assert!(compile_program_with("1_025", options).is_ok());
+
+        // Refused before anything is built, instead of allocating a hundred
+        // million applications.
+        let err = compile_program("100000000").err().expect("100000000 is bigger than the maximum");
+        assert_eq!(err.messages[0].span, Span::new(0, 9));
+        assert!(err.messages[1].is_note());
+        assert!(err.messages[1].message.contains("Mult"), "{}", err);
+        assert!(compile_program("99999999999999999999").is_err());
+    }
+
+    #[test]
+    fn test_multiple_params() {
+        let nested: Expr = "\\a. \\b. \\c. a c".parse().unwrap();
+        let spaced: Expr = "\\a b c. a c".parse().unwrap();
+        let commas: Expr = "\\a, b, c. a c".parse().unwrap();
+        assert_eq!(nested, spaced);
+        assert_eq!(nested, commas);
+        assert!("\\a, a. a".parse::<Expr>().is_err());
+    }
+
+    #[test]
+    fn test_expr_from_str() {
+        let mut expr: Expr = "(\\a. a) (\\x. x)".parse().unwrap();
+        let expected: Expr = "\\x. x".parse().unwrap();
+        assert_eq!(expr.eval().unwrap().pure_alpha_convert(), expected.pure_alpha_convert());
+
+        // Names that are not bound are free variables.
+        let mut expr: Expr = "(\\a. a) x".parse().unwrap();
+        assert!(matches!(expr.eval().unwrap(), Expr::Var(_)));
+        let mut expr: Expr = "(\\a. \\b. b a) x y".parse().unwrap();
+        expr.eval().unwrap();
+        let Expr::Appl { f, arg } = &expr else { panic!("{}", expr) };
+        assert!(matches!((f.as_ref(), arg.as_ref()), (Expr::Var(y), Expr::Var(x)) if x != y), "{}", expr);
+        assert_eq!(expr.free_vars().len(), 2);
+
+        // Macros can't be resolved without an environment.
+        assert!("Id = \\a. a".parse::<Expr>().is_err());
+    }
+
+    #[test]
+    fn test_compile_parsed_expr() {
+        let mut literals = HashSet::new();
+        let mut macros = HashMap::new();
+
+        let ast = ast::Expr::parse(&parser::ParseStream::from("\\a. a")).unwrap();
+        let expr = compile_expr(&ast, &mut literals, &mut macros).unwrap();
+        assert_eq!(expr, Expr::lam(0, Expr::Var(0)));
+
+        let ast = ast::Expr::parse(&parser::ParseStream::from("Id Id")).unwrap();
+        assert!(compile_expr(&ast, &mut literals, &mut macros).is_err());
+
+        let id = define_macro(&mut macros, "Id", expr).unwrap();
+        let expr = compile_expr(&ast, &mut literals, &mut macros).unwrap();
+        assert_eq!(expr, Expr::Appl {
+            f: SubExpr::new(Expr::MacroRef(Rc::clone(&id))),
+            arg: SubExpr::new(Expr::MacroRef(id)),
+        });
+    }
+
+    #[test]
+    fn test_var_names() {
+        let executable = compile_program("K = \\x. \\y. x\n\\foo. \\bar. K foo").unwrap();
+        let Expr::Lambda { name: foo, expr, .. } = &executable.expr else {
+            panic!("expected two lambdas, got {}", executable.expr);
+        };
+        let Expr::Lambda { name: bar, .. } = expr.as_ref() else {
+            panic!("expected two lambdas, got {}", executable.expr);
+        };
+        assert_eq!(foo.0.as_deref(), Some("foo"));
+        assert_eq!(bar.0.as_deref(), Some("bar"));
+    }
+
+    #[test]
+    fn test_did_you_mean() {
+        assert_eq!(edit_distance("Tru", "True"), 1);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("λx", "λy"), 1);
+
+        let input = "True = \\a. \\b. a\nFalse = \\a. \\b. b\nTru";
+        let err = match compile_program(input) {
+            Ok(_)    => panic!("`Tru` is not defined"),
+            Err(err) => err,
+        };
+        assert_eq!(err.messages[0].message, "use of undeclared variable or macro, did you mean `True`?");
+
+        // Names that are too different get no suggestion.
+        let err = match compile_program("True = \\a. \\b. a\nNope") {
+            Ok(_)    => panic!("`Nope` is not defined"),
+            Err(err) => err,
+        };
+        assert_eq!(err.messages[0].message, "use of undeclared variable or macro");
+    }
+
+    #[test]
+    fn test_nothing_hole_span() {
+        let input = "(\\a. a) (\\b. b)";
+        let mut executable = compile_program(input).unwrap();
+        if let Expr::Appl { arg, .. } = &mut executable.expr {
+            **arg = Expr::Nothing;
+        }
+
+        assert!(executable.eval().is_err());
+        assert_eq!(executable.error_span, Some(Span::new(8, 15)));
+
+        // The hole that is reached is reported, not the first one.
+        let input = "(\\a b. b) (\\p. p) (\\q. q)";
+        let mut executable = compile_program(input).unwrap();
+        if let Expr::Appl { f, arg: second } = &mut executable.expr {
+            **second = Expr::Nothing;
+            if let Expr::Appl { arg: first, .. } = &mut **f {
+                **first = Expr::Nothing;
+            }
+        }
+        assert!(executable.eval().is_err());
+        assert_eq!(executable.error_span, Some(Span::new(18, 25)));
+    }
+
+    #[test]
+    fn test_source_map_cleared_by_eval() {
+        let input = "(\\x. \\y. y x) (\\a. a) (\\b. \\c. (\\d. d) c b)";
+        let mut executable = compile_program(input).unwrap();
+        assert!(executable.span_of(&executable.expr).is_some());
+
+        executable.eval().unwrap();
+        let mut stack = vec![&executable.expr];
+        while let Some(curr) = stack.pop() {
+            assert_eq!(executable.span_of(curr), None);
+            match curr {
+                Expr::Lambda { expr, .. } => stack.push(expr),
+                Expr::Appl { f, arg }     => {
+                    stack.push(f);
+                    stack.push(arg);
+                },
+                _                         => (),
+            }
+        }
+    }
+
+    #[test]
+    fn test_infix_operators() {
+        let defs = "Plus = \\m. \\n. \\f. \\x. m f (n f x)\n\
+                    Mult = \\m. \\n. \\f. m (n f)\n\
+                    Pow  = \\b. \\e. e b\n\
+                    infixl 6 + = Plus\n\
+                    infixl 7 * = Mult\n\
+                    infixr 8 ^ = Pow\n";
+        let compile = |expr: &str| compile_program(&format!("{}{}", defs, expr)).unwrap();
+
+        // `1 + 2 + 3` is `Plus (Plus 1 2) 3`.
+        let executable = compile("1 + 2 + 3");
+        let (head, args) = executable.expr.flatten_appl();
+        assert!(matches!(head, Expr::MacroRef(mac) if mac.name() == "+"));
+        assert_eq!(args[1].as_church_numeral(), Some(3));
+        let (inner_head, inner_args) = args[0].flatten_appl();
+        assert!(matches!(inner_head, Expr::MacroRef(mac) if mac.name() == "+"));
+        assert_eq!(inner_args[0].as_church_numeral(), Some(1));
+        assert_eq!(inner_args[1].as_church_numeral(), Some(2));
+
+        // Operator applications are mapped back to the source, even though
+        // their parts are reordered.
+        let source = format!("{}1 + 2 * 3", defs);
+        let executable = compile("1 + 2 * 3");
+        let text = |node: &Expr| &source[executable.span_of(node).unwrap().into_range()];
+        let Expr::Appl { f, arg: product } = &executable.expr else {
+            panic!("expected `+` applied to two operands");
+        };
+        let Expr::Appl { f: plus, arg: one } = f.as_ref() else {
+            panic!("expected `+` applied to two operands");
+        };
+        assert_eq!(text(&executable.expr), "1 + 2 * 3");
+        assert_eq!(text(plus), "+");
+        assert_eq!(text(one), "1");
+        assert_eq!(text(product), "2 * 3");
+        let Expr::Appl { f, arg: three } = product.as_ref() else {
+            panic!("expected `*` applied to two operands");
+        };
+        let Expr::Appl { f: times, arg: two } = f.as_ref() else {
+            panic!("expected `*` applied to two operands");
+        };
+        assert_eq!((text(times), text(two), text(three)), ("*", "2", "3"));
+
+        let eval = |expr: &str| {
+            let mut executable = compile(expr);
+            executable.normalize = true;
+            executable.eval().unwrap();
+            executable.expr.as_church_numeral()
+        };
+        assert_eq!(eval("1 + 2 + 3"), Some(6));
+        assert_eq!(eval("1 + 2 * 3"), Some(7));
+        assert_eq!(eval("(1 + 2) * 3"), Some(9));
+        assert_eq!(eval("2 ^ 3 ^ 2"), Some(512));
+        assert_eq!(eval("(\\n. n + n) 2 * 3"), Some(12));
+        assert_eq!(eval("(+) 1 2"), Some(3));
+
+        let err = |expr: &str| match compile_program(&format!("{}{}", defs, expr)) {
+            Ok(_)    => panic!("`{}` should not compile", expr),
+            Err(err) => err.messages[0].message.clone(),
+        };
+        assert_eq!(err("1 +"), "expected an operand after `+`");
+        assert_eq!(err("1 + * 2"), "expected an operand before `*`");
+        assert!(err("1 - 2").starts_with("use of undeclared operator `-`"));
+        assert!(err("infix 10 - = Plus\n1").starts_with("the precedence of an operator"));
+        assert!(err("infix 6 - = Plus\n1 + 2 - 3").contains("without parenthesis"));
+        assert_eq!(err("infix 4 == = Plus"), "expected an operator after the precedence");
+
+        // Exported operators keep their fixity, and are parenthesized when
+        // referenced on their own.
+        let exported = compile_program(&format!("{}Twice = \\n. (+) n n\n1", defs)).unwrap().export_macros();
+        assert!(exported.contains("infixl 6 + = Plus\n"), "{}", exported);
+        assert!(exported.contains("Twice = \\a. (+) a a\n"), "{}", exported);
+        let mut executable = compile_program(&format!("{}Twice (1 + 2 * 2)", exported)).unwrap();
+        executable.normalize = true;
+        executable.eval().unwrap();
+        assert_eq!(executable.expr.as_church_numeral(), Some(10));
+    }
+
+    #[test]
+    fn test_compile_program_collect() {
+        let input = "Id = \\a. a\n\
+                     Bad = \\a. b\n\
+                     K = \\a. \\b. Id a\n";
+        let (macros, errors) = compile_program_collect(input);
+        let names: Vec<_> = macros.iter().map(|mac| mac.name()).collect();
+        assert_eq!(names, ["Id", "K"]);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].messages[0].span, Span::new(21, 22));
+
+        // Parse errors don't stop the statements after them either.
+        let (macros, errors) = compile_program_collect("Id = \\a. a\n)\nK = \\a. \\b. a\nK = \\a.");
+        assert_eq!(macros.len(), 2);
+        assert_eq!(errors.len(), 2);
+    }
+
+    #[test]
+    fn test_check_program() {
+        assert!(check_program("Id = \\a. a\nK = \\a. \\b. a\n").is_empty());
+
+        // Unused macros are only reported when there is an expression using them.
+        let errors = check_program("Id = \\a. a\nK = \\a. \\b. a\nId");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].messages[0].message, "macro `K` is never used");
+
+        let errors = check_program("Bad = \\a. b\nId = \\a. c\n");
+        let starts: Vec<_> = errors.iter().map(|err| err.cover_span().start).collect();
+        assert_eq!(starts, [10, 21]);
+    }
+
+    #[test]
+    fn test_semicolon_separated_stmts() {
+        let mut literals = HashSet::new();
+        let mut macros = HashMap::new();
+        // Free variables are not allowed, so `x` and `y` are bound here.
+        match compile_stmt("K = \\a.\\b.a ; \\x. \\y. K x y", &mut literals, &mut macros) {
+            Ok(StmtReturn::Expr(mut expr)) => {
+                expr.eval_to_normal_form().unwrap();
+                assert_eq!(expr.as_church_bool(), Some(true));
+            },
+            Ok(StmtReturn::Macro(_)) => panic!("should be an expr"),
+            Err(err)                 => panic!("failed with error: {}", err),
+        }
+        assert!(macros.contains_key("K"));
+
+        match compile_stmt("Id = \\a. a; I = Id;", &mut literals, &mut macros) {
+            Ok(StmtReturn::Macro(name)) => assert_eq!(name, "I"),
+            _                           => panic!("should define `I`"),
+        }
+
+        let err = match compile_stmt("K x y; Id", &mut literals, &mut macros) {
+            Ok(_)    => panic!("the expression should be the last statement"),
+            Err(err) => err,
+        };
+        assert_eq!(err.messages[0].span, Span::new(7, 9));
+        assert_eq!(err.messages[1].span, Span::new(0, 5));
+    }
+
+    #[test]
+    fn test_comments_between_definitions() {
+        let input = "-- Church booleans\n\
+                     True = \\a. \\b. a -- picks the first\n\
+                     \n\
+                     -- and the second\n\
+                     False = \\a. \\b. b\n\
+                     \x20   -- an indented comment\n\
+                     Not = \\p. p False True\n\
+                     -- finally\n\
+                     Not (Not False)\n\
+                     -- the end\n";
+        let mut executable = compile_program(input).unwrap();
+        assert_eq!(executable.macros.len(), 3);
+        executable.eval().unwrap();
+        assert_eq!(executable.expr.as_church_bool(), Some(false));
+
+        let err = match compile_program("Id = \\a. a\nId\nK = \\a. \\b. a") {
+            Ok(_)    => panic!("the expression should be the last statement"),
+            Err(err) => err,
+        };
+        assert_eq!(err.messages[0].span, Span::new(14, 27));
+        assert!(err.messages[1].is_note());
+        assert_eq!(err.messages[1].span, Span::new(11, 13));
+    }
 }
@@ -1,5 +1,9 @@
 use std::fmt;
 
+/// The exit code of a process that was started with the wrong arguments,
+/// `EX_USAGE` from `sysexits.h`.
+pub const USAGE_EXIT_CODE: u8 = 64;
+
 pub trait Error: fmt::Display {
 
 }
@@ -7,9 +11,14 @@ pub trait Error: fmt::Display {
 #[derive(Debug)]
 pub enum RuntimeError {
     Unknown,
-    NothingEval,
+    /// Holds the innermost sub-term around the `Nothing`, as it was when
+    /// evaluation failed, if there is one.
+    NothingEval(Option<String>),
     RecursionDepthExceeded,
     IterationExceeded,
+    MaxLiteralConcat,
+    Cancelled,
+    OutOfFuel,
 }
 
 impl RuntimeError {
@@ -17,6 +26,21 @@ impl RuntimeError {
     pub fn new() -> RuntimeError {
         RuntimeError::default()
     }
+
+    /// The exit code of a process that stopped because of this error, so
+    /// that scripts can tell the kinds of errors apart. Compile errors exit
+    /// with 2, see `parser::error::Error::exit_code`.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            RuntimeError::Unknown                => 8,
+            RuntimeError::RecursionDepthExceeded |
+            RuntimeError::IterationExceeded      => 3,
+            RuntimeError::NothingEval(_)         => 4,
+            RuntimeError::MaxLiteralConcat       => 5,
+            RuntimeError::Cancelled              => 6,
+            RuntimeError::OutOfFuel              => 7,
+        }
+    }
 }
 
 impl std::default::Default for RuntimeError {
@@ -32,8 +56,11 @@ impl fmt::Display for RuntimeError {
             RuntimeError::Unknown                => {
                 write!(f, "Unknown error")?;
             },
-            RuntimeError::NothingEval            => {
+            RuntimeError::NothingEval(context)   => {
                 write!(f, "Tried to evaluate a nothing expression")?;
+                if let Some(context) = context {
+                    write!(f, " in `{}`", context)?;
+                }
             },
             RuntimeError::RecursionDepthExceeded => {
                 writeln!(f, "Recursion depth exceeded:")?;
@@ -43,9 +70,135 @@ impl fmt::Display for RuntimeError {
                 writeln!(f, "Max eval iterations exceeded:")?;
                 write!(f, "\tIt is possible to find a Weak Head Normal Form, but not a Normal Form.")?;
             },
+            RuntimeError::MaxLiteralConcat       => {
+                write!(f, "Concatenated literal is longer than the maximum allowed length")?;
+            },
+            RuntimeError::Cancelled              => {
+                write!(f, "Evaluation was cancelled")?;
+            },
+            RuntimeError::OutOfFuel              => {
+                write!(f, "Ran out of fuel before reaching the normal form")?;
+            },
         }
         Ok(())
     }
 }
 
 impl Error for RuntimeError {}
+
+/// A macro reference that couldn't be bound to a macro of the environment,
+/// from `Expr::map_macro_refs`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnboundMacro {
+    pub name: String,
+}
+
+impl fmt::Display for UnboundMacro {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Macro `{}` is not defined in the environment", self.name)
+    }
+}
+
+impl Error for UnboundMacro {}
+
+/// A macro whose body has free variables, which can't be defined since a
+/// macro may be expanded anywhere, from `compiler::define_macro`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpenMacro {
+    pub name: String,
+    /// The ids of the free variables, from smallest to largest.
+    pub free_vars: Vec<usize>,
+}
+
+impl fmt::Display for OpenMacro {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let free_vars: Vec<String> = self.free_vars.iter().map(usize::to_string).collect();
+        write!(f, "The body of macro `{}` has free variables: {}", self.name, free_vars.join(", "))
+    }
+}
+
+impl Error for OpenMacro {}
+
+/// Why a program couldn't be loaded from a file.
+#[derive(Debug)]
+pub enum LoadError {
+    Io(std::io::Error),
+    Compile(crate::parser::error::Error),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Io(err)      => write!(f, "Unable to read the program: {}", err),
+            LoadError::Compile(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl Error for LoadError {}
+
+impl LoadError {
+    /// The exit code of a process that couldn't load a program. Errors
+    /// reading the file exit with 1.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            LoadError::Io(_)        => 1,
+            LoadError::Compile(err) => err.exit_code(),
+        }
+    }
+}
+
+impl From<std::io::Error> for LoadError {
+    fn from(err: std::io::Error) -> LoadError {
+        LoadError::Io(err)
+    }
+}
+
+impl From<crate::parser::error::Error> for LoadError {
+    fn from(err: crate::parser::error::Error) -> LoadError {
+        LoadError::Compile(err)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_exit_codes() {
+        assert_eq!(RuntimeError::Unknown.exit_code(), 8);
+        assert_eq!(RuntimeError::RecursionDepthExceeded.exit_code(), 3);
+        assert_eq!(RuntimeError::IterationExceeded.exit_code(), 3);
+        assert_eq!(RuntimeError::NothingEval(None).exit_code(), 4);
+        assert_eq!(RuntimeError::NothingEval(Some("a".to_owned())).exit_code(), 4);
+        assert_eq!(RuntimeError::MaxLiteralConcat.exit_code(), 5);
+        assert_eq!(RuntimeError::Cancelled.exit_code(), 6);
+        assert_eq!(RuntimeError::OutOfFuel.exit_code(), 7);
+
+        let io = std::io::Error::new(std::io::ErrorKind::NotFound, "missing");
+        assert_eq!(LoadError::from(io).exit_code(), 1);
+        let compile = crate::parser::error::Error::new(crate::span::Span::new(0, 1), "bad");
+        assert_eq!(compile.exit_code(), 2);
+        assert_eq!(LoadError::from(compile).exit_code(), 2);
+    }
+
+    #[test]
+    fn test_exit_codes_are_distinct() {
+        let io = std::io::Error::new(std::io::ErrorKind::NotFound, "missing");
+        let compile = crate::parser::error::Error::new(crate::span::Span::new(0, 1), "bad");
+        let codes = [
+            USAGE_EXIT_CODE,
+            LoadError::from(io).exit_code(),
+            LoadError::from(compile).exit_code(),
+            RuntimeError::Unknown.exit_code(),
+            RuntimeError::RecursionDepthExceeded.exit_code(),
+            RuntimeError::NothingEval(None).exit_code(),
+            RuntimeError::MaxLiteralConcat.exit_code(),
+            RuntimeError::Cancelled.exit_code(),
+            RuntimeError::OutOfFuel.exit_code(),
+        ];
+        let distinct: std::collections::HashSet<_> = codes.iter().collect();
+        assert_eq!(distinct.len(), codes.len(), "{:?}", codes);
+        assert!(!codes.contains(&0));
+    }
+}
@@ -1,41 +1,196 @@
 use std::collections::{ HashSet, HashMap };
-use std::rc::Rc;
-use std::ptr::NonNull;
+use std::convert::TryInto;
 
-use crate::error::RuntimeError;
+use crate::error::{ LoadError, OpenMacro, RuntimeError, UnboundMacro };
+use crate::shared::Rc;
+use crate::shared_expr::SubExpr;
+use crate::span::Span;
 
 const MAX_EVAL_DEPTH: usize = 64;
+// Number of levels of an expression `Expr::eval_parallel` may start new
+// threads in, so there are at most 2^MAX_PARALLEL_DEPTH of them.
+#[cfg(feature = "parallel")]
+const MAX_PARALLEL_DEPTH: usize = 4;
+// How deep evaluation may go into lambda bodies and arguments while looking for
+// the normal form.
+const MAX_NORMALIZE_DEPTH: usize = 1024;
+// How many reduction steps `Expr::normal_order_eval` may take.
+const MAX_NORMAL_ORDER_STEPS: usize = 1 << 16;
+/// Default for `EvalContext::max_literal_len`.
+pub const DEFAULT_MAX_LITERAL_LEN: usize = 1 << 20;
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Expr {
     // TODO: Allow for a parameter list.
     Lambda {
         param: usize,
-        expr: Box<Expr>,
+        expr: SubExpr,
+        /// Name of the parameter in the source, see `VarName`.
+        name: VarName,
     },
     // TODO: Make this into a vec of expression
     Appl {
-        f: Box<Expr>,
-        arg: Box<Expr>,
+        f: SubExpr,
+        arg: SubExpr,
     },
     MacroRef(Rc<Macro>),
     Var(usize),
     Literal(Rc<String>),
+    Intrinsic(Intrinsic),
     Nothing,
+    /// A `Nothing` that remembers the source span of the node it replaced, so
+    /// that evaluating it can be reported there. See `Executable::eval`.
+    Hole(Span),
+}
+
+/// The name a lambda parameter had in the source, if it came from there. It
+/// is only used for display, and moves with the lambda through substitution
+/// and renumbering, which ids don't survive. Expressions that only differ in
+/// it are still equal.
+#[derive(Debug, Clone, Default)]
+pub struct VarName(pub Option<Rc<str>>);
+
+impl PartialEq for VarName {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for VarName {}
+
+impl std::hash::Hash for VarName {
+    fn hash<H: std::hash::Hasher>(&self, _state: &mut H) {
+        // Must agree with `PartialEq`, which ignores the name.
+    }
+}
+
+/// Operations implemented by the interpreter itself, rather than as lambda
+/// terms.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Intrinsic {
+    /// `IF c t e` reduces to `t` if `c` is the Church boolean `true` and to `e`
+    /// if it is `false`, without evaluating the other branch. Any other `c` is
+    /// just applied to `t` and `e`.
+    If,
+    /// `CONCAT a b` evaluates both `a` and `b` and, if they are literals,
+    /// reduces to a literal with the contents of both.
+    Concat,
+}
+
+impl Intrinsic {
+    pub fn from_name(name: &str) -> Option<Intrinsic> {
+        match name {
+            "IF"     => Some(Intrinsic::If),
+            "CONCAT" => Some(Intrinsic::Concat),
+            _        => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Intrinsic::If     => "IF",
+            Intrinsic::Concat => "CONCAT",
+        }
+    }
 }
 
 pub struct Macro {
     pub expr: Expr,
-    name: NonNull<str>,
+    name: Rc<str>,
+    fixity: Option<Fixity>,
+    private: bool,
+    // Whether `expr` is in normal form, computed once here because checking
+    // it through every reference to other macros can take exponential time.
+    normal_form: bool,
+}
+
+/// How an operator is applied when it is written between its operands.
+/// Operators with a higher precedence are applied first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fixity {
+    pub assoc: Assoc,
+    pub precedence: u8,
+}
+
+/// Which way a chain of operators with the same precedence is grouped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Assoc {
+    /// `a + b + c` is `(a + b) + c`, declared with `infixl`.
+    Left,
+    /// `a + b + c` is `a + (b + c)`, declared with `infixr`.
+    Right,
+    /// `a + b + c` is an error, declared with `infix`.
+    None,
+}
+
+impl Assoc {
+    pub fn from_keyword(keyword: &str) -> Option<Assoc> {
+        match keyword {
+            "infixl" => Some(Assoc::Left),
+            "infixr" => Some(Assoc::Right),
+            "infix"  => Some(Assoc::None),
+            _        => None,
+        }
+    }
+
+    pub fn keyword(&self) -> &'static str {
+        match self {
+            Assoc::Left  => "infixl",
+            Assoc::Right => "infixr",
+            Assoc::None  => "infix",
+        }
+    }
 }
 
 impl Macro {
-    pub fn new(expr: Expr, name: NonNull<str>) -> Macro {
-        Macro { expr, name }
+    pub fn new(expr: Expr, name: impl Into<Rc<str>>) -> Macro {
+        let normal_form = expr.is_normal_form();
+        Macro { expr, name: name.into(), fixity: None, private: false, normal_form }
+    }
+
+    /// Makes the macro an operator, like `+`.
+    pub fn with_fixity(mut self, fixity: Fixity) -> Macro {
+        self.fixity = Some(fixity);
+        self
     }
 
-    unsafe fn get_name(&self) -> &str {
-        self.name.as_ref()
+    /// Makes the macro private, declared with `priv`, or public.
+    pub fn with_private(mut self, private: bool) -> Macro {
+        self.private = private;
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The expression the macro stands for.
+    pub fn body(&self) -> &Expr {
+        &self.expr
+    }
+
+    /// Whether the macro was declared with `priv`. Private macros are only
+    /// exported when a public one needs them.
+    pub fn is_private(&self) -> bool {
+        self.private
+    }
+
+    /// Whether the body is in normal form, see `Expr::is_normal_form`.
+    pub fn is_normal_form(&self) -> bool {
+        self.normal_form
+    }
+
+    /// The fixity of the macro, if it is an operator.
+    pub fn fixity(&self) -> Option<Fixity> {
+        self.fixity
+    }
+
+    // Operators have to be parenthesized when they are not between operands.
+    fn write_name(&self, out: &mut impl std::fmt::Write) -> std::fmt::Result {
+        match self.fixity {
+            Some(_) => write!(out, "({})", self.name),
+            None    => write!(out, "{}", self.name),
+        }
     }
 }
 
@@ -45,50 +200,469 @@ impl PartialEq for Macro {
     }
 }
 
+impl std::hash::Hash for Macro {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        // Must agree with `PartialEq`, which only looks at the body.
+        self.expr.hash(state);
+    }
+}
+
 impl std::fmt::Debug for Macro {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let name = unsafe { self.name.as_ref() };
-        write!(f, "def {} = {:?}", name, self.expr)
+        write!(f, "def {} = {:?}", self.name, self.expr)
     }
 }
 
 impl Eq for Macro {}
 
+// The macros used by `Expr::to_combinator_form`.
+struct Combinators {
+    s: Rc<Macro>,
+    k: Rc<Macro>,
+    i: Rc<Macro>,
+}
+
+impl Combinators {
+    fn new() -> Combinators {
+        let lambda = Expr::lam;
+        let appl = |f, arg| Expr::Appl { f: SubExpr::new(f), arg: SubExpr::new(arg) };
+
+        // S = \a. \b. \c. a c (b c)
+        let s = lambda(0, lambda(1, lambda(2, appl(
+            appl(Expr::Var(0), Expr::Var(2)),
+            appl(Expr::Var(1), Expr::Var(2)),
+        ))));
+        // K = \a. \b. a
+        let k = lambda(0, lambda(1, Expr::Var(0)));
+        // I = \a. a
+        let i = lambda(0, Expr::Var(0));
+
+        Combinators {
+            s: Rc::new(Macro::new(s, "S")),
+            k: Rc::new(Macro::new(k, "K")),
+            i: Rc::new(Macro::new(i, "I")),
+        }
+    }
+
+    fn s(&self) -> Expr {
+        Expr::MacroRef(Rc::clone(&self.s))
+    }
+
+    fn k(&self) -> Expr {
+        Expr::MacroRef(Rc::clone(&self.k))
+    }
+
+    fn i(&self) -> Expr {
+        Expr::MacroRef(Rc::clone(&self.i))
+    }
+}
+
+/// Generic traversal of an expression tree. Every method is called when its
+/// kind of node is found by `Expr::walk`, and by default just visits the
+/// children, so implementors only need to override the nodes they care
+/// about. Macro bodies are not visited by default. It is meant for analyses;
+/// printing, which depends on where each node is, and evaluation, which
+/// changes the tree, are written by hand.
+pub trait ExprVisitor {
+    fn visit_lambda(&mut self, _param: usize, body: &Expr) {
+        body.walk(self);
+    }
+
+    fn visit_appl(&mut self, f: &Expr, arg: &Expr) {
+        f.walk(self);
+        arg.walk(self);
+    }
+
+    fn visit_var(&mut self, _var: usize) {}
+
+    fn visit_literal(&mut self, _literal: &Rc<String>) {}
+
+    fn visit_macro(&mut self, _mac: &Rc<Macro>) {}
+
+    fn visit_intrinsic(&mut self, _intrinsic: Intrinsic) {}
+
+    /// Every `Nothing` is equal to the others, so the node itself is passed
+    /// to tell them apart, like in `SourceMap::get`.
+    fn visit_nothing(&mut self, _nothing: &Expr) {}
+
+    fn visit_hole(&mut self, _span: Span) {}
+}
+
+/// Maps compiled nodes back to the spans of the AST nodes they came from.
+/// Nodes are identified by address, which is stable for everything that lives
+/// inside a `Box`. The root is kept apart because its address changes every
+/// time the `Executable` that owns it is moved.
+///
+/// Evaluation frees nodes and reuses their addresses, so the map only
+/// describes the tree as it was compiled. `Executable::eval` clears it.
+#[derive(Default)]
+pub struct SourceMap {
+    pub root: Option<Span>,
+    nodes: HashMap<*const Expr, Span>,
+}
+
+impl SourceMap {
+    pub fn new(root: Span) -> SourceMap {
+        SourceMap { root: Some(root), nodes: HashMap::new() }
+    }
+
+    /// Records the span of a node. If the node already has a span, the first
+    /// one is kept, since it is the outermost one (groupings compile to the
+    /// same node as their content).
+    pub fn insert(&mut self, expr: &Expr, span: Span) {
+        self.nodes.entry(expr as *const Expr).or_insert(span);
+    }
+
+    pub fn get(&self, expr: &Expr) -> Option<Span> {
+        self.nodes.get(&(expr as *const Expr)).copied()
+    }
+}
+
+/// How a reduction with a step limit ended, see
+/// `Expr::reduce_to_fixpoint_with_limit`.
+#[derive(Debug)]
+pub enum EvalOutcome {
+    /// The expression is in normal form, after the given number of steps.
+    Normalized(usize),
+    /// The limit was reached first. The expression might not have a normal
+    /// form at all.
+    LimitReached,
+    Error(RuntimeError),
+}
+
+/// State that is threaded through an evaluation.
+pub struct EvalContext<'a> {
+    /// Number of beta-reductions performed so far.
+    pub reductions: usize,
+    /// When present, maps alpha-converted applications to the result of
+    /// evaluating them, so that repeated subterms are only reduced once. The
+    /// flag in the key tells whether macros in the result were expanded,
+    /// since a term in head position must not be left as a macro reference.
+    pub memo: Option<HashMap<(Expr, bool), Expr>>,
+    /// Go on under lambdas and into the arguments of applications whose head
+    /// can't be reduced, until the normal form is reached. Otherwise
+    /// evaluation stops at Weak Head Normal Form.
+    pub normalize: bool,
+    /// Longest literal that `CONCAT` may build, so that a program can't make
+    /// a string grow without bound.
+    pub max_literal_len: usize,
+    /// Keep references to macros that are already in normal form, like
+    /// `And True`, instead of expanding them into their bodies. Macros that
+    /// are applied are always expanded, since they have to be reduced.
+    pub preserve_macros: bool,
+    /// Whether the argument of an application is evaluated before being
+    /// substituted, and if so, whether before or after the function.
+    pub arg_order: ArgOrder,
+    normalize_depth: usize,
+    progress: Option<Progress<'a>>,
+    trace: Option<Trace<'a>>,
+    // Reductions that may still be performed, if limited.
+    fuel: Option<&'a mut u64>,
+    /// Source span of the `Expr::Hole` that stopped the evaluation with
+    /// `RuntimeError::NothingEval`, if that is what happened.
+    pub hole_span: Option<Span>,
+}
+
+/// When the argument of an application is evaluated by `eval_with`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ArgOrder {
+    /// Arguments are substituted as they are, and only evaluated where they
+    /// end up, if they are needed at all.
+    #[default]
+    Lazy,
+    /// The function is evaluated first, and then the argument, before it is
+    /// substituted.
+    LeftToRight,
+    /// The argument is evaluated first, and then the function.
+    RightToLeft,
+}
+
+// Called with the whole expression after each reduction, see
+// `EvalContext::with_trace`.
+type Trace<'a> = Box<dyn FnMut(&Expr) + 'a>;
+
+// Reports progress every `every` reductions. Evaluation is cancelled as soon
+// as the callback returns `false`.
+struct Progress<'a> {
+    every: usize,
+    callback: Box<dyn FnMut(usize) -> bool + 'a>,
+}
+
+impl Default for EvalContext<'_> {
+    fn default() -> Self {
+        EvalContext {
+            reductions: 0,
+            memo: None,
+            normalize: false,
+            max_literal_len: DEFAULT_MAX_LITERAL_LEN,
+            preserve_macros: true,
+            arg_order: ArgOrder::default(),
+            normalize_depth: 0,
+            progress: None,
+            trace: None,
+            fuel: None,
+            hole_span: None,
+        }
+    }
+}
+
+impl<'a> EvalContext<'a> {
+    pub fn new() -> Self {
+        EvalContext::default()
+    }
+
+    pub fn memoized() -> Self {
+        EvalContext { memo: Some(HashMap::new()), ..EvalContext::default() }
+    }
+
+    /// A context that evaluates to normal form, under binders too, instead
+    /// of stopping at weak head normal form.
+    pub fn normalizing() -> Self {
+        EvalContext { normalize: true, ..EvalContext::default() }
+    }
+
+    /// Calls `callback` with the number of reductions so far every `every`
+    /// reductions. Returning `false` from it cancels the evaluation with
+    /// `RuntimeError::Cancelled`.
+    pub fn with_progress<F>(mut self, every: usize, callback: F) -> Self
+    where
+        F: FnMut(usize) -> bool + 'a,
+    {
+        assert!(every > 0, "progress can't be reported every 0 reductions");
+        self.progress = Some(Progress { every, callback: Box::new(callback) });
+        self
+    }
+
+    /// Calls `callback` after every reduction with the subterm it produced,
+    /// to observe the evaluation without changing how it goes.
+    pub fn with_trace<F>(mut self, callback: F) -> Self
+    where
+        F: FnMut(&Expr) + 'a,
+    {
+        self.trace = Some(Box::new(callback));
+        self
+    }
+
+    /// Limits evaluation to `fuel` reductions. Each one takes a unit of
+    /// fuel, and when there is none left evaluation stops with
+    /// `RuntimeError::OutOfFuel`. The fixed limit on reduction steps doesn't
+    /// apply then, since the fuel already bounds it.
+    pub fn with_fuel(mut self, fuel: &'a mut u64) -> Self {
+        self.fuel = Some(fuel);
+        self
+    }
+
+    // Takes a unit of fuel for a reduction that is about to happen.
+    fn spend_fuel(&mut self) -> Result<(), RuntimeError> {
+        match &mut self.fuel {
+            Some(fuel) if **fuel == 0 => Err(RuntimeError::OutOfFuel),
+            Some(fuel)                => {
+                **fuel -= 1;
+                Ok(())
+            },
+            None                      => Ok(()),
+        }
+    }
+
+    // Counts one more reduction, which produced `reduced`, traces it and
+    // reports progress if it is time to.
+    fn count_reduction(&mut self, reduced: &Expr) -> Result<(), RuntimeError> {
+        self.reductions += 1;
+        if let Some(trace) = &mut self.trace {
+            trace(reduced);
+        }
+        match &mut self.progress {
+            Some(progress) if self.reductions.is_multiple_of(progress.every) => {
+                if (progress.callback)(self.reductions) {
+                    Ok(())
+                } else {
+                    Err(RuntimeError::Cancelled)
+                }
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
 pub struct Executable {
     pub expr: Expr,
-    // No entries should be removed from this hashmap.
     pub macros: HashMap<String, Rc<Macro>>,
     pub literals: HashSet<Rc<String>>,
+    pub source_map: SourceMap,
+    /// Source span of the subterm that caused the last evaluation error, if
+    /// it could be found.
+    pub error_span: Option<Span>,
+    /// Whether evaluation should reuse the results of identical subterms.
+    pub memoize: bool,
+    /// Whether evaluation goes on to the normal form, see
+    /// `EvalContext::normalize`.
+    pub normalize: bool,
+    /// Number of beta-reductions performed by the last evaluation.
+    pub reductions: usize,
 }
 
 impl Executable {
     pub fn new(expr: Expr, macros: HashMap<String, Rc<Macro>>, literals: HashSet<Rc<String>>) -> Executable {
-        Executable { expr, macros, literals }
+        Executable {
+            expr,
+            macros,
+            literals,
+            source_map: SourceMap::default(),
+            error_span: None,
+            memoize: false,
+            normalize: false,
+            reductions: 0,
+        }
     }
 
+    /// Reads a program from a file and compiles it, ready to be evaluated.
+    pub fn from_file<P: AsRef<std::path::Path>>(path: P) -> Result<Executable, LoadError> {
+        let source = std::fs::read_to_string(path)?;
+        Ok(crate::compiler::compile_program(&source)?)
+    }
+
+    /// Displays the expression using the names its variables had in the
+    /// source, as far as they are known. The names are kept through
+    /// evaluation, variables created by it are written as usual, and names
+    /// that would clash get a numeric suffix.
+    pub fn display_named(&self, style: DisplayStyle) -> StyledExpr<'_> {
+        StyledExpr { expr: &self.expr, style, named: true, church: false }
+    }
+
+    /// Defines a macro in the environment, replacing any previous definition
+    /// with the same name, and returns it so that it can be referenced with
+    /// `Expr::MacroRef`. The body must be closed, otherwise nothing is
+    /// defined and the error tells which variables are free.
+    pub fn add_macro(&mut self, name: String, body: Expr) -> Result<Rc<Macro>, OpenMacro> {
+        crate::compiler::define_macro(&mut self.macros, &name, body)
+    }
+
+    /// Evaluates the expression. If it fails on a `Nothing` that has a source
+    /// span, `error_span` is set to it. The source map no longer describes
+    /// the tree afterwards, so it is cleared.
     pub fn eval(&mut self) -> Result<&mut Executable, RuntimeError> {
-        match self.expr.eval() {
+        // Evaluation moves nodes around, so the holes take their spans with
+        // them before it starts.
+        self.mark_holes();
+        self.source_map = SourceMap::default();
+        self.error_span = None;
+        let mut ctx = if self.memoize {
+            EvalContext::memoized()
+        } else {
+            EvalContext::new()
+        };
+        ctx.normalize = self.normalize;
+        let result = self.expr.eval_with(&mut ctx).map(|_| ());
+        self.reductions = ctx.reductions;
+        match result {
             Ok(_)  => Ok(self),
-            Err(e) => Err(e),
+            Err(e) => {
+                self.error_span = ctx.hole_span;
+                Err(e)
+            },
         }
     }
-}
 
-fn memapply<T, F: FnOnce(T) -> T>(dest: &mut T, f: F) {
-    // This is ok because the return value of `f` is a valid value of type T.
-    // This means that, even though for a moment `dest` receives an unsafe
-    // value, right away it gets replaced by a valid one. It also forgets the
-    // zeroed value so no destructor is called.
-    unsafe {
-        let zeroed = std::mem::zeroed();
-        let owned = std::mem::replace(dest, zeroed);
-        std::mem::forget(std::mem::replace(dest, f(owned)));
+    // Turns every `Nothing` with a known span into an `Expr::Hole`.
+    fn mark_holes(&mut self) {
+        let source_map = &self.source_map;
+        if let Expr::Nothing = self.expr {
+            if let Some(span) = source_map.root {
+                self.expr = Expr::Hole(span);
+            }
+            return;
+        }
+        let mut stack = vec![&mut self.expr];
+        while let Some(curr) = stack.pop() {
+            match curr {
+                Expr::Nothing                => {
+                    if let Some(span) = source_map.get(curr) {
+                        *curr = Expr::Hole(span);
+                    }
+                },
+                Expr::Lambda { expr, .. }    => stack.push(expr),
+                Expr::Appl { f, arg }        => {
+                    stack.push(arg);
+                    stack.push(f);
+                },
+                Expr::MacroRef(_)  |
+                Expr::Var(_)       |
+                Expr::Literal(_)   |
+                Expr::Intrinsic(_) |
+                Expr::Hole(_)                => (),
+            }
+        }
+    }
+
+    /// Gets the source span of a node in the current expression tree.
+    pub fn span_of(&self, node: &Expr) -> Option<Span> {
+        if std::ptr::eq(node, &self.expr) {
+            self.source_map.root
+        } else {
+            self.source_map.get(node)
+        }
+    }
+
+    /// Writes every macro definition as source code. See `export_macros`.
+    pub fn export_macros(&self) -> String {
+        export_macros(&self.macros)
+    }
+
+    /// Source spans of all `Nothing` holes in the expression.
+    pub fn hole_spans(&self) -> Vec<Span> {
+        struct HoleSpans<'a> {
+            executable: &'a Executable,
+            spans: Vec<Span>,
+        }
+
+        impl ExprVisitor for HoleSpans<'_> {
+            fn visit_nothing(&mut self, nothing: &Expr) {
+                self.spans.extend(self.executable.span_of(nothing));
+            }
+
+            fn visit_hole(&mut self, span: Span) {
+                self.spans.push(span);
+            }
+        }
+
+        let mut visitor = HoleSpans { executable: self, spans: Vec::new() };
+        self.expr.walk(&mut visitor);
+        visitor.spans
     }
 }
 
+/// Replaces `dest` by `f` applied to its value. While `f` runs, `dest` holds
+/// `T::default()`, so it is never left with an invalid value, even if `f`
+/// panics.
+fn memapply<T: Default, F: FnOnce(T) -> T>(dest: &mut T, f: F) {
+    let owned = std::mem::take(dest);
+    *dest = f(owned);
+}
+
 
 use std::borrow::Cow;
 impl Expr {
+    /// Builds a lambda, `λparam. body`.
+    pub fn lam(param: usize, body: Expr) -> Expr {
+        Expr::Lambda { param, expr: SubExpr::new(body), name: VarName::default() }
+    }
+
+    /// Builds an application, `f arg`.
+    pub fn app(f: Expr, arg: Expr) -> Expr {
+        Expr::Appl { f: SubExpr::new(f), arg: SubExpr::new(arg) }
+    }
+
+    /// Builds a reference to the variable with id `v`.
+    pub fn var(v: usize) -> Expr {
+        Expr::Var(v)
+    }
+
+    /// Builds a string literal. Unlike the ones from the compiler, it is not
+    /// interned.
+    pub fn lit<S: Into<String>>(s: S) -> Expr {
+        Expr::Literal(Rc::new(s.into()))
+    }
+
     /// Verifies if an expression is in Weak Head Normal Form.
     /// An expression is in WHNF if all of the "left hand side" things are
     /// evaluated.
@@ -96,8 +670,10 @@ impl Expr {
         let mut next = Some(self);
         while let Some(curr) = next.take() {
             match curr {
-                Expr::Nothing    |
-                Expr::Literal(_) |
+                Expr::Nothing      |
+                Expr::Hole(_)      |
+                Expr::Literal(_)   |
+                Expr::Intrinsic(_) |
                 Expr::Var(_)           => return true,
                 Expr::Appl { f, .. }   => next = Some(f),
                 Expr::MacroRef(mac)    => next = Some(&mac.as_ref().expr),
@@ -110,226 +686,1667 @@ impl Expr {
     }
 
     /// Verifies if an expression is n-reducible.
-    /// An expression is n-reducible in case it is an expression like \a. f a.
-    /// In this case, the n-reduction would be \a. f a -> f, meaning that the
-    /// initial expression was n-reducible.
+    /// An expression is n-reducible in case it is an expression like \a. f a,
+    /// where `a` is not used by `f`. In this case, the n-reduction would be
+    /// \a. f a -> f, meaning that the initial expression was n-reducible.
     pub fn is_n_reducible(&self) -> bool {
         match self {
-            Expr::Lambda {
-                param,
-                expr: box Expr::Appl {
-                    arg: box Expr::Var(arg_var),
-                    ..
-                },
-            } if param == arg_var => true,
-            _                     => false,
+            Expr::Lambda { param, expr, .. } => match expr.as_ref() {
+                Expr::Appl { f, arg } => **arg == Expr::Var(*param) && !f.occurs_free(*param),
+                _                     => false,
+            },
+            _                                => false,
+        }
+    }
+
+    /// Performs one n-reduction (eta-reduction) at the root of the
+    /// expression, turning \a. f a into f, if `a` is not used by `f`. Returns
+    /// whether the expression was reduced.
+    pub fn eta_reduce(&mut self) -> bool {
+        if !self.is_n_reducible() {
+            return false;
+        }
+        if let Expr::Lambda { expr, .. } = self.take() {
+            if let Expr::Appl { f, .. } = expr.into_inner() {
+                self.replace(f.into_inner());
+            }
+        }
+        true
+    }
+
+    /// The inverse of `eta_reduce`, turns f into \a. f a, where `a` is a
+    /// variable that is not used anywhere in f.
+    pub fn eta_expand(&mut self) {
+        let param = self.get_biggest_var_id().map_or(0, |v| v + 1);
+        let f = self.take();
+        self.replace(Expr::Lambda {
+            param,
+            expr: SubExpr::new(Expr::Appl {
+                f: SubExpr::new(f),
+                arg: SubExpr::new(Expr::Var(param)),
+            }),
+            name: VarName::default(),
+        });
+    }
+
+    /// Splits a chain of applications like `f a b c` into its head, `f`, and
+    /// its arguments in the order they are applied, `[a, b, c]`. Anything that
+    /// is not an application is a head with no arguments.
+    pub fn flatten_appl(&self) -> (&Expr, Vec<&Expr>) {
+        let mut head = self;
+        let mut args = Vec::new();
+        while let Expr::Appl { f, arg } = head {
+            args.push(arg.as_ref());
+            head = f;
+        }
+        args.reverse();
+        (head, args)
+    }
+
+    // The intrinsic at the head of the expression and the number of arguments
+    // it is applied to, if the head is an intrinsic.
+    fn intrinsic_call(&self) -> Option<(Intrinsic, usize)> {
+        let mut head = self;
+        let mut args = 0;
+        while let Expr::Appl { f, .. } = head {
+            head = f;
+            args += 1;
+        }
+        match head {
+            Expr::Intrinsic(intrinsic) => Some((*intrinsic, args)),
+            _                          => None,
         }
     }
 
+    // The arguments of an intrinsic that is applied to exactly `N` of them,
+    // see `intrinsic_call`, in the order they are applied.
+    fn intrinsic_args_mut<const N: usize>(&mut self) -> [&mut SubExpr; N] {
+        let mut head = self;
+        let mut args = Vec::with_capacity(N);
+        while let Expr::Appl { f, arg } = head {
+            args.push(arg);
+            head = f;
+        }
+        args.reverse();
+        args.try_into().unwrap_or_else(|_| panic!("expected an intrinsic applied to {} arguments", N))
+    }
+
+    /// The inverse of `flatten_appl`, applies `head` to each of `args` in
+    /// order, building `head a b c` out of `head` and `[a, b, c]`.
+    pub fn rebuild_spine(head: Expr, args: Vec<Expr>) -> Expr {
+        args.into_iter().fold(head, Expr::app)
+    }
+
+    /// Checks if the expression is a value, that is, something evaluation
+    /// has nothing left to do with: a lambda, a literal, an intrinsic, a
+    /// variable, or a variable or literal stuck applied to values. Unlike
+    /// `is_normal_form`, bodies of lambdas are not looked into, and an
+    /// application with any redex in it is never a value.
+    pub fn is_value(&self) -> bool {
+        match self {
+            Expr::Lambda { .. } |
+            Expr::Literal(_)    |
+            Expr::Intrinsic(_)  |
+            Expr::Var(_)          => true,
+            Expr::MacroRef(mac)   => mac.expr.is_value(),
+            Expr::Appl { .. }     => {
+                let (head, args) = self.flatten_appl();
+                args.iter().all(|arg| arg.is_value())
+                    && matches!(head.deref_macros(), Expr::Var(_) | Expr::Literal(_))
+            },
+            Expr::Nothing         |
+            Expr::Hole(_)         => false,
+        }
+    }
+
+    /// Checks that there is no beta-redex nor eta-redex anywhere in the
+    /// expression, including in the macros it references.
     pub fn is_normal_form(&self) -> bool {
         match self {
-            Expr::Nothing    |
-            Expr::Literal(_) |
+            Expr::Nothing      |
+            Expr::Hole(_)      |
+            Expr::Literal(_)   |
+            Expr::Intrinsic(_) |
             Expr::Var(_)                 => true,
-            Expr::Appl { f, arg }        => f.is_normal_form() && arg.is_normal_form(),
-            Expr::MacroRef(mac)          => mac.as_ref().expr.is_normal_form(),
+            Expr::Appl { f, arg }        => {
+                !matches!(f.deref_macros(), Expr::Lambda { .. })
+                    && f.is_normal_form()
+                    && arg.is_normal_form()
+            },
+            Expr::MacroRef(mac)          => mac.is_normal_form(),
             lamb@Expr::Lambda {..}
                 if lamb.is_n_reducible() => false,
-            Expr::Lambda{..}             => true,
+            Expr::Lambda { expr, .. }    => expr.is_normal_form(),
         }
     }
 
     /// Converts all used variables so that the ones in the top of the tree will
     /// start at 0 and increase in valua as they go down the expression tree.
     /// This is necessary in order to compare two different expressions.
+    /// Free variables are kept, and bound ones are numbered after them, so
+    /// that none is captured.
     pub fn alpha_convert(&mut self) {
-        self.alpha_convert_from(0);
+        self.alpha_convert_from(self.free_var_floor());
+    }
+
+    // The smallest id above every free variable, so that lambdas numbered
+    // from it can't capture any of them.
+    fn free_var_floor(&self) -> usize {
+        self.free_vars().into_iter().max().map_or(0, |v| v + 1)
+    }
+
+    /// Renumbers the bound variables so that they are as small as possible,
+    /// which keeps their displayed names near the start of the alphabet. The
+    /// parameter of a lambda gets the number of lambdas around it, so sibling
+    /// lambdas share names. Free variables are kept, and bound ones are
+    /// numbered after them, so that none is captured.
+    pub fn normalize_bound_names(&mut self) {
+        self.alpha_convert();
     }
 
     pub fn alpha_convert_from(&mut self, start: usize) {
         // Cow is used so the vec is not cloned unless it is really needed.
         let conversion_table = Cow::Owned(Vec::new());
         self.alpha_convert_with_table(conversion_table, start);
+
+        // The conversion relies on parameters growing down the tree, which is
+        // only kept by construction. If it wasn't, converting again would
+        // give a different result.
+        #[cfg(debug_assertions)]
+        {
+            let mut again = self.clone();
+            again.alpha_convert_with_table(Cow::Owned(Vec::new()), start);
+            assert!(*self == again, "alpha conversion is not idempotent: `{}` became `{}`", self, again);
+        }
     }
 
     fn alpha_convert_with_table(&mut self, mut conversion_table: Cow<Vec<usize>>, start: usize) {
         match self {
-            Expr::Literal(_)  |
-            Expr::MacroRef(_) | // Macros are already always alpha simplified.
-            Expr::Nothing         => (),
+            Expr::Literal(_)   |
+            Expr::Intrinsic(_) |
+            Expr::MacroRef(_)  | // Macros are already always alpha simplified.
+            Expr::Nothing          |
+            Expr::Hole(_)          => (),
             Expr::Appl { f, arg } => {
                 // This clone is necessary because we can't let the local
                 // variables that may be defined in expression `f` to be used
                 // in the `arg` expression.
-                f.alpha_convert_with_table(Cow::Borrowed(conversion_table.as_ref()), start);
-                arg.alpha_convert_with_table(conversion_table, start);
+                if needs_alpha_convert(f, &conversion_table, start) {
+                    f.alpha_convert_with_table(Cow::Borrowed(conversion_table.as_ref()), start);
+                }
+                if needs_alpha_convert(arg, &conversion_table, start) {
+                    arg.alpha_convert_with_table(conversion_table, start);
+                }
             },
-            Expr::Lambda { param, expr } => {
+            Expr::Lambda { param, expr, .. } => {
                 // This is ok because a new parameter found in the tree will
                 // always have a larger value then its predecessors, so the
                 // `conversion_table` will remain sorted.
                 conversion_table.to_mut().push(*param);
                 *param = conversion_table.len() - 1 + start;
-                expr.alpha_convert_with_table(conversion_table, start);
+                if needs_alpha_convert(expr, &conversion_table, start) {
+                    expr.alpha_convert_with_table(conversion_table, start);
+                }
             },
             Expr::Var(v) => {
                 assert!(conversion_table.is_sorted());
-                let pos = conversion_table
-                    .binary_search(v)
-                    .expect("Found var not present in conversion table.");
-
-                *v = pos + start;
+                // Variables that are not in the table are free, and are bound
+                // by some lambda outside of this expression, so they are kept.
+                if let Ok(pos) = conversion_table.binary_search(v) {
+                    *v = pos + start;
+                }
             },
         }
     }
 
-    pub fn get_biggest_var_id(&self) -> Option<usize> {
+    // Whether `alpha_convert_with_table` would leave the expression as it is,
+    // `depth` being the number of lambdas between it and the ones in the
+    // table.
+    fn is_alpha_converted(&self, conversion_table: &[usize], start: usize, depth: usize) -> bool {
+        let base = conversion_table.len() + start;
         match self {
-            Expr::Nothing    |
-            Expr::Literal(_)            => None,
-            Expr::MacroRef(mac)         => mac.as_ref().expr.get_biggest_var_id(),
-            Expr::Appl { f, arg }       => {
-                f.get_biggest_var_id()
-                    .map(|v| {
-                        arg.get_biggest_var_id()
-                            .map_or(v, |v2| std::cmp::max(v, v2))
-                    })
+            Expr::Lambda { param, expr, .. } => {
+                *param == base + depth && expr.is_alpha_converted(conversion_table, start, depth + 1)
+            },
+            Expr::Appl { f, arg }            => {
+                f.is_alpha_converted(conversion_table, start, depth)
+                    && arg.is_alpha_converted(conversion_table, start, depth)
             },
-            Expr::Lambda { param, expr } => {
-                expr.get_biggest_var_id()
-                    .map(|v| std::cmp::max(*param, v))
-                    .or(Some(*param))
+            // Variables bound inside are numbered right if their lambdas are.
+            Expr::Var(v) if (base..base + depth).contains(v) => true,
+            Expr::Var(v)                     => match conversion_table.binary_search(v) {
+                Ok(pos) => pos + start == *v,
+                Err(_)  => true,
             },
-            Expr::Var(v)                => Some(*v),
+            _                                => true,
         }
     }
 
-    // Perform beta-reduction all the way to normal form.
-    pub fn eval(&mut self) -> Result<&mut Expr, RuntimeError> {
-        self.eval_depth(0, false)
-    }
-
-    pub fn eval_depth(&mut self, depth: usize, eval_macros: bool) -> Result<&mut Expr, RuntimeError> {
-        if depth > MAX_EVAL_DEPTH {
-            return Err(RuntimeError::RecursionDepthExceeded);
+    /// Calls the method of `visitor` that corresponds to this node.
+    pub fn walk<V: ExprVisitor + ?Sized>(&self, visitor: &mut V) {
+        match self {
+            Expr::Lambda { param, expr, .. } => visitor.visit_lambda(*param, expr),
+            Expr::Appl { f, arg }        => visitor.visit_appl(f, arg),
+            Expr::Var(v)                 => visitor.visit_var(*v),
+            Expr::Literal(lit)           => visitor.visit_literal(lit),
+            Expr::MacroRef(mac)          => visitor.visit_macro(mac),
+            Expr::Intrinsic(intrinsic)   => visitor.visit_intrinsic(*intrinsic),
+            Expr::Nothing                => visitor.visit_nothing(self),
+            Expr::Hole(span)             => visitor.visit_hole(*span),
         }
+    }
 
-        for _ in 0..MAX_EVAL_DEPTH {
-            match self {
-                Expr::Literal(_) |
-                Expr::Var(_)        => return Ok(self),
-                Expr::Lambda { .. } => {
-                    /*
-                    // NOTE: Maybe this should happen...
-                    expr.eval_depth(depth + 1, false)?;
-                    */
-                    return Ok(self);
-                },
-                Expr::Appl { f, .. }      => {
-                    f.eval_depth(depth + 1, true)?;
-                    let biggest_f_var_id = f.get_biggest_var_id().unwrap_or(0);
-                    let owned = self.take();
-                    if let Expr::Appl {
-                        f: box Expr::Lambda {
-                            param,
-                            box mut expr
-                        },
-                        mut arg
-                    } = owned {
-                        arg.alpha_convert_from(biggest_f_var_id + 1);
-                        expr.subst(param, *arg);
-                        expr.alpha_convert();
-                        drop(self.replace(expr));
-                    } else {
-                        drop(self.replace(owned));
-                        return Ok(self);
-                    }
-                },
-                Expr::MacroRef(ptr) => {
-                    if !ptr.as_ref().expr.is_normal_form() || eval_macros {
-                        let expr = ptr.expr.clone();
-                        // expr.eval_depth(depth, eval_macros)?;
-                        drop(std::mem::replace(self, expr));
-                    } else {
-                        return Ok(self);
-                    }
-                },
-                Expr::Nothing => {
-                    return Err(RuntimeError::NothingEval);
-                }
-            }
-        }
-        // self.eval_depth(depth + 1)?;
-        // Ok(self)
-        return Err(RuntimeError::IterationExceeded);
+    /// Renames every variable in the expression, both parameters and uses,
+    /// to `f` of its id. Macro bodies are left untouched. Unless `f` maps
+    /// different ids to the same one, the meaning of the expression is kept.
+    pub fn map_vars<F: Fn(usize) -> usize>(&mut self, f: F) {
+        self.map_vars_with(&f);
     }
 
-    fn subst(&mut self, var: usize, new_expr: Expr) {
+    fn map_vars_with<F: Fn(usize) -> usize>(&mut self, f: &F) {
         match self {
-            Expr::Lambda { expr, .. } => expr.subst(var, new_expr),
-            Expr::Appl { f, arg }     => {
-                f.subst(var, new_expr.clone());
-                arg.subst(var, new_expr);
+            Expr::Lambda { param, expr, .. } => {
+                *param = f(*param);
+                expr.map_vars_with(f);
             },
-            Expr::MacroRef(ptr)  => {
-                let mut expr = ptr.expr.clone();
-                expr.subst(var, new_expr);
-            }
-            Expr::Var(v)         => if *v == var { *self = new_expr },
-            Expr::Literal(_) |
-            Expr::Nothing        => (),
+            Expr::Appl { f: func, arg }  => {
+                func.map_vars_with(f);
+                arg.map_vars_with(f);
+            },
+            Expr::Var(v)                 => *v = f(*v),
+            Expr::MacroRef(_)  |
+            Expr::Literal(_)   |
+            Expr::Intrinsic(_) |
+            Expr::Nothing                |
+            Expr::Hole(_)                => (),
         }
     }
-}
 
-// Pure implementations (no mutation, lots of cloning)
-impl Expr {
-    pub fn pure_alpha_convert(&self) -> Expr {
-        let mut clone = self.clone();
-        clone.alpha_convert();
-        clone
+    /// Eliminates every lambda by bracket abstraction, leaving only
+    /// applications of the `S`, `K` and `I` combinators, which are macros, to
+    /// the variables, literals and macros of the expression. The result
+    /// evaluates to the same thing as the original.
+    pub fn to_combinator_form(&self) -> Expr {
+        self.to_combinators(&Combinators::new())
     }
-}
 
-impl std::fmt::Display for Expr {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn to_combinators(&self, comb: &Combinators) -> Expr {
         match self {
-            Expr::Lambda { param, expr } => write!(f, "λ{}. {}", Expr::Var(*param), expr),
-            Expr::Appl {
-                f: box Expr::Literal(a),
-                arg: box Expr::Literal(b),
-            } => write!(f, "\"{}{}\"", a.as_ref(), b.as_ref()),
-            
-            Expr::Appl { f: func, arg }  => {
-                match func.as_ref() {
-                    Expr::Lambda { .. }  => write!(f, "({})", func),
-                    _ => write!(f, "{}", func),
-                }?;
-                write!(f, " ")?;
-                match arg.as_ref() {
-                    Expr::Lambda { .. } | Expr::Appl{ .. } => write!(f, "({})", arg),
-                    _ => write!(f, "{}", arg),
-                }
+            Expr::Lambda { param, expr, .. } => expr.to_combinators(comb).abstract_var(*param, comb),
+            Expr::Appl { f, arg }        => Expr::Appl {
+                f: SubExpr::new(f.to_combinators(comb)),
+                arg: SubExpr::new(arg.to_combinators(comb)),
             },
-            Expr::Var(v)            => write!(f, "{}", (*v as u8 + 97) as char),
-            Expr::Literal(s)        => write!(f, "{}", s),
-            Expr::MacroRef(ptr)     => {
-                let name = unsafe { &ptr.as_ref().name.as_ref() };
-                write!(f, "{}", name)
-            }
-            Expr::Nothing           => write!(f, "[nothing expression]"),
+            other                        => other.clone(),
         }
     }
-}
 
-impl std::default::Default for Expr {
-    fn default() -> Expr {
-        Expr::Nothing
-    }
-}
+    // Builds an expression without lambdas that behaves as `\var. self`.
+    // `self` must already be free of lambdas.
+    fn abstract_var(self, var: usize, comb: &Combinators) -> Expr {
+        let appl = |f: Expr, arg: Expr| Expr::Appl { f: SubExpr::new(f), arg: SubExpr::new(arg) };
 
-impl Expr {
-    pub fn take(&mut self) -> Expr {
+        if !self.occurs_free(var) {
+            return appl(comb.k(), self);
+        }
+        match self {
+            Expr::Var(_)                                           => comb.i(),
+            // Eta reduction: \x. f x is just f, when x is not used in f.
+            Expr::Appl { f, arg }
+                if *arg == Expr::Var(var) && !f.occurs_free(var) => f.into_inner(),
+            Expr::Appl { f, arg }                                  => appl(
+                appl(comb.s(), f.into_inner().abstract_var(var, comb)),
+                arg.into_inner().abstract_var(var, comb),
+            ),
+            _ => unreachable!("bracket abstraction of an expression with lambdas"),
+        }
+    }
+
+    /// Gets all variables that are used, but not bound, in the expression.
+    pub fn free_vars(&self) -> HashSet<usize> {
+        struct FreeVars {
+            bound: Vec<usize>,
+            free: HashSet<usize>,
+        }
+
+        impl ExprVisitor for FreeVars {
+            fn visit_lambda(&mut self, param: usize, body: &Expr) {
+                self.bound.push(param);
+                body.walk(self);
+                self.bound.pop();
+            }
+
+            fn visit_var(&mut self, var: usize) {
+                if !self.bound.contains(&var) {
+                    self.free.insert(var);
+                }
+            }
+            // Macros are always closed.
+        }
+
+        let mut visitor = FreeVars { bound: Vec::new(), free: HashSet::new() };
+        self.walk(&mut visitor);
+        visitor.free
+    }
+
+    /// Checks that the expression has no free variables. Macro bodies are
+    /// assumed to be closed.
+    pub fn is_closed(&self) -> bool {
+        self.free_vars().is_empty()
+    }
+
+    /// Follows `MacroRef`s until a non-macro expression is found.
+    fn deref_macros(&self) -> &Expr {
+        let mut curr = self;
+        while let Expr::MacroRef(mac) = curr {
+            curr = &mac.as_ref().expr;
+        }
+        curr
+    }
+
+    /// Decodes a Church numeral of the form `λf. λx. f (f (... x))`. Returns
+    /// the number of applications of `f`, or `None` if the expression doesn't
+    /// have this shape.
+    pub fn as_church_numeral(&self) -> Option<usize> {
+        let (f, x, mut body) = match self.deref_macros() {
+            Expr::Lambda { param: f, expr, .. } => match expr.deref_macros() {
+                Expr::Lambda { param: x, expr, .. } if f != x => (*f, *x, expr.deref_macros()),
+                _ => return None,
+            },
+            _ => return None,
+        };
+
+        let mut n = 0;
+        loop {
+            match body {
+                Expr::Var(v) if *v == x => return Some(n),
+                Expr::Appl { f: head, arg } if **head == Expr::Var(f) => {
+                    n += 1;
+                    body = arg.deref_macros();
+                },
+                _ => return None,
+            }
+        }
+    }
+
+    /// Decodes a Church boolean. `λa. λb. a` is `true` and `λa. λb. b` is
+    /// `false`.
+    pub fn as_church_bool(&self) -> Option<bool> {
+        match self.deref_macros() {
+            Expr::Lambda { param: a, expr, .. } => match expr.deref_macros() {
+                Expr::Lambda { param: b, expr, .. } if a != b => match expr.deref_macros() {
+                    Expr::Var(v) if v == a => Some(true),
+                    Expr::Var(v) if v == b => Some(false),
+                    _ => None,
+                },
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Decodes an application of string literals to each other, like
+    /// `"a" ("b" "c")`, into the concatenation of all of them.
+    pub fn as_literal_chain(&self) -> Option<String> {
+        match self.deref_macros() {
+            Expr::Literal(s)      => Some(s.as_ref().clone()),
+            Expr::Appl { f, arg } => {
+                let mut s = f.as_literal_chain()?;
+                s.push_str(&arg.as_literal_chain()?);
+                Some(s)
+            },
+            _                     => None,
+        }
+    }
+
+    /// Every way the expression can be read: as it is displayed, and then, if
+    /// they apply, as a Church numeral like `#2`, as a Church boolean like
+    /// `#true` and as the string built by a chain of literals.
+    pub fn describe(&self) -> Vec<String> {
+        let mut descriptions = vec![self.to_string()];
+        if let Some(n) = self.as_church_numeral() {
+            descriptions.push(format!("#{}", n));
+        }
+        if let Some(b) = self.as_church_bool() {
+            descriptions.push(format!("#{}", b));
+        }
+        // A single literal is already displayed as its content.
+        if let (Expr::Appl { .. }, Some(s)) = (self, self.as_literal_chain()) {
+            descriptions.push(format!("{:?}", s));
+        }
+        descriptions
+    }
+
+    /// If the expression is a Church boolean, picks `then` for `true` and
+    /// `otherwise` for `false`.
+    pub fn try_as_bool_branch<'a>(&self, then: &'a Expr, otherwise: &'a Expr) -> Option<&'a Expr> {
+        self.as_church_bool()
+            .map(|b| if b { then } else { otherwise })
+    }
+
+    /// Gets the macros directly referenced by the expression, without
+    /// looking into their bodies. Each macro appears only once.
+    pub fn dependencies(&self) -> Vec<Rc<Macro>> {
+        struct Dependencies(Vec<Rc<Macro>>);
+
+        impl ExprVisitor for Dependencies {
+            fn visit_macro(&mut self, mac: &Rc<Macro>) {
+                if !self.0.iter().any(|dep| Rc::ptr_eq(dep, mac)) {
+                    self.0.push(Rc::clone(mac));
+                }
+            }
+        }
+
+        let mut visitor = Dependencies(Vec::new());
+        self.walk(&mut visitor);
+        visitor.0
+    }
+
+    /// Replaces every macro reference by the macro `f` gives for its name,
+    /// which is how an expression is moved to another environment, where the
+    /// macros with the same names are different `Rc`s. Macro bodies are left
+    /// alone. Stops at the first name `f` returns `None` for, in which case
+    /// the references before it may already have been replaced.
+    pub fn map_macro_refs<F>(&mut self, mut f: F) -> Result<(), UnboundMacro>
+    where
+        F: FnMut(&str) -> Option<Rc<Macro>>,
+    {
+        self.map_macro_refs_with(&mut f)
+    }
+
+    fn map_macro_refs_with<F>(&mut self, f: &mut F) -> Result<(), UnboundMacro>
+    where
+        F: FnMut(&str) -> Option<Rc<Macro>>,
+    {
+        match self {
+            Expr::MacroRef(mac) => {
+                *mac = f(mac.name()).ok_or_else(|| UnboundMacro { name: mac.name().to_owned() })?;
+            },
+            Expr::Appl { f: func, arg } => {
+                func.map_macro_refs_with(f)?;
+                arg.map_macro_refs_with(f)?;
+            },
+            Expr::Lambda { expr, .. } => expr.map_macro_refs_with(f)?,
+            Expr::Var(_)       |
+            Expr::Literal(_)   |
+            Expr::Intrinsic(_) |
+            Expr::Nothing      |
+            Expr::Hole(_)      => (),
+        }
+        Ok(())
+    }
+
+    /// Checks if a macro called `name` is referenced by the expression. When
+    /// `transitive` is set, macro bodies are searched as well.
+    pub fn contains_macro(&self, name: &str, transitive: bool) -> bool {
+        struct ContainsMacro<'a> {
+            name: &'a str,
+            transitive: bool,
+            visited: HashSet<*const Macro>,
+            found: bool,
+        }
+
+        impl ExprVisitor for ContainsMacro<'_> {
+            fn visit_macro(&mut self, mac: &Rc<Macro>) {
+                if self.found || !self.visited.insert(Rc::as_ptr(mac)) {
+                    return;
+                }
+
+                if mac.name() == self.name {
+                    self.found = true;
+                } else if self.transitive {
+                    mac.expr.walk(self);
+                }
+            }
+        }
+
+        let mut visitor = ContainsMacro { name, transitive, visited: HashSet::new(), found: false };
+        self.walk(&mut visitor);
+        visitor.found
+    }
+
+    /// Checks if `var` is used in the expression without being bound by a
+    /// lambda inside of it. Stops at the first use found.
+    pub fn occurs_free(&self, var: usize) -> bool {
+        struct OccursFree {
+            var: usize,
+            found: bool,
+        }
+
+        // Macro bodies are closed, so they are not visited.
+        impl ExprVisitor for OccursFree {
+            fn visit_lambda(&mut self, param: usize, body: &Expr) {
+                if param != self.var {
+                    body.walk(self);
+                }
+            }
+
+            fn visit_appl(&mut self, f: &Expr, arg: &Expr) {
+                f.walk(self);
+                if !self.found {
+                    arg.walk(self);
+                }
+            }
+
+            fn visit_var(&mut self, var: usize) {
+                self.found |= var == self.var;
+            }
+        }
+
+        let mut visitor = OccursFree { var, found: false };
+        self.walk(&mut visitor);
+        visitor.found
+    }
+
+    /// Counts how many times `var` is used in the expression. Macro bodies are
+    /// not visited, since they can't refer to variables outside of them.
+    pub fn count_var_occurrences(&self, var: usize) -> usize {
+        struct CountVar {
+            var: usize,
+            count: usize,
+        }
+
+        impl ExprVisitor for CountVar {
+            fn visit_var(&mut self, var: usize) {
+                if var == self.var {
+                    self.count += 1;
+                }
+            }
+        }
+
+        let mut visitor = CountVar { var, count: 0 };
+        self.walk(&mut visitor);
+        visitor.count
+    }
+
+    /// Gets the biggest variable id used in the expression, including in the
+    /// bodies of the macros it references. Each macro is only looked into
+    /// once, so this terminates even if macros ever refer to each other in a
+    /// cycle, and shared macros are not walked over and over.
+    pub fn get_biggest_var_id(&self) -> Option<usize> {
+        struct BiggestVar {
+            biggest: Option<usize>,
+            visited: HashSet<*const Macro>,
+        }
+
+        impl BiggestVar {
+            fn update(&mut self, var: usize) {
+                self.biggest = Some(self.biggest.map_or(var, |v| std::cmp::max(v, var)));
+            }
+        }
+
+        impl ExprVisitor for BiggestVar {
+            fn visit_lambda(&mut self, param: usize, body: &Expr) {
+                self.update(param);
+                body.walk(self);
+            }
+
+            fn visit_var(&mut self, var: usize) {
+                self.update(var);
+            }
+
+            fn visit_macro(&mut self, mac: &Rc<Macro>) {
+                if self.visited.insert(Rc::as_ptr(mac)) {
+                    mac.expr.walk(self);
+                }
+            }
+        }
+
+        let mut visitor = BiggestVar { biggest: None, visited: HashSet::new() };
+        self.walk(&mut visitor);
+        visitor.biggest
+    }
+
+    // Perform beta-reduction until the expression is in Weak Head Normal Form.
+    pub fn eval(&mut self) -> Result<&mut Expr, RuntimeError> {
+        self.eval_depth(0, false)
+    }
+
+    /// Reduces the expression only until it is in Weak Head Normal Form, that
+    /// is, until it is a lambda or an application whose head can't be reduced.
+    /// Redexes under lambdas and in arguments are left untouched. This is
+    /// what `eval` does as well.
+    pub fn eval_to_whnf(&mut self) -> Result<&mut Expr, RuntimeError> {
+        self.eval_with(&mut EvalContext::new())
+    }
+
+    /// Reduces the expression all the way to its normal form, going on under
+    /// lambdas and into the arguments of applications whose head can't be
+    /// reduced. See `EvalContext::normalize`.
+    pub fn eval_to_normal_form(&mut self) -> Result<&mut Expr, RuntimeError> {
+        let mut ctx = EvalContext::normalizing();
+        self.eval_with(&mut ctx)
+    }
+
+    pub fn eval_with(&mut self, ctx: &mut EvalContext) -> Result<&mut Expr, RuntimeError> {
+        let floor = self.free_var_floor();
+        self.eval_depth_with(0, false, floor, ctx)
+    }
+
+    /// Evaluates the expression to normal form like `eval_to_normal_form`,
+    /// but once an application is stuck, with a head that can't be reduced,
+    /// its function and argument are normalized in separate threads, since
+    /// they can't affect each other. New threads are only started in the
+    /// first `MAX_PARALLEL_DEPTH` levels of the expression.
+    #[cfg(feature = "parallel")]
+    pub fn eval_parallel(&mut self) -> Result<&mut Expr, RuntimeError> {
+        self.eval_parallel_from(0, MAX_PARALLEL_DEPTH)?;
+        Ok(self)
+    }
+
+    #[cfg(feature = "parallel")]
+    fn eval_parallel_from(&mut self, floor: usize, splits: usize) -> Result<(), RuntimeError> {
+        if splits == 0 {
+            let mut ctx = EvalContext::normalizing();
+            return self.eval_depth_with(0, false, floor, &mut ctx).map(|_| ());
+        }
+
+        let mut ctx = EvalContext::new();
+        self.eval_depth_with(0, false, floor, &mut ctx)?;
+        match self {
+            Expr::Lambda { param, expr, .. } => {
+                let floor = std::cmp::max(floor, *param + 1);
+                expr.eval_parallel_from(floor, splits)
+            },
+            // Just like in `reduce`, the head is in WHNF and is not a lambda.
+            Expr::Appl { f, arg }        => {
+                let mut owned_arg = arg.take();
+                let handle = std::thread::spawn(move || {
+                    owned_arg.eval_parallel_from(floor, splits - 1).map(|_| owned_arg)
+                });
+                let result = f.eval_parallel_from(floor, splits - 1);
+                **arg = handle.join().expect("evaluation thread panicked")?;
+                result
+            },
+            _                            => Ok(()),
+        }
+    }
+
+    /// Evaluates the expression like `eval`, taking a unit of `fuel` for each
+    /// reduction. When it runs out, evaluation stops with
+    /// `RuntimeError::OutOfFuel` and the expression is left partially reduced,
+    /// so that it can be resumed by calling this again with more fuel.
+    pub fn eval_with_fuel(&mut self, fuel: &mut u64) -> Result<&mut Expr, RuntimeError> {
+        let mut ctx = EvalContext::new().with_fuel(fuel);
+        self.eval_with(&mut ctx)
+    }
+
+    /// Evaluates the expression like `eval`, calling `callback` with the
+    /// number of reductions so far every `every` reductions. The callback
+    /// returns whether to go on, so that it can cancel a long evaluation.
+    pub fn eval_with_progress<F>(&mut self, every: usize, callback: F) -> Result<&mut Expr, RuntimeError>
+    where
+        F: FnMut(usize) -> bool,
+    {
+        let mut ctx = EvalContext::new().with_progress(every, callback);
+        self.eval_with(&mut ctx)
+    }
+
+    pub fn eval_depth(&mut self, depth: usize, eval_macros: bool) -> Result<&mut Expr, RuntimeError> {
+        let floor = self.free_var_floor();
+        self.eval_depth_with(depth, eval_macros, floor, &mut EvalContext::new())
+    }
+
+    // `floor` is the smallest variable id that is not used by any lambda
+    // around this expression. Every lambda created during evaluation gets an
+    // id at least that big, so it can neither capture nor shadow a variable
+    // from outside.
+    fn eval_depth_with(
+        &mut self,
+        depth: usize,
+        eval_macros: bool,
+        floor: usize,
+        ctx: &mut EvalContext,
+    ) -> Result<&mut Expr, RuntimeError>
+    {
+        // Only closed applications are memoized, since the meaning of an open
+        // one depends on where it is.
+        let key = match (&ctx.memo, &*self) {
+            (Some(memo), Expr::Appl { .. }) if self.free_vars().is_empty() => {
+                let key = (self.pure_alpha_convert(), eval_macros);
+                if let Some(cached) = memo.get(&key) {
+                    *self = cached.clone();
+                    self.alpha_convert_from(floor);
+                    return Ok(self);
+                }
+                Some(key)
+            },
+            _ => None,
+        };
+
+        match self.reduce(depth, eval_macros, floor, ctx).map(|_| ()) {
+            // The first term around the `Nothing` tells where it was.
+            Err(RuntimeError::NothingEval(None)) if !matches!(self, Expr::Nothing | Expr::Hole(_)) => {
+                return Err(RuntimeError::NothingEval(Some(self.to_string())));
+            },
+            Err(err) => return Err(err),
+            Ok(())   => (),
+        }
+
+        if let (Some(memo), Some(key)) = (ctx.memo.as_mut(), key) {
+            memo.insert(key, self.pure_alpha_convert());
+        }
+        Ok(self)
+    }
+
+    fn reduce(
+        &mut self,
+        depth: usize,
+        eval_macros: bool,
+        floor: usize,
+        ctx: &mut EvalContext,
+    ) -> Result<&mut Expr, RuntimeError>
+    {
+        self.reduce_whnf(depth, eval_macros, floor, ctx)?;
+        if !ctx.normalize {
+            return Ok(self);
+        }
+
+        if ctx.normalize_depth > MAX_NORMALIZE_DEPTH {
+            return Err(RuntimeError::RecursionDepthExceeded);
+        }
+        ctx.normalize_depth += 1;
+        let result = match self {
+            Expr::Lambda { param, expr, .. } => {
+                let floor = std::cmp::max(floor, *param + 1);
+                expr.eval_depth_with(depth, false, floor, ctx).map(|_| ())
+            },
+            // The head is in WHNF and is not a lambda, so normalizing the parts
+            // of the application can't create a new redex here.
+            Expr::Appl { f, arg }        => {
+                f.eval_depth_with(depth, false, floor, ctx)
+                    .and_then(|_| arg.eval_depth_with(depth, false, floor, ctx))
+                    .map(|_| ())
+            },
+            _                            => Ok(()),
+        };
+        ctx.normalize_depth -= 1;
+        result?;
+
+        Ok(self)
+    }
+
+    fn reduce_whnf(
+        &mut self,
+        depth: usize,
+        eval_macros: bool,
+        floor: usize,
+        ctx: &mut EvalContext,
+    ) -> Result<&mut Expr, RuntimeError>
+    {
+        if depth > MAX_EVAL_DEPTH {
+            return Err(RuntimeError::RecursionDepthExceeded);
+        }
+
+        let mut iterations = 0;
+        loop {
+            if ctx.fuel.is_none() {
+                if iterations == MAX_EVAL_DEPTH {
+                    return Err(RuntimeError::IterationExceeded);
+                }
+                iterations += 1;
+            }
+
+            match self {
+                Expr::Literal(_)   |
+                Expr::Intrinsic(_) |
+                Expr::Var(_)        => return Ok(self),
+                Expr::Lambda { .. } => return Ok(self),
+                Expr::Appl { .. } if self.intrinsic_call() == Some((Intrinsic::If, 3)) => {
+                    let [cond, then, otherwise] = self.intrinsic_args_mut();
+                    // Only the condition is evaluated before picking a branch,
+                    // so the other one may even diverge.
+                    cond.eval_depth_with(depth + 1, false, floor, ctx)?;
+                    let expr = match cond.try_as_bool_branch(then, otherwise) {
+                        Some(branch) => branch.clone(),
+                        None         => Expr::Appl {
+                            f: SubExpr::new(Expr::Appl { f: cond.clone(), arg: then.clone() }),
+                            arg: otherwise.clone(),
+                        },
+                    };
+                    ctx.spend_fuel()?;
+                    drop(self.replace(expr));
+                    ctx.count_reduction(self)?;
+                },
+                Expr::Appl { .. } if self.intrinsic_call() == Some((Intrinsic::Concat, 2)) => {
+                    let [lhs, rhs] = self.intrinsic_args_mut();
+                    lhs.eval_depth_with(depth + 1, false, floor, ctx)?;
+                    rhs.eval_depth_with(depth + 1, false, floor, ctx)?;
+                    match concat_literals(lhs, rhs, ctx.max_literal_len)? {
+                        Some(lit) => {
+                            ctx.spend_fuel()?;
+                            drop(self.replace(lit));
+                        },
+                        None      => return Ok(self),
+                    }
+                    ctx.count_reduction(self)?;
+                },
+                Expr::Appl { f, arg }     => {
+                    if ctx.arg_order == ArgOrder::RightToLeft {
+                        arg.eval_depth_with(depth + 1, false, floor, ctx)?;
+                    }
+                    f.eval_depth_with(depth + 1, true, floor, ctx)?;
+                    if !matches!(**f, Expr::Lambda { .. }) {
+                        return Ok(self);
+                    }
+                    if ctx.arg_order == ArgOrder::LeftToRight {
+                        arg.eval_depth_with(depth + 1, false, floor, ctx)?;
+                    }
+                    ctx.spend_fuel()?;
+                    self.beta_reduce(floor);
+                    ctx.count_reduction(self)?;
+                },
+                Expr::MacroRef(ptr) => {
+                    if !ptr.is_normal_form() || eval_macros || !ctx.preserve_macros {
+                        let expr = ptr.expr.clone();
+                        drop(std::mem::replace(self, expr));
+                        self.alpha_convert_from(floor);
+                    } else {
+                        return Ok(self);
+                    }
+                },
+                Expr::Nothing => {
+                    return Err(RuntimeError::NothingEval(None));
+                },
+                Expr::Hole(span) => {
+                    ctx.hole_span = Some(*span);
+                    return Err(RuntimeError::NothingEval(None));
+                }
+            }
+        }
+    }
+
+    // Contracts the expression if it is a redex, that is, a lambda applied to
+    // some argument. Returns whether it was.
+    fn beta_reduce(&mut self, floor: usize) -> bool {
+        let f = match self {
+            Expr::Appl { f, .. } if matches!(**f, Expr::Lambda { .. }) => f,
+            _ => return false,
+        };
+        f.alpha_convert_from(floor);
+        let biggest_f_var_id = f.get_biggest_var_id()
+            .map_or(floor, |v| std::cmp::max(v + 1, floor));
+
+        let (f, mut arg) = match self.take() {
+            Expr::Appl { f, arg } => (f.into_inner(), arg.into_inner()),
+            _                     => unreachable!(),
+        };
+        let (param, mut expr) = match f {
+            Expr::Lambda { param, expr, .. } => (param, expr.into_inner()),
+            _                                => unreachable!(),
+        };
+        let mut uses = Vec::new();
+        expr.free_uses(param, 0, &mut uses);
+        let same_depth = uses.array_windows().all(|[(_, a), (_, b)]| a == b);
+        if uses.len() > 1 && same_depth && arg.is_closed() {
+            // The conversion below numbers every copy of the argument the
+            // same, so they are numbered that way already and can share all
+            // of their nodes.
+            arg.alpha_convert_from(floor + uses[0].1);
+            put_in_uses(uses, arg.clone_shared());
+        } else {
+            arg.alpha_convert_from(biggest_f_var_id);
+            put_in_uses(uses, arg);
+        }
+        expr.alpha_convert_from(floor);
+        drop(self.replace(expr));
+        true
+    }
+
+    /// Reduces the expression to normal form by always contracting the
+    /// leftmost-outermost redex. Arguments are never evaluated before being
+    /// substituted, so, unlike `eval`, this finds the normal form whenever
+    /// there is one, given enough steps.
+    pub fn normal_order_eval(&mut self) -> Result<&mut Expr, RuntimeError> {
+        self.normal_order_eval_traced(|_| ())
+    }
+
+    /// Same as `normal_order_eval`, but calls `trace` with the whole
+    /// expression after every step, which includes expanding macros.
+    pub fn normal_order_eval_traced<F>(&mut self, trace: F) -> Result<&mut Expr, RuntimeError>
+    where
+        F: FnMut(&Expr),
+    {
+        self.normal_order_eval_with(&mut EvalContext::new(), trace)
+    }
+
+    /// Same as `normal_order_eval_traced`, but with the limits of `ctx`, like
+    /// `EvalContext::max_literal_len`. The steps are counted in
+    /// `ctx.reductions`.
+    pub fn normal_order_eval_with<F>(&mut self, ctx: &mut EvalContext<'_>, mut trace: F) -> Result<&mut Expr, RuntimeError>
+    where
+        F: FnMut(&Expr),
+    {
+        for _ in 0..MAX_NORMAL_ORDER_STEPS {
+            if !self.normal_order_step(0, ctx.max_literal_len)? {
+                return Ok(self);
+            }
+            ctx.reductions += 1;
+            trace(self);
+        }
+        Err(RuntimeError::IterationExceeded)
+    }
+
+    /// Reduces the expression in normal order, like `normal_order_eval`, but
+    /// for at most `limit` steps, telling apart a term that didn't normalize
+    /// in time from an evaluation that failed.
+    pub fn reduce_to_fixpoint_with_limit(&mut self, limit: usize) -> EvalOutcome {
+        for steps in 0..=limit {
+            match self.normal_order_step(0, DEFAULT_MAX_LITERAL_LEN) {
+                Ok(false) => return EvalOutcome::Normalized(steps),
+                Ok(true)  => (),
+                Err(err)  => return EvalOutcome::Error(err),
+            }
+        }
+        EvalOutcome::LimitReached
+    }
+
+    // Performs a single step of normal order reduction. Returns whether there
+    // was anything to reduce. Literals can't be concatenated past
+    // `max_literal_len`.
+    fn normal_order_step(&mut self, floor: usize, max_literal_len: usize) -> Result<bool, RuntimeError> {
+        match self {
+            Expr::Appl { f, .. } if matches!(**f, Expr::Lambda { .. }) => Ok(self.beta_reduce(floor)),
+            Expr::Appl { .. } if self.intrinsic_call() == Some((Intrinsic::If, 3)) => {
+                let [cond, then, otherwise] = self.intrinsic_args_mut();
+                if let Some(branch) = cond.try_as_bool_branch(then, otherwise) {
+                    let branch = branch.clone();
+                    drop(self.replace(branch));
+                } else if !cond.normal_order_step(floor, max_literal_len)? {
+                    // Not a boolean, so `IF` behaves as if it wasn't there.
+                    let expr = Expr::Appl {
+                        f: SubExpr::new(Expr::Appl { f: cond.clone(), arg: then.clone() }),
+                        arg: otherwise.clone(),
+                    };
+                    drop(self.replace(expr));
+                }
+                Ok(true)
+            },
+            Expr::Appl { .. } if self.intrinsic_call() == Some((Intrinsic::Concat, 2)) => {
+                let [lhs, rhs] = self.intrinsic_args_mut();
+                match concat_literals(lhs, rhs, max_literal_len)? {
+                    Some(lit) => {
+                        drop(self.replace(lit));
+                        Ok(true)
+                    },
+                    None      => Ok(lhs.normal_order_step(floor, max_literal_len)? || rhs.normal_order_step(floor, max_literal_len)?),
+                }
+            },
+            // A macro that is applied to something has to be expanded in order
+            // to find out whether it is a redex.
+            Expr::Appl { f, .. } if matches!(**f, Expr::MacroRef(_)) => {
+                if let Expr::MacroRef(mac) = &**f {
+                    let mut expr = mac.expr.clone();
+                    expr.alpha_convert_from(floor);
+                    *f = expr.into();
+                }
+                Ok(true)
+            },
+            Expr::Appl { f, arg } => {
+                Ok(f.normal_order_step(floor, max_literal_len)? || arg.normal_order_step(floor, max_literal_len)?)
+            },
+            Expr::Lambda { param, expr, .. } => {
+                let floor = std::cmp::max(floor, *param + 1);
+                expr.normal_order_step(floor, max_literal_len)
+            },
+            Expr::MacroRef(mac) if !mac.is_normal_form() => {
+                let mut expr = mac.expr.clone();
+                expr.alpha_convert_from(floor);
+                drop(self.replace(expr));
+                Ok(true)
+            },
+            Expr::MacroRef(_)  |
+            Expr::Var(_)       |
+            Expr::Literal(_)   |
+            Expr::Intrinsic(_) => Ok(false),
+            Expr::Nothing      |
+            Expr::Hole(_)      => Err(RuntimeError::NothingEval(None)),
+        }
+    }
+
+    // Copies `new_expr` once per use of `var` but the last one, which takes
+    // it as it is.
+    fn subst(&mut self, var: usize, new_expr: Expr) {
+        let mut uses = Vec::new();
+        self.free_uses(var, 0, &mut uses);
+        put_in_uses(uses, new_expr);
+    }
+
+    // Collects the nodes where `var` is used without being bound inside of
+    // the expression, along with the number of lambdas around each of them,
+    // `depth` being the number around the expression.
+    fn free_uses<'a>(&'a mut self, var: usize, depth: usize, uses: &mut Vec<(&'a mut Expr, usize)>) {
+        if matches!(self, Expr::Var(v) if *v == var) {
+            uses.push((self, depth));
+            return;
+        }
+        // Changing a shared child copies it, so the ones without uses are
+        // not looked into.
+        let visit = |child: &'a mut SubExpr, depth: usize, uses: &mut Vec<(&'a mut Expr, usize)>| {
+            if !child.is_shared() || child.occurs_free(var) {
+                child.free_uses(var, depth, uses);
+            }
+        };
+        match self {
+            // A lambda that binds the same variable shadows it.
+            Expr::Lambda { param, .. } if *param == var => (),
+            Expr::Lambda { expr, .. } => visit(expr, depth + 1, uses),
+            Expr::Appl { f, arg }     => {
+                visit(f, depth, uses);
+                visit(arg, depth, uses);
+            },
+            // Macros are closed, so there is nothing to substitute in them.
+            Expr::MacroRef(_)  |
+            Expr::Var(_)       |
+            Expr::Literal(_)   |
+            Expr::Intrinsic(_) |
+            Expr::Nothing        |
+            Expr::Hole(_)        => (),
+        }
+    }
+
+    /// Substitutes every variable in `subs` by its expression at the same
+    /// time, so the expressions that are put in are never substituted again.
+    /// Replacing `a` by `b` and `b` by `a` in `a b` gives `b a`, where doing
+    /// one after the other would give `a a`. Like beta-reduction, this
+    /// assumes the variables of the new expressions are not bound here.
+    pub fn subst_many(&mut self, subs: &[(usize, Expr)]) {
+        match self {
+            Expr::Lambda { param, expr, .. } => {
+                // A lambda that binds one of the variables shadows it.
+                if subs.iter().any(|(var, _)| var == param) {
+                    let unshadowed: Vec<_> = subs.iter()
+                        .filter(|(var, _)| var != param)
+                        .cloned()
+                        .collect();
+                    expr.subst_many(&unshadowed);
+                } else {
+                    expr.subst_many(subs);
+                }
+            },
+            Expr::Appl { f, arg }        => {
+                f.subst_many(subs);
+                arg.subst_many(subs);
+            },
+            Expr::Var(v)                 => {
+                if let Some((_, new_expr)) = subs.iter().find(|(var, _)| var == v) {
+                    *self = new_expr.clone();
+                }
+            },
+            // Macros are closed, so there is nothing to substitute in them.
+            Expr::MacroRef(_)  |
+            Expr::Literal(_)   |
+            Expr::Intrinsic(_) |
+            Expr::Nothing                |
+            Expr::Hole(_)                => (),
+        }
+    }
+}
+
+// Whether `Expr::alpha_convert_with_table` has to visit `child`. Changing a
+// shared child copies it, so that is only done if it isn't converted already.
+fn needs_alpha_convert(child: &SubExpr, conversion_table: &[usize], start: usize) -> bool {
+    !child.is_shared() || !child.is_alpha_converted(conversion_table, start, 0)
+}
+
+// Puts `new_expr` in place of each of `uses`, see `Expr::free_uses`. It is
+// copied for all of them but the last one, which takes it as it is.
+fn put_in_uses(mut uses: Vec<(&mut Expr, usize)>, new_expr: Expr) {
+    if let Some((last, _)) = uses.pop() {
+        for (node, _) in uses {
+            *node = new_expr.clone();
+        }
+        *last = new_expr;
+    }
+}
+
+// Concatenates both expressions if they are literals, following macros.
+
+fn concat_literals(lhs: &Expr, rhs: &Expr, max_len: usize) -> Result<Option<Expr>, RuntimeError> {
+    match (lhs.deref_macros(), rhs.deref_macros()) {
+        (Expr::Literal(a), Expr::Literal(b)) => {
+            if a.len() + b.len() > max_len {
+                return Err(RuntimeError::MaxLiteralConcat);
+            }
+            let mut concat = String::with_capacity(a.len() + b.len());
+            concat.push_str(a);
+            concat.push_str(b);
+            Ok(Some(Expr::Literal(Rc::new(concat))))
+        },
+        _ => Ok(None),
+    }
+}
+
+// Gets an ASCII name for a variable: a, b, ..., z, aa, ab, ...
+fn source_var_name(id: usize) -> String {
+    let mut name = Vec::new();
+    let mut n = id + 1;
+    while n > 0 {
+        n -= 1;
+        name.push(b'a' + (n % 26) as u8);
+        n /= 26;
+    }
+    name.reverse();
+    String::from_utf8(name).unwrap()
+}
+
+impl Expr {
+    /// Writes the expression as source code, with `\\` for lambdas and only
+    /// the parenthesis that are needed. Compiling it with the same macros
+    /// gives back an alpha-equivalent expression. Variables are named `a`,
+    /// `b`, ..., `z`, `aa`, ..., skipping keywords and the names of the
+    /// macros the expression uses, which the variables would hide.
+    pub fn to_source(&self) -> String {
+        self.to_source_with(&HashMap::new())
+    }
+
+    // Same as `to_source`, but the macros in `names` are written with the
+    // name they are mapped to instead of their own.
+    fn to_source_with(&self, names: &HashMap<*const Macro, String>) -> String {
+        let mut taken: HashSet<String> = self.dependencies()
+            .iter()
+            .map(|mac| names.get(&Rc::as_ptr(mac)).map_or(mac.name(), String::as_str).to_owned())
+            .collect();
+        taken.extend(crate::compiler::RESERVED_WORDS.iter().map(|word| word.to_string()));
+        taken.extend(crate::parser::INFIX_KEYWORDS.iter().map(|word| word.to_string()));
+
+        // After alpha conversion, bound variables are numbered from 0, so the
+        // n-th one gets the n-th name that is free to use.
+        let mut expr = self.pure_alpha_convert();
+        let count = expr.get_biggest_var_id().map_or(0, |v| v + 1);
+        let ids: Vec<usize> = (0..).filter(|&id| !taken.contains(&source_var_name(id))).take(count).collect();
+        expr.map_vars(|v| ids[v]);
+
+        let mut out = String::new();
+        expr.write_source(names, &mut out);
+        out
+    }
+
+    fn write_source(&self, names: &HashMap<*const Macro, String>, out: &mut String) {
+        match self {
+            Expr::Lambda { param, expr, .. } => {
+                out.push('\\');
+                out.push_str(&source_var_name(*param));
+                out.push_str(". ");
+                expr.write_source(names, out);
+            },
+            Expr::Appl { f, arg }        => {
+                match f.as_ref() {
+                    Expr::Lambda { .. } => f.write_source_grouped(names, out),
+                    _                   => f.write_source(names, out),
+                }
+                out.push(' ');
+                match arg.as_ref() {
+                    Expr::Lambda { .. } |
+                    Expr::Appl { .. }   => arg.write_source_grouped(names, out),
+                    _                   => arg.write_source(names, out),
+                }
+            },
+            Expr::Var(v)                 => out.push_str(&source_var_name(*v)),
+            Expr::Literal(s)             => {
+                out.push('"');
+                for c in s.chars() {
+                    if c == '"' || c == '\\' {
+                        out.push('\\');
+                    }
+                    out.push(c);
+                }
+                out.push('"');
+            },
+            Expr::MacroRef(mac)          => match names.get(&Rc::as_ptr(mac)) {
+                Some(name) if mac.fixity().is_some() => out.push_str(&format!("({})", name)),
+                Some(name)                           => out.push_str(name),
+                None                                 => mac.write_name(out).unwrap(),
+            },
+            Expr::Intrinsic(intrinsic)   => out.push_str(intrinsic.name()),
+            Expr::Nothing                |
+            Expr::Hole(_)                => out.push_str("[nothing expression]"),
+        }
+    }
+
+    fn write_source_grouped(&self, names: &HashMap<*const Macro, String>, out: &mut String) {
+        out.push('(');
+        self.write_source(names, out);
+        out.push(')');
+    }
+}
+
+impl Expr {
+    /// Renders the expression tree as a Graphviz DOT graph. If
+    /// `follow_macros` is set, macros point to the tree of their body, which is
+    /// only drawn once even if the macro is used many times.
+    pub fn to_dot(&self, follow_macros: bool) -> String {
+        let mut dot = DotWriter {
+            out: String::from("digraph {\n"),
+            next_id: 0,
+            macros: HashMap::new(),
+            follow_macros,
+        };
+        dot.write(self);
+        dot.out.push_str("}\n");
+        dot.out
+    }
+}
+
+struct DotWriter {
+    out: String,
+    next_id: usize,
+    // Node of every macro already drawn, so each is drawn once, and cycles
+    // can't make us loop forever.
+    macros: HashMap<*const Macro, usize>,
+    follow_macros: bool,
+}
+
+impl DotWriter {
+    // Writes the node and its children, returning the id of the node.
+    fn write(&mut self, expr: &Expr) -> usize {
+        if let Expr::MacroRef(mac) = expr {
+            if let Some(&id) = self.macros.get(&Rc::as_ptr(mac)) {
+                return id;
+            }
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+        let label = match expr {
+            Expr::Lambda { param, .. } => format!("λ{}", Expr::Var(*param)),
+            Expr::Appl { .. }          => "@".to_owned(),
+            Expr::Var(_)               => expr.to_string(),
+            Expr::Literal(lit)         => format!("\"{}\"", lit),
+            Expr::MacroRef(mac)        => mac.name().to_owned(),
+            Expr::Intrinsic(intr)      => intr.name().to_owned(),
+            Expr::Nothing              |
+            Expr::Hole(_)              => "[nothing]".to_owned(),
+        };
+        let label = label.replace('\\', "\\\\").replace('"', "\\\"");
+        self.out.push_str(&format!("    n{} [label=\"{}\"];\n", id, label));
+
+        match expr {
+            Expr::Lambda { expr, .. } => self.edge(id, expr),
+            Expr::Appl { f, arg }     => {
+                self.edge(id, f);
+                self.edge(id, arg);
+            },
+            Expr::MacroRef(mac) if self.follow_macros => {
+                self.macros.insert(Rc::as_ptr(mac), id);
+                self.edge(id, &mac.expr);
+            },
+            _                         => (),
+        }
+        id
+    }
+
+    fn edge(&mut self, from: usize, to: &Expr) {
+        let to = self.write(to);
+        self.out.push_str(&format!("    n{} -> n{};\n", from, to));
+    }
+}
+
+// Pure implementations (no mutation, lots of cloning)
+impl Expr {
+    pub fn pure_alpha_convert(&self) -> Expr {
+        let mut clone = self.clone();
+        clone.alpha_convert();
+        clone
+    }
+
+    pub fn pure_eval(&self) -> Result<Expr, RuntimeError> {
+        let mut clone = self.clone();
+        clone.eval()?;
+        Ok(clone)
+    }
+}
+
+// Notions of equality between expressions, from the strictest to the loosest.
+impl Expr {
+    /// Checks if both expressions are the same up to the names of their
+    /// variables. Macros are compared by reference, not expanded.
+    pub fn alpha_eq(&self, other: &Expr) -> bool {
+        self.pure_alpha_convert() == other.pure_alpha_convert()
+    }
+
+    /// Checks if both expressions have alpha-equivalent normal forms, with
+    /// macros expanded. Each one is reduced in normal order for at most
+    /// `limit` steps, and `None` is returned if one of them doesn't reach its
+    /// normal form in time, or fails to evaluate.
+    pub fn beta_eq(&self, other: &Expr, limit: usize) -> Option<bool> {
+        Some(self.beta_normal_form(limit)?.alpha_eq(&other.beta_normal_form(limit)?))
+    }
+
+    /// Same as `beta_eq`, but the normal forms are also eta-reduced, so
+    /// `\a. f a` is equal to `f`.
+    pub fn eta_eq(&self, other: &Expr, limit: usize) -> Option<bool> {
+        let mut left = self.beta_normal_form(limit)?;
+        let mut right = other.beta_normal_form(limit)?;
+        left.eta_normalize();
+        right.eta_normalize();
+        Some(left.alpha_eq(&right))
+    }
+
+    fn beta_normal_form(&self, limit: usize) -> Option<Expr> {
+        let mut clone = self.clone();
+        match clone.reduce_to_fixpoint_with_limit(limit) {
+            EvalOutcome::Normalized(_) => {
+                clone.inline_macros(0);
+                Some(clone)
+            },
+            EvalOutcome::LimitReached |
+            EvalOutcome::Error(_)      => None,
+        }
+    }
+
+    // Replaces every macro reference by its body, so that a macro is equal to
+    // what it is defined as.
+    fn inline_macros(&mut self, floor: usize) {
+        match self {
+            Expr::MacroRef(mac) => {
+                let mut expr = mac.expr.clone();
+                expr.alpha_convert_from(floor);
+                expr.inline_macros(floor);
+                drop(self.replace(expr));
+            },
+            Expr::Appl { f, arg } => {
+                f.inline_macros(floor);
+                arg.inline_macros(floor);
+            },
+            Expr::Lambda { param, expr, .. } => {
+                let floor = std::cmp::max(floor, *param + 1);
+                expr.inline_macros(floor);
+            },
+            Expr::Var(_)       |
+            Expr::Literal(_)   |
+            Expr::Intrinsic(_) |
+            Expr::Nothing      |
+            Expr::Hole(_)      => (),
+        }
+    }
+
+    // Eta-reduces every subterm, from the bottom up. Reducing a beta normal
+    // form this way never creates new beta-redexes.
+    fn eta_normalize(&mut self) {
+        match self {
+            Expr::Appl { f, arg } => {
+                f.eta_normalize();
+                arg.eta_normalize();
+            },
+            Expr::Lambda { expr, .. } => expr.eta_normalize(),
+            _                         => (),
+        }
+        while self.eta_reduce() {}
+    }
+}
+
+/// Writes every macro definition in `macros` as source code, one per line, so
+/// that compiling the output line by line rebuilds the same environment. A
+/// macro is always written after the macros it uses, and otherwise they are
+/// sorted by name, so the output doesn't depend on the order of the map.
+/// Private macros are left out, unless an exported macro uses them, in which
+/// case they are written with `priv`. So are the definitions that were
+/// replaced by a later one of the same name but are still used, under a
+/// fresh name, unless they are operators, which can't be private.
+pub fn export_macros(macros: &HashMap<String, Rc<Macro>>) -> String {
+    let mut names: Vec<&String> = macros.keys()
+        .filter(|name| !macros[*name].is_private())
+        .collect();
+    names.sort();
+
+    let mut exporter = MacroExporter {
+        macros,
+        names: HashMap::new(),
+        taken: macros.keys().cloned().collect(),
+        out: String::new(),
+    };
+    for name in names {
+        exporter.export(&macros[name]);
+    }
+    exporter.out
+}
+
+struct MacroExporter<'a> {
+    macros: &'a HashMap<String, Rc<Macro>>,
+    // Name every macro already written was written with.
+    names: HashMap<*const Macro, String>,
+    taken: HashSet<String>,
+    out: String,
+}
+
+impl MacroExporter<'_> {
+    // Writes the definition of `mac`, after the ones it depends on.
+    fn export(&mut self, mac: &Rc<Macro>) {
+        if self.names.contains_key(&Rc::as_ptr(mac)) {
+            return;
+        }
+        for dep in mac.expr.dependencies() {
+            self.export(&dep);
+        }
+
+        let is_current = self.macros.get(mac.name()).is_some_and(|current| Rc::ptr_eq(current, mac));
+        let name = if is_current { mac.name().to_owned() } else { self.fresh_name(mac) };
+        if let Some(fixity) = mac.fixity() {
+            self.out.push_str(&format!("{} {} ", fixity.assoc.keyword(), fixity.precedence));
+        }
+        // Operators can't be private.
+        if mac.is_private() || (!is_current && mac.fixity().is_none()) {
+            self.out.push_str("priv ");
+        }
+        self.out.push_str(&name);
+        self.out.push_str(" = ");
+        self.out.push_str(&mac.expr.to_source_with(&self.names));
+        self.out.push('\n');
+        self.names.insert(Rc::as_ptr(mac), name);
+    }
+
+    // A name for a definition that was replaced, which no other macro has.
+    // Operators get another operator, so that they keep their fixity.
+    fn fresh_name(&mut self, mac: &Macro) -> String {
+        let suffix = if mac.fixity().is_some() { "~" } else { "Old" };
+        let mut name = format!("{}{}", mac.name(), suffix);
+        while self.taken.contains(&name) {
+            name.push_str(suffix);
+        }
+        self.taken.insert(name.clone());
+        name
+    }
+}
+
+/// How expressions are rendered by `Expr::display`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DisplayStyle {
+    /// Lambdas are written with `\`, as in the source code.
+    Ascii,
+    /// Lambdas are written with `λ`.
+    #[default]
+    Unicode,
+}
+
+/// Displays an expression with a given `DisplayStyle`. Created by
+/// `Expr::display` and `Executable::display_named`.
+pub struct StyledExpr<'a> {
+    expr: &'a Expr,
+    style: DisplayStyle,
+    // Whether lambdas are written with the names their parameters had in
+    // the source, see `VarName`.
+    named: bool,
+    // Whether Church numerals and booleans are collapsed.
+    church: bool,
+}
+
+impl Expr {
+    pub fn display(&self, style: DisplayStyle) -> StyledExpr<'_> {
+        StyledExpr { expr: self, style, named: false, church: false }
+    }
+
+    /// Displays the expression with every Church numeral and boolean in it
+    /// written as a number or as `true` and `false`, like `λa. a 2 3` for a
+    /// pair of numerals. Since `0` and `false` are the same term, it is
+    /// written as `0`. Macros are still written by name.
+    pub fn pretty_church(&self) -> StyledExpr<'_> {
+        StyledExpr { expr: self, style: DisplayStyle::default(), named: false, church: true }
+    }
+}
+
+impl StyledExpr<'_> {
+    /// Formats at most `max_chars` characters of the expression, and tells
+    /// whether there was more. Formatting stops as soon as the limit is
+    /// reached, so it is cheap even for huge expressions.
+    pub fn to_string_bounded(&self, max_chars: usize) -> (String, bool) {
+        let mut writer = BoundedWriter { out: String::new(), remaining: max_chars, truncated: false };
+        // The only error comes from the writer, once it is full.
+        let _ = std::fmt::write(&mut writer, format_args!("{}", self));
+        (writer.out, writer.truncated)
+    }
+
+    /// Counts the characters of the formatted expression, without keeping
+    /// them around.
+    pub fn char_count(&self) -> usize {
+        let mut counter = CharCounter(0);
+        // Counting never fails.
+        let _ = std::fmt::write(&mut counter, format_args!("{}", self));
+        counter.0
+    }
+}
+
+// Keeps the first `remaining` characters written to it, and fails the write
+// that goes past them, which stops the formatting.
+struct BoundedWriter {
+    out: String,
+    remaining: usize,
+    truncated: bool,
+}
+
+impl std::fmt::Write for BoundedWriter {
+    fn write_str(&mut self, s: &str) -> std::fmt::Result {
+        for c in s.chars() {
+            if self.remaining == 0 {
+                self.truncated = true;
+                return Err(std::fmt::Error);
+            }
+            self.out.push(c);
+            self.remaining -= 1;
+        }
+        Ok(())
+    }
+}
+
+struct CharCounter(usize);
+
+impl std::fmt::Write for CharCounter {
+    fn write_str(&mut self, s: &str) -> std::fmt::Result {
+        self.0 += s.chars().count();
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for StyledExpr<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write_styled(self.expr, self.style, self.named, self.church, &mut Vec::new(), f)
+    }
+}
+
+// `scope` holds the name given to each parameter of the lambdas around `expr`,
+// innermost last. It is only used when writing source names.
+fn write_styled(
+    expr: &Expr,
+    style: DisplayStyle,
+    named: bool,
+    church: bool,
+    scope: &mut Vec<(usize, String)>,
+    f: &mut std::fmt::Formatter<'_>,
+) -> std::fmt::Result
+{
+    let letter = |v: usize| ((v as u8 + 97) as char).to_string();
+    let sub = |expr: &Expr, scope: &mut Vec<(usize, String)>, f: &mut std::fmt::Formatter<'_>| {
+        write_styled(expr, style, named, church, scope, f)
+    };
+    let collapsed = |expr: &Expr| {
+        if !church || matches!(expr, Expr::MacroRef(_)) {
+            None
+        } else if let Some(n) = expr.as_church_numeral() {
+            Some(n.to_string())
+        } else {
+            expr.as_church_bool().map(|b| b.to_string())
+        }
+    };
+
+    if let Some(collapsed) = collapsed(expr) {
+        return write!(f, "{}", collapsed);
+    }
+
+    match expr {
+        Expr::Lambda { param, expr, name } => {
+            let lambda = match style {
+                DisplayStyle::Ascii   => '\\',
+                DisplayStyle::Unicode => 'λ',
+            };
+            let name = if named {
+                // Parameters without a source name are written as usual.
+                let base = name.0.as_deref().map_or_else(|| letter(*param), str::to_owned);
+                let mut name = base.clone();
+                let mut suffix = 1;
+                while scope.iter().any(|(id, used)| *used == name && id != param) {
+                    name = format!("{}{}", base, suffix);
+                    suffix += 1;
+                }
+                name
+            } else {
+                letter(*param)
+            };
+            write!(f, "{}{}. ", lambda, name)?;
+            scope.push((*param, name));
+            let result = sub(expr, scope, f);
+            scope.pop();
+            result
+        },
+        Expr::Appl { f: func, arg }  => {
+            if let (Expr::Literal(a), Expr::Literal(b)) = (func.as_ref(), arg.as_ref()) {
+                return write!(f, "\"{}{}\"", a.as_ref(), b.as_ref());
+            }
+            match func.as_ref() {
+                Expr::Lambda { .. } if collapsed(func).is_none() => {
+                    write!(f, "(")?;
+                    sub(func, scope, f)?;
+                    write!(f, ")")
+                },
+                _ => sub(func, scope, f),
+            }?;
+            write!(f, " ")?;
+            match arg.as_ref() {
+                Expr::Lambda { .. } | Expr::Appl{ .. } if collapsed(arg).is_none() => {
+                    write!(f, "(")?;
+                    sub(arg, scope, f)?;
+                    write!(f, ")")
+                },
+                _ => sub(arg, scope, f),
+            }
+        },
+        Expr::Var(v)            => {
+            let name = scope.iter().rev()
+                .find(|(id, _)| id == v)
+                .map_or_else(|| letter(*v), |(_, name)| name.clone());
+            write!(f, "{}", name)
+        },
+        Expr::Literal(s)        => write!(f, "{}", s),
+        Expr::MacroRef(mac)     => mac.write_name(f),
+        Expr::Intrinsic(intr)   => write!(f, "{}", intr.name()),
+        Expr::Nothing           |
+        Expr::Hole(_)           => write!(f, "[nothing expression]"),
+    }
+}
+
+impl std::fmt::Display for Expr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.display(DisplayStyle::default()).fmt(f)
+    }
+}
+
+impl std::default::Default for Expr {
+    fn default() -> Expr {
+        Expr::Nothing
+    }
+}
+
+impl Expr {
+    pub fn take(&mut self) -> Expr {
         std::mem::take(self)
     }
 
@@ -337,3 +2354,1218 @@ impl Expr {
         std::mem::replace(self, expr)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::compiler::{ compile_program, compile_stmt, define_macro, StmtReturn };
+
+    fn compile(input: &str) -> Expr {
+        compile_program(input).unwrap().expr
+    }
+
+    #[test]
+    fn test_church_bool() {
+        assert_eq!(compile("\\a. \\b. a").as_church_bool(), Some(true));
+        assert_eq!(compile("\\a. \\b. b").as_church_bool(), Some(false));
+        assert_eq!(compile("\\a. \\b. a b").as_church_bool(), None);
+        assert_eq!(compile("\\a. a").as_church_bool(), None);
+    }
+
+    #[test]
+    fn test_eta_expand() {
+        let original = Expr::Appl {
+            f: SubExpr::new(Expr::Var(0)),
+            arg: SubExpr::new(Expr::Var(1)),
+        };
+        let mut expr = original.clone();
+        expr.eta_expand();
+        assert_eq!(expr, Expr::Lambda {
+            param: 2,
+            expr: SubExpr::new(Expr::Appl {
+                f: SubExpr::new(original.clone()),
+                arg: SubExpr::new(Expr::Var(2)),
+            }),
+            name: VarName::default(),
+        });
+        assert!(expr.eta_reduce());
+        assert_eq!(expr, original);
+
+        let mut id = compile("\\a. a");
+        id.eta_expand();
+        assert_eq!(id.to_string(), "λb. (λa. a) b");
+        assert!(id.eta_reduce());
+        assert!(!id.eta_reduce());
+        assert_eq!(id, compile("\\a. a"));
+    }
+
+    #[test]
+    fn test_eta_reduce_keeps_used_param() {
+        let mut expr = compile("\\a. a a");
+        assert!(!expr.eta_reduce());
+    }
+
+    #[test]
+    fn test_if_intrinsic() {
+        let input = r#"
+            True  = \a. \b. a
+            False = \a. \b. b
+            Omega = (\a. a a) (\a. a a)
+
+            IF True "x" Omega
+        "#;
+        let mut expr = compile(input);
+        assert_eq!(expr.eval().unwrap().to_string(), "x");
+
+        let mut expr = compile("IF (\\a. \\b. b) ((\\a. a a) (\\a. a a)) \"y\"");
+        assert_eq!(expr.eval().unwrap().to_string(), "y");
+
+        // Not a boolean, so the condition is applied to both branches.
+        let mut expr = compile("IF (\\p. p) (\\a. \\b. b) \"x\" \"y\"");
+        assert_eq!(expr.eval().unwrap().to_string(), "y");
+    }
+
+    #[test]
+    fn test_concat_intrinsic() {
+        let mut expr = compile("CONCAT \"ab\" ((\\a. a) \"cd\")");
+        assert_eq!(expr.eval().unwrap(), &Expr::Literal(Rc::new("abcd".to_owned())));
+
+        let mut expr = compile("CONCAT \"ab\" ((\\a. a) \"cd\")");
+        assert_eq!(expr.normal_order_eval().unwrap(), &Expr::Literal(Rc::new("abcd".to_owned())));
+
+        // Doubles the string 4 times.
+        let input = r#"
+            Four = \f. \x. f (f (f (f x)))
+            Dup  = \s. CONCAT s s
+
+            Four Dup "x"
+        "#;
+        let mut ctx = EvalContext { max_literal_len: 10, ..EvalContext::new() };
+        assert!(matches!(compile(input).eval_with(&mut ctx), Err(RuntimeError::MaxLiteralConcat)));
+
+        let mut expr = compile(input);
+        let result = expr.eval().unwrap();
+        assert_eq!(result, &Expr::Literal(Rc::new("x".repeat(16))));
+
+        // Normal order reduction takes the limit from the context as well.
+        let mut ctx = EvalContext { max_literal_len: 10, ..EvalContext::new() };
+        let result = compile(input).normal_order_eval_with(&mut ctx, |_| ()).map(|_| ());
+        assert!(matches!(result, Err(RuntimeError::MaxLiteralConcat)));
+        let mut ctx = EvalContext { max_literal_len: 16, ..EvalContext::new() };
+        let mut expr = compile(input);
+        assert_eq!(expr.normal_order_eval_with(&mut ctx, |_| ()).unwrap(), &Expr::Literal(Rc::new("x".repeat(16))));
+    }
+
+    #[test]
+    fn test_normal_order_eval() {
+        // Normalizing the function first diverges on `Omega`, but the
+        // leftmost-outermost redex throws it away.
+        let input = r#"
+            K     = \a. \b. a
+            Omega = (\a. a a) (\a. a a)
+
+            (\k. k (\i. i) Omega) K
+        "#;
+        assert!(compile(input).eval_to_normal_form().is_err());
+        let mut expr = compile(input);
+        expr.normal_order_eval().unwrap().assert_alpha_eq(&compile("\\i. i"));
+
+        let input = r#"
+            Two  = \f. \x. f (f x)
+            Mult = \m. \n. \f. m (n f)
+
+            Mult Two (Mult Two Two)
+        "#;
+        let mut expr = compile(input);
+        assert_eq!(expr.normal_order_eval().unwrap().as_church_numeral(), Some(8));
+        expr.assert_alpha_eq(compile(input).eval_to_normal_form().unwrap());
+
+        let mut expr = compile("IF (\\a. \\b. b) ((\\a. a a) (\\a. a a)) \"y\"");
+        assert_eq!(expr.normal_order_eval().unwrap().to_string(), "y");
+
+        assert!(compile("(\\a. a a) (\\a. a a)").normal_order_eval().is_err());
+    }
+
+    #[test]
+    fn test_to_dot() {
+        // \a. a b
+        let expr = Expr::Lambda {
+            param: 0,
+            expr: SubExpr::new(Expr::Appl {
+                f: SubExpr::new(Expr::Var(0)),
+                arg: SubExpr::new(Expr::Var(1)),
+            }),
+            name: VarName::default(),
+        };
+        let dot = expr.to_dot(false);
+        assert!(dot.starts_with("digraph {\n"));
+        assert!(dot.contains("n0 [label=\"λa\"];"));
+        assert!(dot.contains("n1 [label=\"@\"];"));
+        assert!(dot.contains("n3 [label=\"b\"];"));
+        assert_eq!(dot.matches("[label=").count(), 4);
+        assert_eq!(dot.matches(" -> ").count(), 3);
+
+        let input = "Id = \\a. a\nId (Id \"x\")";
+        // Keeps the macros alive, since the dot graph shows their names.
+        let executable = compile_program(input).unwrap();
+        let expr = &executable.expr;
+        assert_eq!(expr.to_dot(false).matches("[label=").count(), 5);
+        let dot = expr.to_dot(true);
+        // The body of `Id` is only drawn once.
+        assert_eq!(dot.matches("[label=").count(), 6);
+        assert_eq!(dot.matches(" -> ").count(), 6);
+        assert!(dot.contains("[label=\"\\\"x\\\"\"];"));
+    }
+
+    #[test]
+    fn test_map_vars() {
+        let original = compile("\\a. \\b. a b");
+        let mut expr = original.clone();
+        expr.map_vars(|v| v * 2);
+        assert_eq!(expr, Expr::Lambda {
+            param: 0,
+            expr: SubExpr::new(Expr::Lambda {
+                param: 2,
+                expr: SubExpr::new(Expr::Appl {
+                    f: SubExpr::new(Expr::Var(0)),
+                    arg: SubExpr::new(Expr::Var(2)),
+                }),
+                name: VarName::default(),
+            }),
+            name: VarName::default(),
+        });
+        expr.assert_alpha_eq(&original);
+    }
+
+    #[test]
+    fn test_visitor() {
+        struct CountLambdas(usize);
+
+        impl ExprVisitor for CountLambdas {
+            fn visit_lambda(&mut self, _param: usize, body: &Expr) {
+                self.0 += 1;
+                body.walk(self);
+            }
+        }
+
+        let mut visitor = CountLambdas(0);
+        compile("\\a. \\b. a b").walk(&mut visitor);
+        assert_eq!(visitor.0, 2);
+    }
+
+    #[test]
+    fn test_add_macro() {
+        let mut executable = Executable::new(Expr::Nothing, HashMap::new(), HashSet::new());
+        let id = executable.add_macro("Id".to_owned(), Expr::Lambda {
+            param: 0,
+            expr: SubExpr::new(Expr::Var(0)),
+            name: VarName::default(),
+        }).unwrap();
+        let k = executable.add_macro("K".to_owned(), Expr::Lambda {
+            param: 0,
+            expr: SubExpr::new(Expr::lam(1, Expr::Var(0))),
+            name: VarName::default(),
+        }).unwrap();
+
+        // K Id K
+        executable.expr = Expr::Appl {
+            f: SubExpr::new(Expr::Appl {
+                f: SubExpr::new(Expr::MacroRef(Rc::clone(&k))),
+                arg: SubExpr::new(Expr::MacroRef(Rc::clone(&id))),
+            }),
+            arg: SubExpr::new(Expr::MacroRef(k)),
+        };
+        assert_eq!(executable.expr.to_string(), "K Id K");
+
+        executable.eval().unwrap();
+        match &executable.expr {
+            Expr::MacroRef(mac) => assert!(Rc::ptr_eq(mac, &id)),
+            other               => panic!("expected `Id`, got `{}`", other),
+        }
+        assert_eq!(executable.export_macros(), "Id = \\a. a\nK = \\a. \\b. a\n");
+
+        // A body with free variables is refused, and the environment is left
+        // as it was.
+        let err = executable.add_macro("Open".to_owned(), Expr::lam(0, Expr::Var(2))).unwrap_err();
+        assert_eq!(err, OpenMacro { name: "Open".to_owned(), free_vars: vec![2] });
+        assert!(!executable.macros.contains_key("Open"));
+    }
+
+    #[test]
+    fn test_macro_name_outlives_map_growth() {
+        let mut macros = HashMap::new();
+        let first = define_macro(&mut macros, "First", compile("\\a. a")).unwrap();
+        for i in 0..1000 {
+            define_macro(&mut macros, &format!("M{}", i), compile("\\a. a")).unwrap();
+        }
+        assert_eq!(Expr::MacroRef(Rc::clone(&first)).to_string(), "First");
+
+        // The name stays valid even after the environment is gone.
+        drop(macros);
+        assert_eq!(first.name(), "First");
+        assert_eq!(compile_program("F = \\a. a\nF").unwrap().expr.to_string(), "F");
+    }
+
+    #[test]
+    fn test_to_string_bounded() {
+        let expr = compile("\\f. \\x. f (f x)");
+        assert_eq!(expr.display(DisplayStyle::Ascii).to_string_bounded(100), ("\\a. \\b. a (a b)".to_owned(), false));
+        assert_eq!(expr.display(DisplayStyle::Unicode).to_string_bounded(4), ("λa. ".to_owned(), true));
+
+        // A balanced tree with 65536 leaves.
+        let mut huge = Expr::lit("leaf");
+        for _ in 0..16 {
+            huge = Expr::app(huge.clone(), huge);
+        }
+        let (shown, truncated) = huge.display(DisplayStyle::Ascii).to_string_bounded(50);
+        assert!(truncated);
+        assert_eq!(shown.chars().count(), 50);
+
+        // Counts the calls to `write_str`, to check that formatting really
+        // stops.
+        struct CountingWriter(BoundedWriter, usize);
+
+        impl std::fmt::Write for CountingWriter {
+            fn write_str(&mut self, s: &str) -> std::fmt::Result {
+                self.1 += 1;
+                self.0.write_str(s)
+            }
+        }
+
+        let bounded = BoundedWriter { out: String::new(), remaining: 50, truncated: false };
+        let mut writer = CountingWriter(bounded, 0);
+        assert!(std::fmt::write(&mut writer, format_args!("{}", huge)).is_err());
+        assert!(writer.1 < 100, "{} writes", writer.1);
+
+        assert_eq!(expr.display(DisplayStyle::Unicode).char_count(), "λa. λb. a (a b)".chars().count());
+    }
+
+    #[test]
+    fn test_arg_order() {
+        let reductions = |arg_order| {
+            // The argument is duplicated, so evaluating it first saves a step.
+            let mut expr = compile("(\\a. a a) ((\\x. x) (\\y. y))");
+            let mut ctx = EvalContext { arg_order, ..EvalContext::new() };
+            expr.eval_with(&mut ctx).unwrap();
+            expr.assert_alpha_eq(&compile("\\y. y"));
+            ctx.reductions
+        };
+        assert_eq!(reductions(ArgOrder::Lazy), 4);
+        assert_eq!(reductions(ArgOrder::LeftToRight), 3);
+        assert_eq!(reductions(ArgOrder::RightToLeft), 3);
+
+        // Both the function and the argument fail, so the error tells which
+        // one was evaluated first.
+        let error = |arg_order| {
+            let concat = compile("\\s. CONCAT s s");
+            let mut expr = Expr::app(
+                Expr::app(Expr::lam(0, Expr::Nothing), Expr::lit("a")),
+                Expr::app(concat, Expr::lit("aa")),
+            );
+            let mut ctx = EvalContext { arg_order, max_literal_len: 3, ..EvalContext::new() };
+            expr.eval_with(&mut ctx).unwrap_err()
+        };
+        assert!(matches!(error(ArgOrder::Lazy), RuntimeError::NothingEval(_)));
+        assert!(matches!(error(ArgOrder::LeftToRight), RuntimeError::NothingEval(_)));
+        assert!(matches!(error(ArgOrder::RightToLeft), RuntimeError::MaxLiteralConcat));
+    }
+
+    #[test]
+    fn test_preserve_macros() {
+        let input = r#"
+            True  = \a. \b. a
+            False = \a. \b. b
+            And   = \p. \q. p q False
+            \x. (\y. y) (x And True)
+        "#;
+        let mut preserved = compile(input);
+        preserved.eval_to_normal_form().unwrap();
+        assert_eq!(preserved.to_string(), "λa. a And True");
+
+        let mut expanded = compile(input);
+        let mut ctx = EvalContext { preserve_macros: false, normalize: true, ..EvalContext::new() };
+        expanded.eval_with(&mut ctx).unwrap();
+        assert!(!expanded.contains_macro("True", true), "{}", expanded);
+        assert!(!expanded.contains_macro("And", true), "{}", expanded);
+        expanded.assert_alpha_eq(&compile("\\x. x (\\p. \\q. p q (\\a. \\b. b)) (\\a. \\b. a)"));
+    }
+
+    #[test]
+    fn test_pretty_church() {
+        let input = r#"
+            Pair = \a. \b. \f. f a b
+            Pair 2 (Pair 3 (\a. \b. a))
+        "#;
+        let mut expr = compile(input);
+        expr.eval_to_normal_form().unwrap();
+        let pretty = expr.pretty_church().to_string();
+        assert!(pretty.ends_with(" 2 (λb. b 3 true)"), "{}", pretty);
+        assert!(!expr.to_string().contains('2'));
+
+        // Applied numerals aren't parenthesized as lambdas would be.
+        let expr = compile("\\g. (\\f. \\x. f x) g");
+        assert_eq!(expr.pretty_church().to_string(), "λa. 1 a");
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn test_eval_parallel() {
+        fn assert_send<T: Send>() {}
+        assert_send::<Expr>();
+
+        let input = r#"
+            Pair = \a. \b. \f. f a b
+            Mult = \m. \n. \f. m (n f)
+            Plus = \m. \n. \f. \x. m f (n f x)
+            \g. g (Pair (Mult 12 12) (Plus 100 100)) (Mult 7 (Mult 5 5))
+        "#;
+        let mut sequential = compile(input);
+        sequential.eval_to_normal_form().unwrap();
+
+        let mut parallel = compile(input);
+        parallel.eval_parallel().unwrap();
+        parallel.assert_alpha_eq(&sequential);
+        assert!(parallel.pretty_church().to_string().contains("144 200"), "{}", parallel.pretty_church());
+    }
+
+    #[test]
+    fn test_executable_from_file() {
+        let path = std::env::temp_dir().join(format!("lambda-from-file-{}.lambda", std::process::id()));
+        std::fs::write(&path, "Two = \\f. \\x. f (f x)\nMult = \\m. \\n. \\f. m (n f)\nMult Two Two\n").unwrap();
+        let result = Executable::from_file(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let mut executable = result.unwrap();
+        executable.normalize = true;
+        executable.eval().unwrap();
+        assert_eq!(executable.expr.as_church_numeral(), Some(4));
+
+        assert!(matches!(Executable::from_file(&path), Err(LoadError::Io(_))));
+    }
+
+    #[test]
+    fn test_is_normal_form() {
+        assert!(compile("\\f. \\x. f (f x)").is_normal_form());
+        assert!(compile("\\a. a a").is_normal_form());
+        assert!(!compile("\\f. \\x. f x").is_normal_form());
+
+        // Redexes nested in arguments and under lambdas.
+        assert!(!compile("\\g. \\y. g (\\x. y x)").is_normal_form());
+        assert!(!compile("\\g. \\y. g ((\\x. x) y)").is_normal_form());
+        assert!(!compile("\\g. \\y. \\z. g (\\x. \\w. z x w)").is_normal_form());
+        assert!(!compile("Id = \\a. a\n\\g. g (Id g)").is_normal_form());
+
+        // `is_whnf` only looks at the head.
+        let expr = compile("\\g. \\y. g (\\x. y x)");
+        assert!(expr.is_whnf());
+
+        // Each macro uses the previous one twice, so checking every
+        // reference would take 2^40 steps.
+        let mut macros = HashMap::new();
+        let mut prev = define_macro(&mut macros, "M0", compile("\\a. \\b. b a")).unwrap();
+        for i in 1..=40 {
+            let body = Expr::lam(0, Expr::app(
+                Expr::app(Expr::Var(0), Expr::MacroRef(Rc::clone(&prev))),
+                Expr::MacroRef(Rc::clone(&prev)),
+            ));
+            prev = define_macro(&mut macros, &format!("M{}", i), body).unwrap();
+        }
+        assert!(prev.is_normal_form());
+        let mut expr = Expr::MacroRef(Rc::clone(&prev));
+        assert!(expr.is_normal_form());
+        let mut ctx = EvalContext::new();
+        ctx.preserve_macros = true;
+        expr.eval_with(&mut ctx).unwrap();
+        assert_eq!(expr, Expr::MacroRef(prev));
+    }
+
+    #[test]
+    fn test_nothing_eval_context() {
+        // \f. \g. f (g [nothing])
+        let mut expr = Expr::lam(0, Expr::lam(1, Expr::app(Expr::var(0), Expr::app(Expr::var(1), Expr::Nothing))));
+
+        let err = expr.eval_to_normal_form().unwrap_err();
+        assert!(matches!(&err, RuntimeError::NothingEval(Some(context)) if context == "b [nothing expression]"), "{:?}", err);
+        assert_eq!(err.to_string(), "Tried to evaluate a nothing expression in `b [nothing expression]`");
+
+        let mut expr = Expr::Nothing;
+        assert!(matches!(expr.eval(), Err(RuntimeError::NothingEval(None))));
+    }
+
+    #[test]
+    fn test_flatten_appl() {
+        let expr = compile("\\f. \\a. \\b. \\c. f a (b c) c");
+        let mut body = &expr;
+        for _ in 0..4 {
+            let Expr::Lambda { expr, .. } = body else { panic!("expected four lambdas") };
+            body = expr;
+        }
+        let (head, args) = body.flatten_appl();
+        assert_eq!(*head, Expr::var(0));
+        assert_eq!(args, [&Expr::var(1), &Expr::app(Expr::var(2), Expr::var(3)), &Expr::var(3)]);
+
+        let (head, args) = expr.flatten_appl();
+        assert_eq!(head, &expr);
+        assert!(args.is_empty());
+    }
+
+    #[test]
+    fn test_normalize_bound_names() {
+        // λf. (λx. f x) (λy. y), with ids left sparse by earlier reductions.
+        let mut expr = Expr::lam(12, Expr::app(
+            Expr::lam(40, Expr::app(Expr::var(12), Expr::var(40))),
+            Expr::lam(73, Expr::var(73)),
+        ));
+        let original = expr.clone();
+        assert_eq!(expr.get_biggest_var_id(), Some(73));
+
+        expr.normalize_bound_names();
+        assert_eq!(expr.get_biggest_var_id(), Some(1));
+        assert_eq!(expr.display(DisplayStyle::Ascii).to_string(), "\\a. (\\b. a b) (\\b. b)");
+        assert!(expr.alpha_eq(&original));
+
+        // After a reduction, the names are as small as they can be.
+        let mut expr = compile("(\\a. \\b. \\c. a b c) (\\x. \\y. y)");
+        expr.reduce_to_fixpoint_with_limit(100);
+        expr.normalize_bound_names();
+        assert_eq!(expr.get_biggest_var_id(), Some(1));
+        assert_eq!(expr.display(DisplayStyle::Ascii).to_string(), "\\a. \\b. b");
+
+        // Free variables can't be captured.
+        let mut open = Expr::lam(7, Expr::app(Expr::var(0), Expr::var(7)));
+        open.normalize_bound_names();
+        assert_eq!(open, Expr::lam(1, Expr::app(Expr::var(0), Expr::var(1))));
+    }
+
+    #[test]
+    fn test_macro_accessors() {
+        let executable = compile_program("infixl 6 + = \\m. \\n. m n\nK = \\a. \\b. a\nK").unwrap();
+        let k = &executable.macros["K"];
+        assert_eq!(k.name(), "K");
+        k.body().assert_alpha_eq(&compile("\\x. \\y. x"));
+
+        let plus = &executable.macros["+"];
+        assert_eq!(plus.name(), "+");
+        assert!(plus.body().is_closed());
+    }
+
+    #[test]
+    fn test_rebuild_spine() {
+        // f a b c, with all four free.
+        let expr = Expr::app(Expr::app(Expr::app(Expr::var(0), Expr::var(1)), Expr::var(2)), Expr::var(3));
+        let (head, args) = expr.flatten_appl();
+        let rebuilt = Expr::rebuild_spine(head.clone(), args.into_iter().cloned().collect());
+        assert_eq!(rebuilt, expr);
+
+        assert_eq!(Expr::rebuild_spine(Expr::var(0), Vec::new()), Expr::var(0));
+    }
+
+    #[test]
+    fn test_builders() {
+        let id = Expr::lam(0, Expr::var(0));
+        id.assert_alpha_eq(&compile("\\a. a"));
+
+        // S = λx. λy. λz. x z (y z), and S K K behaves like the identity.
+        let s = Expr::lam(0, Expr::lam(1, Expr::lam(2, Expr::app(
+            Expr::app(Expr::var(0), Expr::var(2)),
+            Expr::app(Expr::var(1), Expr::var(2)),
+        ))));
+        let k = Expr::lam(0, Expr::lam(1, Expr::var(0)));
+        let mut expr = Expr::app(Expr::app(Expr::app(s, k.clone()), k), Expr::lit("hello"));
+        expr.eval().unwrap();
+        assert_eq!(expr, Expr::lit("hello"));
+    }
+
+    #[test]
+    fn test_map_macro_refs() {
+        let input = "Two = \\f. \\x. f (f x)\nMult = \\m. \\n. \\f. m (n f)\nMult Two Two";
+        let executable = compile_program(input).unwrap();
+
+        // A fresh environment, with the same definitions but other `Rc`s.
+        let mut literals = HashSet::new();
+        let mut macros = HashMap::new();
+        for line in executable.export_macros().lines() {
+            compile_stmt(line, &mut literals, &mut macros).unwrap();
+        }
+
+        let mut expr = executable.expr.clone();
+        expr.map_macro_refs(|name| macros.get(name).cloned()).unwrap();
+        for dep in expr.dependencies() {
+            assert!(Rc::ptr_eq(&dep, &macros[dep.name()]));
+        }
+        expr.eval_to_normal_form().unwrap();
+        assert_eq!(expr.as_church_numeral(), Some(4));
+
+        macros.remove("Two");
+        let mut expr = executable.expr.clone();
+        let err = expr.map_macro_refs(|name| macros.get(name).cloned()).unwrap_err();
+        assert_eq!(err.name, "Two");
+    }
+
+    #[test]
+    fn test_equivalences() {
+        // `\a. f a` and `f`, where `f` is free.
+        let eta_long = Expr::lam(0, Expr::app(Expr::var(9), Expr::var(0)));
+        let f = Expr::var(9);
+        assert!(!eta_long.alpha_eq(&f));
+        assert_eq!(eta_long.beta_eq(&f, 100), Some(false));
+        assert_eq!(eta_long.eta_eq(&f, 100), Some(true));
+
+        // Renaming the bound variable can't capture a free one.
+        let open = Expr::lam(5, Expr::app(Expr::var(0), Expr::var(5)));
+        assert!(!open.alpha_eq(&Expr::lam(0, Expr::app(Expr::var(0), Expr::var(0)))));
+        assert!(open.alpha_eq(&Expr::lam(7, Expr::app(Expr::var(0), Expr::var(7)))));
+
+        let id = compile("\\a. a");
+        assert!(id.alpha_eq(&compile("\\x. x")));
+        let applied = compile("(\\a. a) (\\b. b)");
+        assert!(!applied.alpha_eq(&id));
+        assert_eq!(applied.beta_eq(&id, 100), Some(true));
+
+        // Macros are expanded to compare the normal forms.
+        let with_macro = compile_program("Id = \\a. a\n\\f. f Id").unwrap().expr;
+        assert!(!with_macro.alpha_eq(&compile("\\f. f (\\a. a)")));
+        assert_eq!(with_macro.beta_eq(&compile("\\f. f (\\a. a)"), 100), Some(true));
+
+        // Terms without a normal form are not compared.
+        let omega = compile("(\\a. a a) (\\a. a a)");
+        assert_eq!(omega.beta_eq(&omega, 100), None);
+        assert_eq!(omega.eta_eq(&id, 100), None);
+    }
+
+    #[test]
+    fn test_subst() {
+        // With a single use, the argument is moved in instead of copied.
+        let arg = compile("\\f. \\g. f (f g)");
+        let Expr::Lambda { expr: arg_body, .. } = &arg else { panic!("{}", arg) };
+        let arg_body: *const Expr = arg_body.as_ref();
+        let mut expr = Expr::app(Expr::var(10), Expr::lam(11, Expr::var(12)));
+        expr.subst(12, arg);
+        let Expr::Appl { arg: used, .. } = &expr else { panic!("{}", expr) };
+        let Expr::Lambda { expr: used, .. } = used.as_ref() else { panic!("{}", expr) };
+        let Expr::Lambda { expr: used_body, .. } = used.as_ref() else { panic!("{}", expr) };
+        assert!(std::ptr::eq(used_body.as_ref(), arg_body));
+
+        // Otherwise every use gets a copy, except where the variable is bound
+        // again.
+        let arg = compile("\\a. a");
+        let mut expr = Expr::app(Expr::app(Expr::var(12), Expr::var(12)), Expr::lam(12, Expr::var(12)));
+        let mut expected = expr.clone();
+        expected.subst_many(&[(12, arg.clone())]);
+        expr.subst(12, arg.clone());
+        assert_eq!(expr, expected);
+        assert_eq!(expr, Expr::app(Expr::app(arg.clone(), arg), Expr::lam(12, Expr::var(12))));
+    }
+
+    #[test]
+    fn test_clone_shared() {
+        let expr = compile("\\a. \\b. a (a (a (a b)))");
+        let shared = expr.clone_shared();
+        assert_eq!(shared, expr);
+
+        // Copies of a shared expression only copy their root.
+        let mut copies = Expr::app(shared.clone(), shared);
+        assert_eq!(copies.allocations(), expr.allocations() + 2);
+
+        // Changing one of the copies leaves the other alone.
+        let Expr::Appl { arg, .. } = &mut copies else { panic!("{}", copies) };
+        if let Expr::Lambda { expr: body, .. } = &mut **arg {
+            **body = Expr::Nothing;
+        }
+        let Expr::Appl { f, arg } = &copies else { panic!("{}", copies) };
+        assert_eq!(f.as_ref(), &expr);
+        assert_eq!(arg.as_ref(), &Expr::lam(0, Expr::Nothing));
+    }
+
+    #[test]
+    fn test_beta_reduce_shares_argument() {
+        let f = compile("\\f. \\x. f (f (f x))");
+        let arg = compile("\\a. \\b. a (a (a (a b)))");
+        let mut expr = Expr::app(f.clone(), arg.clone());
+        assert!(expr.beta_reduce(0));
+
+        let Expr::Lambda { param, expr: body, .. } = f else { panic!() };
+        let mut renamed = arg.clone();
+        renamed.alpha_convert_from(2);
+        let mut expected = body.into_inner();
+        expected.subst_many(&[(param, renamed)]);
+        expected.alpha_convert_from(0);
+        assert_eq!(expr, expected);
+
+        // The argument is used three times, but, except for its root, its
+        // nodes are only allocated once.
+        assert_eq!(expected.allocations() - expr.allocations(), 2 * arg.allocations());
+    }
+
+    #[test]
+    fn test_subst_many() {
+        let appl = |f, arg| Expr::Appl { f: SubExpr::new(f), arg: SubExpr::new(arg) };
+
+        let mut expr = appl(Expr::Var(0), Expr::Var(1));
+        expr.subst_many(&[(0, Expr::Var(1)), (1, Expr::Var(0))]);
+        assert_eq!(expr, appl(Expr::Var(1), Expr::Var(0)));
+
+        // Doing it one at a time mixes them up.
+        let mut sequential = appl(Expr::Var(0), Expr::Var(1));
+        sequential.subst(0, Expr::Var(1));
+        sequential.subst(1, Expr::Var(0));
+        assert_eq!(sequential, appl(Expr::Var(0), Expr::Var(0)));
+
+        // Only the variables that are not bound by a lambda are replaced.
+        let mut expr = appl(Expr::Var(0), Expr::lam(0, appl(Expr::Var(0), Expr::Var(1))));
+        expr.subst_many(&[(0, Expr::Var(2)), (1, Expr::Var(3))]);
+        assert_eq!(expr, appl(Expr::Var(2), Expr::lam(0, appl(Expr::Var(0), Expr::Var(3)))));
+    }
+
+    #[test]
+    fn test_reduce_to_fixpoint_with_limit() {
+        let mut expr = compile("(\\a. \\b. a b) (\\c. c) (\\d. d)");
+        assert!(matches!(expr.reduce_to_fixpoint_with_limit(100), EvalOutcome::Normalized(3)));
+        expr.assert_alpha_eq(&compile("\\d. d"));
+
+        // Exactly as many steps as needed is enough.
+        let mut expr = compile("(\\a. \\b. a b) (\\c. c) (\\d. d)");
+        assert!(matches!(expr.reduce_to_fixpoint_with_limit(3), EvalOutcome::Normalized(3)));
+
+        let mut expr = compile("\\a. a");
+        assert!(matches!(expr.reduce_to_fixpoint_with_limit(0), EvalOutcome::Normalized(0)));
+
+        let mut omega = compile("(\\a. a a) (\\a. a a)");
+        assert!(matches!(omega.reduce_to_fixpoint_with_limit(100), EvalOutcome::LimitReached));
+
+        let mut expr = Expr::Nothing;
+        assert!(matches!(
+            expr.reduce_to_fixpoint_with_limit(100),
+            EvalOutcome::Error(RuntimeError::NothingEval(_))
+        ));
+    }
+
+    #[test]
+    fn test_normal_order_eval_traced() {
+        let mut expr = compile("(\\a. \\b. a b) (\\c. c) (\\d. d)");
+        let mut steps = Vec::new();
+        expr.normal_order_eval_traced(|step| steps.push(step.to_string())).unwrap();
+        assert_eq!(steps.len(), 3);
+        assert_eq!(steps.last().unwrap(), &expr.to_string());
+
+        // Nothing is traced for an expression that is already normal.
+        let mut expr = compile("\\a. a");
+        expr.normal_order_eval_traced(|step| panic!("unexpected step {}", step)).unwrap();
+    }
+
+    #[test]
+    fn test_eval_with_trace() {
+        let input = "(\\m. \\n. n m) (\\f. \\x. f (f x)) (\\f. \\x. f (f x))";
+        let mut expected = compile(input);
+        let mut ctx = EvalContext::normalizing();
+        expected.eval_with(&mut ctx).unwrap();
+
+        let mut steps = Vec::new();
+        let mut traced = compile(input);
+        let mut traced_ctx = EvalContext::normalizing().with_trace(|step| steps.push(step.to_string()));
+        traced.eval_with(&mut traced_ctx).unwrap();
+        let reductions = traced_ctx.reductions;
+        drop(traced_ctx);
+
+        // Tracing doesn't change how the expression is evaluated.
+        assert_eq!(traced, expected);
+        assert_eq!(reductions, ctx.reductions);
+        assert_eq!(steps.len(), reductions);
+    }
+
+    #[test]
+    fn test_alpha_convert_is_idempotent() {
+        let inputs = [
+            "(\\a. \\b. a b) (\\c. c)",
+            "(\\a. a a) (\\b. \\c. b c)",
+            "(\\a. \\b. \\c. a (b c)) (\\d. d) (\\e. \\f. e)",
+            "\\x. (\\a. \\b. a) x (\\c. c x)",
+            "(\\f. \\x. f (f x)) (\\f. \\x. f (f x))",
+        ];
+        for input in inputs.iter() {
+            let mut expr = compile(input);
+            expr.eval().unwrap();
+            expr.alpha_convert();
+            let once = expr.clone();
+            expr.alpha_convert();
+            assert_eq!(expr, once, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_eval_with_progress() {
+        // 4 ^ 4, in Church numerals, takes a lot of reductions.
+        let input = "(\\m. \\n. n m) (\\f. \\x. f (f (f (f x)))) (\\f. \\x. f (f (f (f x))))";
+        let mut reported = Vec::new();
+        let mut ctx = EvalContext::normalizing()
+            .with_progress(10, |steps| {
+                reported.push(steps);
+                steps < 30
+            });
+        let err = compile(input).eval_with(&mut ctx).unwrap_err();
+        drop(ctx);
+        assert!(matches!(err, RuntimeError::Cancelled), "{}", err);
+        assert_eq!(reported, vec![10, 20, 30]);
+
+        let mut calls = 0;
+        let mut expr = compile(input);
+        let mut ctx = EvalContext::normalizing().with_progress(1, |_| { calls += 1; true });
+        expr.eval_with(&mut ctx).unwrap();
+        drop(ctx);
+        assert_eq!(expr.as_church_numeral(), Some(256));
+        assert!(calls > 30);
+    }
+
+    #[test]
+    fn test_display_named() {
+        let executable = compile_program("\\foo. foo").unwrap();
+        assert_eq!(executable.display_named(DisplayStyle::Unicode).to_string(), "λfoo. foo");
+        assert_eq!(executable.expr.to_string(), "λa. a");
+
+        let executable = compile_program("\\x. \\y. x y (\\z. z)").unwrap();
+        assert_eq!(executable.display_named(DisplayStyle::Ascii).to_string(), "\\x. \\y. x y (\\z. z)");
+
+        // Sibling lambdas share an id, but not a name.
+        let executable = compile_program("(\\x. \\y. y x) (\\q. q)").unwrap();
+        assert_eq!(executable.display_named(DisplayStyle::Ascii).to_string(), "(\\x. \\y. y x) (\\q. q)");
+
+        // The names are kept through reduction, which renumbers the variables.
+        let mut executable = compile_program("(\\x. \\y. y x) (\\q. q)").unwrap();
+        executable.expr.eval_to_normal_form().unwrap();
+        assert_eq!(executable.display_named(DisplayStyle::Ascii).to_string(), "\\y. y (\\q. q)");
+        assert_eq!(executable.expr.to_string(), "λa. a (λb. b)");
+
+        // After reduction, the same name may end up bound twice.
+        let mut executable = compile_program("(\\f. \\x. f x) (\\y. \\x. y)").unwrap();
+        executable.expr.eval_to_normal_form().unwrap();
+        assert_eq!(executable.display_named(DisplayStyle::Ascii).to_string(), "\\x. \\x1. x");
+    }
+
+    #[test]
+    fn test_biggest_var_id_visits_macros_once() {
+        assert_eq!(compile("\\a. \\b. a").get_biggest_var_id(), Some(1));
+        assert_eq!(compile("\"x\" (\\a. a)").get_biggest_var_id(), Some(0));
+        assert_eq!(compile("\"x\"").get_biggest_var_id(), None);
+
+        // Each macro uses the previous one twice, so walking into every
+        // reference would take 2^40 steps. Macros can't refer to themselves
+        // yet, so this is the closest to a cycle that can be built.
+        let mut macros = HashMap::new();
+        let mut prev = define_macro(&mut macros, "M0", compile("\\a. \\b. \\c. a")).unwrap();
+        for i in 1..=40 {
+            let body = Expr::Lambda {
+                param: 0,
+                expr: SubExpr::new(Expr::Appl {
+                    f: SubExpr::new(Expr::MacroRef(Rc::clone(&prev))),
+                    arg: SubExpr::new(Expr::Appl {
+                        f: SubExpr::new(Expr::MacroRef(Rc::clone(&prev))),
+                        arg: SubExpr::new(Expr::Var(0)),
+                    }),
+                }),
+                name: VarName::default(),
+            };
+            prev = define_macro(&mut macros, &format!("M{}", i), body).unwrap();
+        }
+        assert_eq!(Expr::MacroRef(prev).get_biggest_var_id(), Some(2));
+    }
+
+    #[test]
+    fn test_combinator_form() {
+        struct CountLambdas(usize);
+
+        impl ExprVisitor for CountLambdas {
+            fn visit_lambda(&mut self, _param: usize, body: &Expr) {
+                self.0 += 1;
+                body.walk(self);
+            }
+        }
+
+        assert_eq!(compile("\\a. a").to_combinator_form().to_string(), "I");
+        assert_eq!(compile("\\a. \\b. a").to_combinator_form().to_string(), "K");
+        assert_eq!(compile("\\a. \\b. b").to_combinator_form().to_string(), "K I");
+        assert_eq!(compile("\\a. \\b. \\c. a c (b c)").to_combinator_form().to_string(), "S");
+
+        let inputs = [
+            "(\\a. a) \"v\"",
+            "(\\m. \\n. \\f. \\x. m f (n f x)) 2 3 (CONCAT \"x\") \"\"",
+            "(\\b. \\e. e b) 2 3 (CONCAT \"y\") \"\"",
+        ];
+        for input in inputs.iter() {
+            let expr = compile(input);
+            let mut combinators = expr.to_combinator_form();
+            let mut visitor = CountLambdas(0);
+            combinators.walk(&mut visitor);
+            assert_eq!(visitor.0, 0, "{}", combinators);
+
+            combinators.eval_to_normal_form().unwrap();
+            let mut expected = expr.clone();
+            expected.eval_to_normal_form().unwrap();
+            assert_eq!(combinators, expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_occurs_free() {
+        // `a` is used in the body, but bound by the lambda itself.
+        let expr = compile("\\a. a");
+        assert!(!expr.occurs_free(0));
+        let Expr::Lambda { expr: body, .. } = &expr else { panic!("{}", expr) };
+        assert!(body.occurs_free(0));
+
+        // `b` is bound outside of `\a. b`.
+        let expr = compile("\\b. \\a. b");
+        let Expr::Lambda { expr: inner, .. } = &expr else { panic!("{}", expr) };
+        assert!(inner.occurs_free(0));
+        assert!(!inner.occurs_free(1));
+
+        // A lambda that binds the same id shadows it.
+        let shadowed = Expr::Appl {
+            f: SubExpr::new(Expr::lam(0, Expr::Var(0))),
+            arg: SubExpr::new(Expr::Var(1)),
+        };
+        assert!(!shadowed.occurs_free(0));
+        assert!(shadowed.occurs_free(1));
+    }
+
+    #[test]
+    fn test_describe() {
+        assert_eq!(compile("\\f. \\x. f (f x)").describe(), vec!["λa. λb. a (a b)", "#2"]);
+        assert_eq!(compile("\\a. \\b. b").describe(), vec!["λa. λb. b", "#0", "#false"]);
+        assert_eq!(compile("\\a. a").describe(), vec!["λa. a"]);
+        assert_eq!(compile("\"x\"").describe(), vec!["x"]);
+        assert_eq!(compile("\"a\" (\"b\" \"c\")").describe()[1], "\"abc\"");
+    }
+
+    #[test]
+    fn test_is_value() {
+        assert!(compile("\\a. a").is_value());
+        assert!(compile("\\a. (\\b. b) a").is_value());
+        assert!(compile("\"x\" \"y\"").is_value());
+        assert!(!compile("(\\a. a) \"x\"").is_value());
+        assert!(!compile("\"x\" ((\\a. a) \"y\")").is_value());
+
+        // In the bodies, `x` is free: applying it gets stuck, while applying a
+        // lambda to it is still a redex.
+        let expr = compile("\\x. (\\a. a) x");
+        let Expr::Lambda { expr: body, .. } = &expr else { panic!("{}", expr) };
+        assert!(!body.is_value());
+        let expr = compile("\\x. x (\\a. a)");
+        let Expr::Lambda { expr: body, .. } = &expr else { panic!("{}", expr) };
+        assert!(body.is_value());
+    }
+
+    #[test]
+    fn test_eval_with_fuel() {
+        let input = "(\\m. \\n. n m) (\\f. \\x. f (f x)) (\\f. \\x. f (f (f x)))";
+        let mut expected = compile(input);
+        expected.eval_to_normal_form().unwrap();
+
+        let mut expr = compile(input);
+        let mut fuel = 0;
+        let mut refills = 0;
+        loop {
+            let result = {
+                let mut ctx = EvalContext::normalizing().with_fuel(&mut fuel);
+                expr.eval_with(&mut ctx).map(|_| ())
+            };
+            match result {
+                Ok(_)                         => break,
+                Err(RuntimeError::OutOfFuel) => {
+                    assert_eq!(fuel, 0);
+                    fuel += 3;
+                    refills += 1;
+                },
+                Err(err)                      => panic!("{}", err),
+            }
+            assert!(refills < 1000, "evaluation doesn't make progress");
+        }
+        assert!(refills > 1);
+        assert_eq!(expr.as_church_numeral(), Some(8));
+        assert_eq!(expr, expected);
+
+        // With enough fuel it is done at once, and the rest is left over.
+        let mut fuel = 1000;
+        let mut expr = compile(input);
+        expr.eval_with_fuel(&mut fuel).unwrap();
+        assert!(fuel > 0 && fuel < 1000);
+    }
+
+    #[test]
+    fn test_contains_macro() {
+        let input = "H = \\a. a\nG = \\a. H a\nF = \\a. a\n\\x. F (G x)";
+        let executable = compile_program(input).unwrap();
+        let expr = &executable.expr;
+        assert!(expr.contains_macro("F", false));
+        assert!(expr.contains_macro("G", false));
+        assert!(!expr.contains_macro("H", false));
+        assert!(expr.contains_macro("H", true));
+        assert!(!expr.contains_macro("I", true));
+    }
+
+    #[test]
+    fn test_memapply() {
+        let mut expr = compile("\\a. \\b. a");
+        memapply(&mut expr, |expr| match expr {
+            Expr::Lambda { expr, .. } => expr.into_inner(),
+            _                         => panic!("expected a lambda"),
+        });
+        assert_eq!(expr, Expr::lam(1, Expr::Var(0)));
+
+        // If `f` panics, `dest` is still left with a valid value.
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            memapply(&mut expr, |_| panic!("f panicked"));
+        }));
+        assert!(result.is_err());
+        assert_eq!(expr, Expr::Nothing);
+    }
+
+    #[test]
+    fn test_display_style() {
+        let expr = compile("(\\a. \\b. a) (\\c. c)");
+        assert_eq!(expr.display(DisplayStyle::Unicode).to_string(), "(λa. λb. a) (λa. a)");
+        assert_eq!(expr.display(DisplayStyle::Ascii).to_string(), "(\\a. \\b. a) (\\a. a)");
+        assert_eq!(expr.to_string(), expr.display(DisplayStyle::Unicode).to_string());
+    }
+
+    #[test]
+    fn test_memoized_eval() {
+        let input = r#"
+            Two  = \f. \x. f (f x)
+            Mult = \m. \n. \f. m (n f)
+
+            Mult Two (Mult Two Two) (\a. a) "x"
+        "#;
+
+        let mut plain = compile_program(input).unwrap();
+        plain.eval().unwrap();
+
+        let mut memoized = compile_program(input).unwrap();
+        memoized.memoize = true;
+        memoized.eval().unwrap();
+
+        assert_eq!(plain.expr, memoized.expr);
+        assert!(memoized.reductions < plain.reductions,
+            "memoized: {}, plain: {}", memoized.reductions, plain.reductions);
+    }
+
+    #[test]
+    fn test_memoized_eval_in_head_position() {
+        // `Id True` is first evaluated as an argument, where the result may
+        // be kept as `True`, and then applied, where it must be expanded.
+        let macros = "Id = \\a. a\nTrue = \\a. \\b. a\nFalse = \\a. \\b. b\n";
+        let eval = |input: &str, memo: bool, normalize: bool, arg_order: ArgOrder| {
+            let mut expr = compile(&format!("{}{}", macros, input));
+            let mut ctx = if memo { EvalContext::memoized() } else { EvalContext::new() };
+            ctx.normalize = normalize;
+            ctx.arg_order = arg_order;
+            expr.eval_with(&mut ctx).unwrap();
+            expr
+        };
+
+        let input = "(\\p. (Id True) False True) (Id True)";
+        let plain = eval(input, false, false, ArgOrder::LeftToRight);
+        assert_eq!(plain.to_string(), "False");
+        assert_eq!(eval(input, true, false, ArgOrder::LeftToRight), plain);
+
+        let input = "\\p. p (Id True) ((Id True) False True)";
+        let plain = eval(input, false, true, ArgOrder::Lazy);
+        assert_eq!(plain.to_string(), "λa. a True False");
+        assert_eq!(eval(input, true, true, ArgOrder::Lazy), plain);
+    }
+
+    #[test]
+    fn test_count_var_occurrences() {
+        match compile("\\a. a a a") {
+            Expr::Lambda { param, expr, .. } => {
+                assert_eq!(expr.count_var_occurrences(param), 3);
+                assert_eq!(expr.count_var_occurrences(param + 1), 0);
+            },
+            _ => panic!("should be a lambda"),
+        }
+    }
+
+    #[test]
+    fn test_eval_to_whnf() {
+        let input = "\\a. (\\b. b) a";
+        let mut whnf = compile(input);
+        whnf.eval_to_whnf().unwrap();
+        assert_eq!(whnf, compile(input));
+
+        let mut lazy = compile(input);
+        lazy.eval().unwrap();
+        assert_eq!(lazy, compile(input));
+
+        let mut normal = compile(input);
+        normal.eval_to_normal_form().unwrap();
+        assert_eq!(normal.pure_alpha_convert(), compile("\\a. a"));
+    }
+
+    #[test]
+    fn test_eval_under_binders_avoids_capture() {
+        let input = r#"
+            K = \a. \b. a
+            \x. \y. K y
+        "#;
+        let mut expr = compile(input);
+        expr.eval_to_normal_form().unwrap();
+        assert_eq!(expr.pure_alpha_convert(), compile("\\x. \\y. \\z. y"));
+    }
+
+    #[test]
+    fn test_pure_eval() {
+        let expr = compile("(\\a. a) (\\b. b)");
+        let original = expr.clone();
+        let result = expr.pure_eval().unwrap();
+        assert_eq!(expr, original);
+        assert_eq!(result, compile("\\a. a"));
+    }
+
+    #[test]
+    fn test_is_closed() {
+        assert!(compile("\\a. a").is_closed());
+        match compile("\\a. \\b. a b") {
+            Expr::Lambda { expr, .. } => assert!(!expr.is_closed()),
+            _                         => panic!("should be a lambda"),
+        }
+        assert!(!Expr::lam(0, Expr::Var(1)).is_closed());
+    }
+
+    #[test]
+    fn test_export_macros() {
+        let input = r#"
+            Two  = \f. \x. f (f x)
+            Mult = \m. \n. \f. m (n f)
+            Quad = Mult Two Two
+            Str  = (\a. a) "say \"hi\""
+
+            Quad
+        "#;
+        let executable = compile_program(input).unwrap();
+        let exported = executable.export_macros();
+
+        let mut literals = HashSet::new();
+        let mut macros = HashMap::new();
+        for line in exported.lines() {
+            compile_stmt(line, &mut literals, &mut macros).unwrap();
+        }
+
+        assert_eq!(macros.len(), executable.macros.len());
+        for (name, mac) in executable.macros.iter() {
+            assert_eq!(macros[name], *mac, "macro {} differs", name);
+        }
+    }
+
+    #[test]
+    fn test_export_redefined_macros() {
+        let inputs = [
+            "Id = \\a. a\nB = \\x. Id x\nId = \\a. \\b. a\nB Id",
+            "Id = \\a. a\nIdOld = Id\nId = \\a. Id IdOld a\nId",
+            "infixl 6 + = \\a. \\b. a\nB = \\x. \\y. x + y\ninfixr 2 + = \\a. \\b. B b a\nB",
+        ];
+        for input in inputs.iter() {
+            let executable = compile_program(input).unwrap();
+            let exported = executable.export_macros();
+
+            let mut literals = HashSet::new();
+            let mut macros = HashMap::new();
+            for line in exported.lines() {
+                compile_stmt(line, &mut literals, &mut macros).unwrap_or_else(|err| panic!("{}\n{}", exported, err));
+            }
+            for (name, mac) in executable.macros.iter() {
+                assert_eq!(macros[name], *mac, "macro {} differs in\n{}", name, exported);
+                assert_eq!(macros[name].fixity(), mac.fixity());
+            }
+            // The replaced definitions are written in the same order, so
+            // exporting again gives the same output.
+            assert_eq!(export_macros(&macros), exported);
+        }
+    }
+
+    #[test]
+    fn test_to_source_round_trip() {
+        let defs = "a = \\x. x\ninfixl 6 + = \\m. \\n. \\f. \\x. m f (n f x)\n";
+        let inputs = [
+            "\\f. \\x. f (f x)",
+            "(\\a. a) (\\b. \\c. b c c) \"say \\\"hi\\\"\"",
+            "\\f. f a (\\g. g) (f a)",
+            "\\f. (\\x. f x) (\\y. y) 3",
+            "\\f. f (1 + 2)",
+            "\\in. in a",
+        ];
+        for input in inputs.iter() {
+            let executable = compile_program(&format!("{}{}", defs, input)).unwrap();
+            let source = executable.expr.to_source();
+            assert!(!source.contains('λ'), "{}", source);
+
+            let mut literals = HashSet::new();
+            let mut macros = executable.macros.clone();
+            let recompiled = match compile_stmt(&source, &mut literals, &mut macros) {
+                Ok(StmtReturn::Expr(expr)) => expr,
+                Ok(StmtReturn::Macro(_))   => panic!("`{}` compiled to a definition", source),
+                Err(err)                   => panic!("`{}` doesn't compile: {}", source, err),
+            };
+            assert!(recompiled.alpha_eq(&executable.expr), "`{}` became `{}`", input, source);
+        }
+
+        // The variable can't be named `a`, since that would hide the macro.
+        let executable = compile_program(&format!("{}\\f. f a", defs)).unwrap();
+        assert_eq!(executable.expr.to_source(), "\\b. b a");
+    }
+
+    #[test]
+    fn test_export_private_macros() {
+        let input = "priv Two = \\f. \\x. f (f x)\n\
+                     priv Mult = \\m. \\n. \\f. m (n f)\n\
+                     Four = Mult Two Two\n\
+                     Mult Four Two";
+        let mut executable = compile_program(input).unwrap();
+        assert!(executable.macros["Two"].is_private());
+        assert!(!executable.macros["Four"].is_private());
+
+        // Private macros are usable in the file that defines them.
+        executable.normalize = true;
+        executable.eval().unwrap();
+        assert_eq!(executable.expr.as_church_numeral(), Some(8));
+
+        // They are only exported because `Four` needs them, and stay private.
+        let exported = executable.export_macros();
+        assert_eq!(exported.lines().filter(|line| line.starts_with("priv ")).count(), 2, "{}", exported);
+        assert!(exported.lines().last().unwrap().starts_with("Four = "), "{}", exported);
+
+        let executable = compile_program("priv Two = \\f. \\x. f (f x)\nId = \\a. a\nId Two").unwrap();
+        assert_eq!(executable.export_macros(), "Id = \\a. a\n");
+    }
+
+    #[test]
+    fn test_export_is_deterministic() {
+        let mut input = String::new();
+        for i in 0..50 {
+            input.push_str(&format!("M{} = \\a. \\b. b\n", source_var_name(i)));
+        }
+        input.push_str("Last = Mh Md\nLast");
+
+        // Each compilation gets maps with different hashers.
+        let first = compile_program(&input).unwrap().export_macros();
+        let second = compile_program(&input).unwrap().export_macros();
+        assert_eq!(first, second);
+        assert!(first.starts_with("Mh = \\a. \\b. b\nMd = \\a. \\b. b\nLast = Mh Md\nMa = "), "{}", first);
+
+        // Replaced definitions get the same fresh names every time.
+        let input = "Id = \\a. a\nK = \\a. \\b. Id a\nId = \\a. \\b. b\nF = \\a. K Id a\nK = \\a. a\nF";
+        let first = compile_program(input).unwrap().export_macros();
+        let second = compile_program(input).unwrap().export_macros();
+        assert_eq!(first, second);
+        assert_eq!(
+            first,
+            "priv IdOld = \\a. a\npriv KOld = \\a. \\b. IdOld a\nId = \\a. \\b. b\nF = \\a. KOld Id a\nK = \\a. a\n",
+        );
+    }
+
+    #[test]
+    fn test_church_numeral() {
+        assert_eq!(compile("\\f. \\x. x").as_church_numeral(), Some(0));
+        assert_eq!(compile("\\f. \\x. f (f x)").as_church_numeral(), Some(2));
+        assert_eq!(compile("\\f. \\x. x f").as_church_numeral(), None);
+    }
+}
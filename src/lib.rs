@@ -0,0 +1,32 @@
+//! A lambda calculus interpreter. The REPL lives in the binary, behind the
+//! `repl` feature, so the library can be used without `rustyline`.
+
+#![allow(dead_code)]
+#![allow(incomplete_features)]
+#![feature(array_windows)]
+#![feature(try_trait)]
+#![feature(hash_set_entry)]
+#![feature(str_split_once)]
+#![feature(box_patterns)]
+#![feature(bindings_after_at)]
+#![feature(if_let_guard)]
+#![feature(pattern)]
+#![feature(box_syntax)]
+#![feature(cell_update)]
+#![feature(is_sorted)]
+
+pub mod span;
+pub mod shared;
+pub mod error;
+pub mod interpreter;
+pub mod shared_expr;
+pub mod compiler;
+pub mod parser;
+mod utils;
+#[cfg(any(test, feature = "bench-support"))]
+pub mod bench_support;
+#[cfg(test)]
+mod test_support;
+
+// TODO: Maybe will became a submodule somewhere.
+// mod thunk;
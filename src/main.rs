@@ -1,77 +1,548 @@
-#![allow(dead_code)]
-#![allow(incomplete_features)]
-#![feature(array_windows)]
-#![feature(try_trait)]
-#![feature(hash_set_entry)]
-#![feature(str_split_once)]
-#![feature(box_patterns)]
-#![feature(bindings_after_at)]
-#![feature(if_let_guard)]
-#![feature(iterator_fold_self)]
-#![feature(pattern)]
-#![feature(box_syntax)]
-#![feature(cell_update)]
-#![feature(is_sorted)]
-
-mod span;
-mod error;
-mod interpreter;
-mod compiler;
-mod parser;
-mod utils;
-
-// TODO: Maybe will became a submodule somewhere.
-// mod thunk;
-
 use std::collections::{ HashMap, HashSet };
+use std::path::{ Path, PathBuf };
+use std::time::{ Duration, Instant };
 use rustyline::error::ReadlineError;
-use rustyline::Editor;
+use rustyline::completion::Completer;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{ Config, Context, Editor, Helper };
+
+use lambda_compiler::compiler::{ check_program, compile_program, compile_stmt, define_macro, StmtReturn };
+use lambda_compiler::error::{ LoadError, RuntimeError, USAGE_EXIT_CODE };
+use lambda_compiler::interpreter::{ self, DisplayStyle, EvalContext, Macro };
+use lambda_compiler::parser::error::Error;
+use lambda_compiler::shared::Rc;
+use lambda_compiler::span::Span;
+
+/// Name the result of the last successful evaluation is bound to.
+const LAST_RESULT: &str = "it";
+
+/// A command of the REPL, like `:trace on`.
+struct Command {
+    name: &'static str,
+    usage: &'static str,
+    description: &'static str,
+    run: fn(&mut Repl, &str) -> Result<String, String>,
+}
+
+/// Every command of the REPL. Both the dispatch in `Repl::run_line` and
+/// `:help` are driven by this table.
+const COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        usage: ":help",
+        description: "lists the commands",
+        run: |_, _| Ok(help()),
+    },
+    Command {
+        name: "set",
+        usage: ":set display ascii|unicode | print <chars>",
+        description: "changes how results are displayed, and how much of them",
+        run: Repl::run_set,
+    },
+    Command {
+        name: "trace",
+        usage: ":trace on|off",
+        description: "prints every reduction step to stderr",
+        run: Repl::run_trace,
+    },
+    Command {
+        name: "eval",
+        usage: ":eval <name>",
+        description: "shows the normal form of a macro without changing it",
+        run: Repl::run_eval_macro,
+    },
+    Command {
+        name: "time",
+        usage: ":time <expr>",
+        description: "evaluates an expression and shows how long it took",
+        run: Repl::run_time,
+    },
+    Command {
+        name: "full",
+        usage: ":full",
+        description: "shows the last result, even if it is too long",
+        run: Repl::run_full,
+    },
+    Command {
+        name: "macros",
+        usage: ":macros",
+        description: "lists the definitions of every macro",
+        run: |repl, _| Ok(interpreter::export_macros(&repl.macros).trim_end().to_owned()),
+    },
+];
+
+/// Shows each command with what it does.
+fn help() -> String {
+    let width = COMMANDS.iter().map(|cmd| cmd.usage.len()).max().unwrap_or(0);
+    COMMANDS.iter()
+        .map(|cmd| format!("{:<width$}  {}", cmd.usage, cmd.description, width = width))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// State kept by the REPL between lines.
+struct Repl {
+    literals: HashSet<Rc<String>>,
+    macros: HashMap<String, Rc<Macro>>,
+    display: DisplayStyle,
+    /// Whether each reduction step is printed to stderr during evaluation.
+    trace: bool,
+    /// Results longer than this many characters are cut short.
+    max_print: usize,
+}
+
+impl Repl {
+    const DEFAULT_MAX_PRINT: usize = 2000;
+
+    fn new() -> Repl {
+        Repl {
+            literals: HashSet::new(),
+            macros: HashMap::new(),
+            display: DisplayStyle::default(),
+            trace: false,
+            max_print: Repl::DEFAULT_MAX_PRINT,
+        }
+    }
+
+    /// Runs a `:set <option> <value>` command.
+    fn run_set(&mut self, args: &str) -> Result<String, String> {
+        match args.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["display", "ascii"]   => self.display = DisplayStyle::Ascii,
+            ["display", "unicode"] => self.display = DisplayStyle::Unicode,
+            ["print", chars] if chars.parse::<usize>().is_ok() => self.max_print = chars.parse().unwrap(),
+            _ => return Err(format!(
+                "unknown option `{}`, expected `display ascii|unicode` or `print <chars>`\n",
+                args.trim(),
+            )),
+        }
+        Ok(format!("Set {}", args.trim()))
+    }
+
+    /// Runs a `:trace on|off` command.
+    fn run_trace(&mut self, args: &str) -> Result<String, String> {
+        match args.trim() {
+            "on"  => self.trace = true,
+            "off" => self.trace = false,
+            _     => return Err(format!("unknown argument `{}`, expected `on` or `off`\n", args.trim())),
+        }
+        Ok(format!("Trace {}", args.trim()))
+    }
+
+    /// Runs a `:eval <name>` command, which shows the normal form of a macro
+    /// without changing it.
+    fn run_eval_macro(&mut self, name: &str) -> Result<String, String> {
+        let name = name.trim();
+        match self.macros.get(name) {
+            Some(mac) => {
+                let expr = mac.expr.clone();
+                self.eval(expr)
+            },
+            None      => Err(format!("undefined macro `{}`\n", name)),
+        }
+    }
+
+    /// Runs a `:time <expr>` command, which evaluates the expression like any
+    /// other line, and also shows how long it took.
+    fn run_time(&mut self, line: &str) -> Result<String, String> {
+        let mut expr = match compile_stmt(line, &mut self.literals, &mut self.macros) {
+            Ok(StmtReturn::Expr(expr)) => expr,
+            Ok(StmtReturn::Macro(_))   => return Err("expected an expression, not a definition\n".to_owned()),
+            Err(err)                   => return Err(render_compile_error(line, &err)),
+        };
+        match timed_eval(&mut expr) {
+            Ok(stats) => {
+                let output = self.show_result(expr);
+                Ok(format!("{}\nTime: {:?}, {} reductions", output, stats.duration, stats.reductions))
+            },
+            Err(err)  => Err(render_runtime_error(&err, &expr)),
+        }
+    }
+
+    /// Runs a `:full` command, which shows all of the last result.
+    fn run_full(&mut self, _args: &str) -> Result<String, String> {
+        match self.macros.get(LAST_RESULT) {
+            Some(mac) => Ok(mac.expr.display(self.display).to_string()),
+            None      => Err("nothing was evaluated yet\n".to_owned()),
+        }
+    }
 
-use crate::compiler::{ compile_stmt, StmtReturn };
+    /// Evaluates an expression and shows the result, which is then bound to
+    /// `it`.
+    fn eval(&mut self, mut expr: interpreter::Expr) -> Result<String, String> {
+        // Tracing only observes the evaluation, showing the subterm each step
+        // produced, so the result is the same either way.
+        let style = self.display;
+        let mut ctx = EvalContext::normalizing();
+        if self.trace {
+            let mut step = 0;
+            ctx = ctx.with_trace(move |expr| {
+                step += 1;
+                eprintln!("{:>4}: {}", step, expr.display(style));
+            });
+        }
+        let result = expr.eval_with(&mut ctx).map(|_| ());
+        match result {
+            Ok(())   => Ok(self.show_result(expr)),
+            Err(err) => Err(render_runtime_error(&err, &expr)),
+        }
+    }
+
+    // Shows an evaluated expression and binds it to `it`. Long results are cut
+    // short, telling how much was left out, and can be seen with `:full`.
+    fn show_result(&mut self, res: interpreter::Expr) -> String {
+        let styled = res.display(self.display);
+        let (shown, truncated) = styled.to_string_bounded(self.max_print);
+        let more = if truncated {
+            format!("… ({} more)", styled.char_count() - shown.chars().count())
+        } else {
+            String::new()
+        };
+        let output = format!("{}{}{}", shown, more, church_annotations(&res));
+        // Names that are not in scope don't compile in the REPL, so results
+        // are always closed.
+        define_macro(&mut self.macros, LAST_RESULT, res.pure_alpha_convert()).unwrap();
+        output
+    }
+
+    /// Compiles and runs a line of input. Returns what should be printed to
+    /// stdout if everything went fine, or to stderr otherwise.
+    fn run_line(&mut self, line: &str) -> Result<String, String> {
+        if let Some(command) = line.trim().strip_prefix(':') {
+            let (name, args) = command.split_once(' ').unwrap_or((command, ""));
+            return match COMMANDS.iter().find(|cmd| cmd.name == name) {
+                Some(cmd) => (cmd.run)(self, args),
+                None      => Err(format!("unknown command `:{}`, see `:help`\n", name)),
+            };
+        }
+
+        match compile_stmt(line, &mut self.literals, &mut self.macros) {
+            Ok(StmtReturn::Macro(name))    => Ok(format!("Defined macro {}", name)),
+            Ok(StmtReturn::Expr(expr))     => self.eval(expr),
+            Err(err) => Err(render_compile_error(line, &err)),
+        }
+    }
+}
+
+/// How long an evaluation took, shown by `:time`.
+struct EvalStats {
+    duration: Duration,
+    reductions: usize,
+}
+
+/// Evaluates the expression to normal form, like `Expr::eval_to_normal_form`,
+/// measuring the wall-clock time it takes and counting the beta-reductions.
+fn timed_eval(expr: &mut interpreter::Expr) -> Result<EvalStats, RuntimeError> {
+    let mut ctx = EvalContext::normalizing();
+    let start = Instant::now();
+    expr.eval_with(&mut ctx)?;
+    Ok(EvalStats { duration: start.elapsed(), reductions: ctx.reductions })
+}
+
+/// Shows a runtime error of the REPL, along with the expression as it was
+/// when evaluation failed. Ends with a newline, like compile errors, since it
+/// is printed right before the next prompt.
+fn render_runtime_error(err: &RuntimeError, expr: &interpreter::Expr) -> String {
+    format!("RuntimeError:\n\t{}\nError occurred at: {}\n", err, expr)
+}
+
+/// Shows each message of a compiler error under the line it refers to. Notes
+/// are marked with `-` instead of `^`, and indented under the error they
+/// belong to.
+fn render_compile_error(line: &str, err: &Error) -> String {
+    let mut output = String::from("Compiler Error:\n\n");
+    for e in err.messages.iter() {
+        if e.is_note() {
+            output.push_str(&format!("\t\t{}\n", line));
+            output.push_str(&format!("\t\t{} note: {}\n\n", underline(line, e.span, '-'), e.message));
+        } else {
+            output.push_str(&format!("\t{}\n", line));
+            output.push_str(&format!("\t{} {}\n\n", caret(line, e.span), e.message));
+        }
+    }
+    output
+}
+
+/// Shows each message of an error found in a file, with the line it refers
+/// to. Unlike `render_compile_error`, spans are relative to the whole source,
+/// which may have many lines.
+fn render_file_error(path: &Path, source: &str, err: &Error) -> String {
+    let mut output = String::new();
+    for e in err.messages.iter() {
+        let start = e.span.start.min(source.len());
+        let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[start..].find('\n').map_or(source.len(), |i| start + i);
+        let line = &source[line_start..line_end];
+        let span = Span::new(start - line_start, e.span.end.min(line_end) - line_start);
+        let line_number = source[..line_start].matches('\n').count() + 1;
+
+        let (kind, mark) = if e.is_note() { ("note", '-') } else { ("error", '^') };
+        output.push_str(&format!("{}:{}: {}: {}\n", path.display(), line_number, kind, e.message));
+        output.push_str(&format!("\t{}\n", line));
+        output.push_str(&format!("\t{}\n\n", underline(line, span, mark)));
+    }
+    output
+}
+
+/// Compiles and lints every file without evaluating anything, for `--check`.
+/// Returns the diagnostics to be printed, and the exit code, which is 0 if no
+/// errors were found.
+fn check_files<P: AsRef<Path>>(paths: &[P]) -> (String, u8) {
+    let mut output = String::new();
+    let mut exit_code = 0;
+    for path in paths.iter().map(AsRef::as_ref) {
+        let source = match std::fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(err)   => {
+                output.push_str(&format!("{}: error: {}\n", path.display(), err));
+                exit_code = exit_code.max(LoadError::from(err).exit_code());
+                continue;
+            },
+        };
+        for err in check_program(&source) {
+            output.push_str(&render_file_error(path, &source, &err));
+            exit_code = exit_code.max(err.exit_code());
+        }
+    }
+    (output, exit_code)
+}
+
+/// Compiles and evaluates a program, for `--eval`. Returns the result, or
+/// what went wrong along with the exit code for it.
+fn eval_file(path: &Path) -> Result<String, (String, u8)> {
+    let source = std::fs::read_to_string(path).map_err(|err| {
+        (format!("{}: error: {}\n", path.display(), err), LoadError::from(err).exit_code())
+    })?;
+    let mut executable = compile_program(&source)
+        .map_err(|err| (render_file_error(path, &source, &err), err.exit_code()))?;
+    executable.normalize = true;
+    match executable.eval() {
+        Ok(executable) => Ok(format!("{}{}", executable.expr, church_annotations(&executable.expr))),
+        Err(err)       => Err((format!("RuntimeError:\n\t{}\n", err), err.exit_code())),
+    }
+}
+
+fn caret(line: &str, span: Span) -> String {
+    underline(line, span, '^')
+}
+
+/// Builds the line that marks `span` under the source. Wide spans are
+/// underlined entirely, while empty ones get a single marker at their
+/// position. Spans are in bytes, but the marker is aligned by characters, so
+/// that it still lands in the right place after a `λ`.
+fn underline(line: &str, span: Span, mark: char) -> String {
+    let before = line.get(..span.start).unwrap_or(line);
+    // Tabs are kept so that they take up as much space as in the line above.
+    let spaces: String = before.chars()
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    let width = line.get(span.start..span.end)
+        .map_or(span.width(), |marked| marked.chars().count());
+
+    let width = if span.is_empty() { 1 } else { width };
+    format!("{}{}", spaces, mark.to_string().repeat(width))
+}
+
+/// Completes the macro name that ends at `pos`, if `pos` is at an identifier
+/// that is not inside a string literal. Returns where the completed text
+/// starts and the candidates, sorted.
+fn complete_macro_name<'a, I>(names: I, line: &str, pos: usize) -> (usize, Vec<String>)
+where
+    I: IntoIterator<Item = &'a String>,
+{
+    let before = &line[..pos];
+    let start = before
+        .char_indices()
+        .rev()
+        .take_while(|(_, c)| c.is_alphabetic())
+        .last()
+        .map_or(pos, |(i, _)| i);
+
+    // An odd number of unescaped quotes means we are inside a string.
+    let mut in_string = false;
+    let mut escaped = false;
+    for c in line[..start].chars() {
+        match c {
+            '\\' if in_string && !escaped => escaped = true,
+            '"' if !escaped            => in_string = !in_string,
+            _                          => escaped = false,
+        }
+    }
+    if in_string {
+        return (pos, Vec::new());
+    }
+
+    let prefix = &line[start..pos];
+    let mut candidates: Vec<String> = names.into_iter()
+        .filter(|name| name.starts_with(prefix))
+        .cloned()
+        .collect();
+    candidates.sort();
+    (start, candidates)
+}
+
+impl Completer for Repl {
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<String>)> {
+        Ok(complete_macro_name(self.macros.keys(), line, pos))
+    }
+}
+
+impl Hinter for Repl {
+    type Hint = String;
+}
+
+impl Highlighter for Repl {}
+
+impl Validator for Repl {}
+
+impl Helper for Repl {}
+
+/// Where the REPL history is kept and how much of it.
+#[derive(Debug, PartialEq)]
+struct HistoryConfig {
+    path: PathBuf,
+    max_size: usize,
+}
+
+impl HistoryConfig {
+    const DEFAULT_PATH: &'static str = ".lambda";
+    const DEFAULT_MAX_SIZE: usize = 1000;
+
+    /// Reads the configuration from the `LAMBDA_HISTORY` and
+    /// `LAMBDA_HISTORY_SIZE` environment variables, which can be overridden
+    /// by the `--history <path>` and `--history-size <n>` arguments.
+    fn from_env_and_args<I>(env: impl Fn(&str) -> Option<String>, args: I) -> Result<HistoryConfig, String>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut path = env("LAMBDA_HISTORY");
+        let mut max_size = env("LAMBDA_HISTORY_SIZE");
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let value = match arg.as_str() {
+                "--history"      => &mut path,
+                "--history-size" => &mut max_size,
+                _                => return Err(format!("unknown argument `{}`", arg)),
+            };
+            *value = Some(args.next().ok_or_else(|| format!("missing value for `{}`", arg))?);
+        }
+
+        let max_size = match max_size {
+            Some(size) => size.parse()
+                .map_err(|_| format!("invalid history size `{}`", size))?,
+            None       => HistoryConfig::DEFAULT_MAX_SIZE,
+        };
+
+        Ok(HistoryConfig {
+            path: path.unwrap_or_else(|| HistoryConfig::DEFAULT_PATH.to_owned()).into(),
+            max_size,
+        })
+    }
+}
+
+/// Removes consecutive repeated entries, keeping the first of each run.
+fn dedup_history<'a, I>(entries: I) -> Vec<String>
+where
+    I: IntoIterator<Item = &'a String>,
+{
+    let mut deduped: Vec<String> = Vec::new();
+    for entry in entries {
+        if deduped.last() != Some(entry) {
+            deduped.push(entry.clone());
+        }
+    }
+    deduped
+}
+
+/// Saves the history, deduplicated. Failing to save is not a reason to stop
+/// the REPL, so errors are only reported, and only the first time, since the
+/// history is saved after every line.
+fn save_history(rl: &mut Editor<Repl>, path: &Path, warned: &mut bool) {
+    let deduped = dedup_history(rl.history().iter());
+    let history = rl.history_mut();
+    history.clear();
+    for entry in deduped {
+        history.add(entry);
+    }
+
+    if let Err(err) = rl.save_history(path) {
+        if !*warned {
+            eprintln!("Warning: could not save history to {}: {}", path.display(), err);
+        }
+        *warned = true;
+    }
+}
 
 fn main() -> std::io::Result<()> {
-    let mut literals = HashSet::new();
-    let mut macros = HashMap::new();
+    let mut args = std::env::args().skip(1).peekable();
+    if args.peek().map(String::as_str) == Some("--check") {
+        let paths: Vec<String> = args.skip(1).collect();
+        if paths.is_empty() {
+            eprintln!("Usage: lambda-compiler --check <file>...");
+            std::process::exit(USAGE_EXIT_CODE.into());
+        }
+        let (output, exit_code) = check_files(&paths);
+        eprint!("{}", output);
+        std::process::exit(exit_code.into());
+    }
+    if args.peek().map(String::as_str) == Some("--eval") {
+        let path = match (args.nth(1), args.next()) {
+            (Some(path), None) => PathBuf::from(path),
+            _                  => {
+                eprintln!("Usage: lambda-compiler --eval <file>");
+                std::process::exit(USAGE_EXIT_CODE.into());
+            },
+        };
+        match eval_file(&path) {
+            Ok(output)              => println!("{}", output),
+            Err((output, exit_code)) => {
+                eprint!("{}", output);
+                std::process::exit(exit_code.into());
+            },
+        }
+        return Ok(());
+    }
+
+    let history_config = HistoryConfig::from_env_and_args(
+        |var| std::env::var(var).ok(),
+        std::env::args().skip(1),
+    );
+    let history_config = match history_config {
+        Ok(config) => config,
+        Err(err)   => {
+            eprintln!("Error: {}", err);
+            eprintln!("Usage: lambda-compiler [--history <path>] [--history-size <n>]");
+            eprintln!("       lambda-compiler --check <file>...");
+            eprintln!("       lambda-compiler --eval <file>");
+            std::process::exit(USAGE_EXIT_CODE.into());
+        },
+    };
 
-    let mut rl = Editor::<()>::new();
-    let _ = rl.load_history(".lambda");
+    let config = Config::builder()
+        .max_history_size(history_config.max_size)
+        .build();
+    let mut rl = Editor::<Repl>::with_config(config);
+    rl.set_helper(Some(Repl::new()));
+    let _ = rl.load_history(&history_config.path);
 
+    // Saved after every line, so that nothing is lost if the REPL is killed.
+    let mut warned = false;
     loop {
         let readline = rl.readline(">> ");
         match readline {
             Ok(line) => {
                 rl.add_history_entry(line.as_str());
+                save_history(&mut rl, &history_config.path, &mut warned);
                 if line == "exit" { break; }
-                match compile_stmt(line.as_str(), &mut literals, &mut macros) {
-                    Ok(StmtReturn::Macro(name))    => println!("Defined macro {}", name),
-                    Ok(StmtReturn::Expr(mut expr)) => {
-                        match expr.eval() {
-                            Ok(res)  => println!("{}", res),
-                            Err(err) => {
-                                eprintln!("RuntimeError:\n\t{}", err);
-                                eprintln!("Error occurred at: {}", expr);
-                            },
-                        }
-                    },
-                    Err(err) => {
-                        eprintln!("Compiler Error:\n");
-                        for e in err.messages {
-                            eprintln!("\t{}", line);
-                            let start = e.span.start;
-                            let spaces: String = std::iter::repeat(' ')
-                                .take(start)
-                                .collect();
-
-                            let up_arrow: String = std::iter::repeat('^')
-                                .take(e.span.width())
-                                .collect();
-
-                            eprintln!("\t{}{} {}", spaces, up_arrow, e.message);
-                            eprintln!();
-                        }
-                    },
+                let repl = rl.helper_mut().unwrap(); // Safe: set right before the loop.
+                match repl.run_line(line.as_str()) {
+                    Ok(output)  => println!("{}", output),
+                    Err(output) => eprint!("{}", output),
                 }
-                rl.save_history(".lambda").unwrap();
             },
             Err(ReadlineError::Interrupted) => {
                 println!("CTRL-C");
@@ -86,16 +557,23 @@ fn main() -> std::io::Result<()> {
             }
         }
     }
-    rl.save_history(".lambda").unwrap();
     Ok(())
 }
 
+/// Builds the annotations shown after an evaluated result, with every other
+/// way it can be interpreted, like Church numerals or booleans.
+fn church_annotations(expr: &interpreter::Expr) -> String {
+    expr.describe()
+        .into_iter()
+        .skip(1)
+        .map(|description| format!(" {}", description))
+        .collect()
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
-    use crate::compiler::StmtReturn;
-    use crate::compiler::{ compile_stmt, compile_program };
 
     macro_rules! assert_matches {
         ($expression:expr, $( $pattern:pat )|+ $( if $guard: expr )? => $resolve:expr, $($args:tt)*) => {
@@ -144,4 +622,261 @@ mod test {
         let mut expr = assert_matches!(compile_program(input), Ok(ex) => ex.expr,);
         let _ = assert_matches!(expr.eval(), Ok(_), err => "err is {:?}", err);
     }
+
+    #[test]
+    fn test_church_annotations() {
+        let mut literals = HashSet::new();
+        let mut macros = HashMap::new();
+        let mut eval = |input: &str| match compile_stmt(input, &mut literals, &mut macros) {
+            Ok(StmtReturn::Expr(mut expr)) => church_annotations(expr.eval().unwrap()),
+            _ => panic!("should be an expr"),
+        };
+
+        assert_eq!(eval("(\\a. \\b. a) (\\a. \\b. a)"), "");
+        assert_eq!(eval("\\a. \\b. a"), " #true");
+        assert_eq!(eval("\\a. \\b. b"), " #0 #false");
+        assert_eq!(eval("\\f. \\x. f (f x)"), " #2");
+        assert_eq!(eval("(\\a. a) \"a\" \"b\""), " \"ab\"");
+    }
+
+    #[test]
+    fn test_complete_macro_name() {
+        let names: Vec<String> = vec!["True".into(), "Tail".into(), "False".into()];
+        assert_eq!(
+            complete_macro_name(names.iter(), "And T", 5),
+            (4, vec!["Tail".to_owned(), "True".to_owned()])
+        );
+        assert_eq!(complete_macro_name(names.iter(), "(\\a. a) Fa", 10), (8, vec!["False".to_owned()]));
+        assert_eq!(complete_macro_name(names.iter(), "Tr x", 2), (0, vec!["True".to_owned()]));
+        assert_eq!(complete_macro_name(names.iter(), "Id \"Tr", 6), (6, Vec::new()));
+        assert_eq!(complete_macro_name(names.iter(), "Id \"a\\\"\" Tr", 11), (9, vec!["True".to_owned()]));
+    }
+
+    #[test]
+    fn test_last_result() {
+        let mut repl = Repl::new();
+        assert!(repl.run_line("it").unwrap_err().contains("undeclared"));
+
+        assert_eq!(repl.run_line("(\\a. \\b. a) \"x\"").unwrap(), "λa. x");
+        assert_eq!(repl.run_line("it \"y\"").unwrap(), "x");
+    }
+
+    #[test]
+    fn test_caret() {
+        assert_eq!(caret("abcdef", Span::new(3, 3)), "   ^");
+        assert_eq!(caret("abcdef", Span::new_start(3)), "   ^");
+        assert_eq!(caret("abcdef", Span::new(2, 5)), "  ^^^");
+        assert_eq!(caret("", Span::new(0, 0)), "^");
+        // `λ` takes two bytes, but only one column.
+        assert_eq!(caret("λa. @", Span::new_start(5)), "    ^");
+        assert_eq!(caret("\ta b", Span::new_start(3)), "\t  ^");
+        assert_eq!(caret("\"λé\" x", Span::new(0, 6)), "^^^^");
+    }
+
+    #[test]
+    fn test_caret_in_errors() {
+        let mut repl = Repl::new();
+        // A point error, at the `@`.
+        let err = repl.run_line("(@)").unwrap_err();
+        assert!(err.contains("\t(@)\n\t ^ expected an expression"), "{}", err);
+
+        // A range error, under the whole name.
+        let err = repl.run_line("foo").unwrap_err();
+        assert!(err.contains("\tfoo\n\t^^^ use of undeclared"), "{}", err);
+
+        let err = repl.run_line("λa. @").unwrap_err();
+        assert!(err.contains("\tλa. @\n\t    ^ "), "{}", err);
+    }
+
+    #[test]
+    fn test_render_note() {
+        let err = Error::new(Span::new(5, 6), "identifier is already in scope")
+            .with_note(Span::new(1, 2), "first defined here");
+        let output = render_compile_error("\\a. \\a. a", &err);
+        assert_eq!(output, concat!(
+            "Compiler Error:\n\n",
+            "\t\\a. \\a. a\n",
+            "\t     ^ identifier is already in scope\n\n",
+            "\t\t\\a. \\a. a\n",
+            "\t\t - note: first defined here\n\n",
+        ));
+
+        let mut repl = Repl::new();
+        let err = repl.run_line("\\a. \\a. a").unwrap_err();
+        assert_eq!(err, output);
+    }
+
+    #[test]
+    fn test_dedup_history() {
+        let entries: Vec<String> = vec!["Id", "Id", "K", "Id", "K", "K", "K"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert_eq!(dedup_history(&entries), vec!["Id", "K", "Id", "K"]);
+        assert!(dedup_history(&Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn test_history_config() {
+        let no_env = |_: &str| None;
+        let args = |args: &[&str]| args.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+        let config = HistoryConfig::from_env_and_args(no_env, args(&[])).unwrap();
+        assert_eq!(config, HistoryConfig { path: ".lambda".into(), max_size: 1000 });
+
+        let env = |var: &str| match var {
+            "LAMBDA_HISTORY"      => Some("env_history".to_owned()),
+            "LAMBDA_HISTORY_SIZE" => Some("10".to_owned()),
+            _                     => None,
+        };
+        let config = HistoryConfig::from_env_and_args(env, args(&["--history-size", "20"])).unwrap();
+        assert_eq!(config, HistoryConfig { path: "env_history".into(), max_size: 20 });
+
+        assert!(HistoryConfig::from_env_and_args(no_env, args(&["--history"])).is_err());
+        assert!(HistoryConfig::from_env_and_args(no_env, args(&["--history-size", "many"])).is_err());
+        assert!(HistoryConfig::from_env_and_args(no_env, args(&["--verbose"])).is_err());
+    }
+
+    #[test]
+    fn test_trace() {
+        let mut repl = Repl::new();
+        assert!(repl.run_line(":trace on").is_ok());
+        assert!(repl.trace);
+        assert_eq!(repl.run_line("(\\a. a) (\\b. b)").unwrap(), "λa. a");
+        assert!(repl.run_line(":trace off").is_ok());
+        assert!(!repl.trace);
+        assert!(repl.run_line(":trace maybe").is_err());
+    }
+
+    #[test]
+    fn test_eval_macro() {
+        let mut repl = Repl::new();
+        assert!(repl.run_line("M = (\\a. a) \"x\"").is_ok());
+        assert_eq!(repl.run_line(":eval M").unwrap(), "x");
+
+        // The stored macro is left as it was.
+        match &repl.macros["M"].expr {
+            interpreter::Expr::Appl { .. } => (),
+            other                          => panic!("`M` was changed to `{}`", other),
+        }
+        assert!(repl.run_line(":eval Nope").is_err());
+    }
+
+    #[test]
+    fn test_timed_eval() {
+        let mut expr = compile_program("(\\a. \\b. a) (\\x. x) (\\y. y)").unwrap().expr;
+        let stats = timed_eval(&mut expr).unwrap();
+        assert_eq!(stats.reductions, 2);
+        assert!(stats.duration < Duration::from_secs(10));
+        assert_eq!(expr.to_string(), compile_program("\\x. x").unwrap().expr.to_string());
+
+        let mut repl = Repl::new();
+        let output = repl.run_line(":time (\\a. a) \"x\"").unwrap();
+        let mut lines = output.lines();
+        assert_eq!(lines.next(), Some("x"));
+        assert!(lines.next().unwrap().ends_with(", 1 reductions"), "{}", output);
+        assert!(matches!(&repl.macros[LAST_RESULT].expr, interpreter::Expr::Literal(lit) if lit.as_str() == "x"));
+        assert!(repl.run_line(":time Id = \\a. a").is_err());
+    }
+
+    #[test]
+    fn test_runtime_error_ends_line() {
+        let mut repl = Repl::new();
+        let err = repl.run_line("(\\a. a a) (\\a. a a)").unwrap_err();
+        assert!(err.starts_with("RuntimeError:\n") && err.ends_with('\n'), "{:?}", err);
+        assert!(err.contains("Error occurred at: "), "{:?}", err);
+        assert_eq!(repl.run_line(":time (\\a. a a) (\\a. a a)").unwrap_err(), err);
+    }
+
+    #[test]
+    fn test_list_macros() {
+        let mut repl = Repl::new();
+        assert_eq!(repl.run_line(":macros").unwrap(), "");
+        for line in ["K = \\a. \\b. a", "Id = \\a. a", "B = K Id"].iter() {
+            repl.run_line(line).unwrap();
+        }
+        assert_eq!(repl.run_line(":macros").unwrap(), "K = \\a. \\b. a\nId = \\a. a\nB = K Id");
+    }
+
+    #[test]
+    fn test_semicolon_separated_line() {
+        let mut repl = Repl::new();
+        let output = repl.run_line("K = \\a.\\b.a ; Id = \\a. a ; K Id K").unwrap();
+        assert_eq!(output, "Id");
+        assert!(repl.macros.contains_key("K") && repl.macros.contains_key("Id"));
+    }
+
+    #[test]
+    fn test_help_covers_commands() {
+        let mut repl = Repl::new();
+        let help = repl.run_line(":help").unwrap();
+        assert_eq!(help.lines().count(), COMMANDS.len());
+        for cmd in COMMANDS {
+            assert!(help.contains(cmd.usage), "{} is missing from the help", cmd.name);
+            assert!(cmd.usage.starts_with(&format!(":{}", cmd.name)));
+            let result = repl.run_line(&format!(":{}", cmd.name));
+            assert!(!matches!(&result, Err(err) if err.starts_with("unknown command")), "{:?}", result);
+        }
+        assert!(repl.run_line(":nope").unwrap_err().starts_with("unknown command `:nope`"));
+    }
+
+    #[test]
+    fn test_check_files() {
+        let path = std::env::temp_dir().join(format!("lambda-check-{}.lambda", std::process::id()));
+        std::fs::write(&path, "Id = \\a. a\nK = \\a. \\b. Missing a\n").unwrap();
+        let (output, exit_code) = check_files(&[&path]);
+        std::fs::write(&path, "Id = \\a. a\nId\n").unwrap();
+        let (clean, clean_exit_code) = check_files(&[&path]);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(exit_code, 2);
+        assert!(output.contains(":2: error: "), "{}", output);
+        assert!(output.contains("Missing"), "{}", output);
+        assert_eq!(clean_exit_code, 0, "{}", clean);
+        assert!(clean.is_empty());
+
+        let (_, exit_code) = check_files(&[&path]);
+        assert_eq!(exit_code, 1);
+    }
+
+    #[test]
+    fn test_eval_file() {
+        let path = std::env::temp_dir().join(format!("lambda-eval-{}.lambda", std::process::id()));
+        std::fs::write(&path, "Two = \\f. \\x. f (f x)\nTwo Two").unwrap();
+        let result = eval_file(&path);
+        std::fs::write(&path, "Id = \\a. a\nNope").unwrap();
+        let compile_err = eval_file(&path);
+        std::fs::write(&path, "(\\a. a a) (\\a. a a)").unwrap();
+        let runtime_err = eval_file(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(result.unwrap().contains("4"));
+        assert_eq!(compile_err.unwrap_err().1, 2);
+        assert_eq!(runtime_err.unwrap_err().1, 3);
+        assert_eq!(eval_file(&path).unwrap_err().1, 1);
+    }
+
+    #[test]
+    fn test_set_display() {
+        let mut repl = Repl::new();
+        assert_eq!(repl.run_line("\\a. a").unwrap(), "λa. a");
+        assert!(repl.run_line(":set display ascii").is_ok());
+        assert_eq!(repl.run_line("\\a. a").unwrap(), "\\a. a");
+        assert!(repl.run_line(":set display unicode").is_ok());
+        assert_eq!(repl.run_line("\\a. a").unwrap(), "λa. a");
+        assert!(repl.run_line(":set display fancy").is_err());
+    }
+
+    #[test]
+    fn test_max_print() {
+        let mut repl = Repl::new();
+        assert!(repl.run_line(":full").is_err());
+        assert!(repl.run_line(":set print 6").is_ok());
+        assert!(repl.run_line(":set print many").is_err());
+
+        let output = repl.run_line("\\f. \\x. f (f x)").unwrap();
+        assert!(output.starts_with("λa. λb… (9 more)"), "{}", output);
+        assert_eq!(repl.run_line(":full").unwrap(), "λa. λb. a (a b)");
+        assert_eq!(repl.run_line("\\a. a").unwrap(), "λa. a");
+    }
 }
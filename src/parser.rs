@@ -27,21 +27,17 @@ impl<T: Spanned> Spanned for Box<T> {
 
 impl<T: Spanned> Spanned for [T] {
     fn span(&self) -> Span {
-        self.iter()
-            .map(|el| el.span())
-            .fold_first(|a, b| a.merge(b))
-            .unwrap()
+        Span::merge_all(self.iter().map(|el| el.span()))
+            .expect("can't get the span of an empty slice")
     }
 }
 
 impl<Fst: Spanned, Snd: Spanned> Spanned for Vec<(Fst, Snd)> {
     fn span(&self) -> Span {
-        let (left, right) = self.iter()
-            .map(|(fst, snd)| (fst.span(), snd.span()))
-            .fold_first(|(pfst, psnd), (fst, snd)| (pfst.merge(fst), psnd.merge(snd)))
-            .unwrap();
-
-        left.merge(right)
+        let spans = self.iter()
+            .flat_map(|(fst, snd)| std::iter::once(fst.span()).chain(std::iter::once(snd.span())));
+        Span::merge_all(spans)
+            .expect("can't get the span of an empty list")
     }
 }
 
@@ -73,82 +69,234 @@ impl<T: Parser> Parser for Box<T> {
 
 impl Parser for Program {
     fn parse<'tok>(input: &ParseStream<'tok>) -> Result<Program> {
-        let s = input.get_remaining();
-        let mut stmts = Vec::new();
+        let stmts = StmtStream::new(input.scope.start, input.get_remaining())
+            .collect::<Result<_>>()?;
 
-        let mut start = input.scope.start;
-        for line in s.lines() {
-            let end = start + line.len();
+        Ok(Program { stmts })
+    }
+}
 
-            if line.len() > 0 && !line.chars().all(|c| c.is_whitespace()) {
-                let content = ParseStream::new(Span::new(start, end), line);
-                stmts.push(content.parse()?);
-            }
-            start += line.len() + 1;
+/// Splits a line into the statements separated by `;` in it, along with the
+/// byte offset of each one. Semicolons inside string literals, parenthesis or
+/// comments don't separate statements.
+pub fn split_stmts(s: &str) -> Vec<(usize, &str)> {
+    let bytes = s.as_bytes();
+    let mut stmts = Vec::new();
+    let mut start = 0;
+    let mut depth = 0;
+    let mut in_str = false;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' if in_str               => i += 1,
+            b'"'                           => in_str = !in_str,
+            _ if in_str                    => (),
+            b'-' if s[i..].starts_with("--") => {
+                i = s[i..].find('\n').map_or(s.len(), |end| i + end);
+                continue;
+            },
+            b'('                           => depth += 1,
+            b')'                           => depth -= 1,
+            b';' if depth == 0             => {
+                stmts.push((start, &s[start..i]));
+                start = i + 1;
+            },
+            _                              => (),
         }
+        i += 1;
+    }
+    stmts.push((start, &s[start..]));
+    stmts
+}
 
-        Ok(Program { stmts })
+/// Lazily parses the statements of a program. Unlike `Program::parse`,
+/// nothing past the current statement is looked at, so the caller may stop at
+/// the first error or evaluate definitions as they come.
+///
+/// Each statement starts on a new line, and continues on the lines that
+/// follow it as long as they are indented further than its first line, like
+///
+/// ```text
+/// K = \a.
+///     \b. a
+/// ```
+///
+/// Lines with only whitespace or comments don't start a statement.
+pub struct StmtStream<'a> {
+    rest: &'a str,
+    start: usize,
+}
+
+impl<'a> StmtStream<'a> {
+    /// Creates a stream over `s`, where `start` is the byte offset of `s` in
+    /// the original source, used to compute the spans of each statement.
+    pub fn new(start: usize, s: &'a str) -> StmtStream<'a> {
+        StmtStream { rest: s, start }
+    }
+
+    // Length of the first line of `s`, without the line break.
+    fn line_len(s: &str) -> usize {
+        s.find('\n').unwrap_or(s.len())
+    }
+
+    fn indentation(line: &str) -> usize {
+        line.len() - line.trim_start().len()
+    }
+}
+
+impl<'a> Iterator for StmtStream<'a> {
+    type Item = Result<Stmt>;
+
+    fn next(&mut self) -> Option<Result<Stmt>> {
+        while !self.rest.is_empty() {
+            let mut len = Self::line_len(self.rest);
+            let is_blank = skip_blank(&self.rest[..len]).is_empty();
+
+            if !is_blank {
+                // Lines right after that are indented further are part of the
+                // same statement.
+                let indentation = Self::indentation(&self.rest[..len]);
+                while len < self.rest.len() {
+                    let next = &self.rest[len + 1..];
+                    let next_len = Self::line_len(next);
+                    let line = &next[..next_len];
+                    if Self::indentation(line) <= indentation || line.trim().is_empty() {
+                        break;
+                    }
+                    len += 1 + next_len;
+                }
+            }
+
+            let start = self.start;
+            let content = &self.rest[..len];
+            self.rest = self.rest.get(len + 1..).unwrap_or("");
+            self.start += len + 1;
+
+            if !is_blank {
+                let content = ParseStream::new(Span::new(start, start + len), content);
+                return Some(content.parse());
+            }
+        }
+        None
     }
 }
 
 impl Parser for Stmt {
     fn parse<'tok>(input: &ParseStream<'tok>) -> Result<Stmt> {
-        let result = input.parse()
-            .map(|macro_def| Stmt::Macro(macro_def))
-            .or_else(|_| {
-                let cache = input.cache_borrow_mut()?;
-                drop(cache);
-                Ok(Stmt::Expr(input.parse()?))
-            });
-
-        if result.is_ok() && input.get_remaining().len() > 0 {
-            Err(Error::new(input.curr_span(), "unexpected trailing input"))
+        // Once there is a `Name =`, it can only be a definition, so the errors
+        // are about its body rather than about a failed expression. The same
+        // goes for `infixl 6`.
+        let checkpoint = input.checkpoint();
+        let is_infix = input.parse::<tokens::Var>()
+            .is_ok_and(|var| INFIX_KEYWORDS.contains(&var.name.as_str()))
+            && input.parse::<tokens::Number>().is_ok();
+        input.rewind(checkpoint);
+        let is_definition = !is_infix
+            && parse_priv(input).is_ok()
+            && input.parse::<tokens::Var>().is_ok()
+            && input.parse::<tokens::Equal>().is_ok();
+        input.rewind(checkpoint);
+
+        let result = if is_infix {
+            input.parse().map(Stmt::Infix)
+        } else if is_definition {
+            input.parse().map(Stmt::Macro)
+        } else {
+            input.parse().map(Stmt::Expr)
+        };
+
+        if result.is_ok() && !input.is_empty() {
+            Err(trailing_input_error(input))
         } else {
             result
         }
     }
 }
 
+/// Keywords that start the declaration of an operator.
+pub const INFIX_KEYWORDS: &[&str] = &["infixl", "infixr", "infix"];
+
+impl Parser for Infix {
+    fn parse<'tok>(input: &ParseStream<'tok>) -> Result<Infix> {
+        Ok(Infix {
+            keyword: input.parse()?,
+            precedence: input.parse()?,
+            op: input.expect("expected an operator after the precedence")?,
+            eq_token: input.expect("expected `=` after the operator")?,
+            value: input.expect("expected an expression after `=`")?,
+        })
+    }
+}
+
 impl Parser for Macro {
     fn parse<'tok>(input: &ParseStream<'tok>) -> Result<Macro> {
         Ok(Macro {
+            priv_token: parse_priv(input)?,
             name: input.parse()?,
             eq_token: input.parse()?,
-            value: input.parse()?,
+            value: input.expect("expected an expression after `=`")?,
         })
     }
 }
 
+/// Keyword that makes a definition private.
+pub const PRIV_KEYWORD: &str = "priv";
+
+// Parses the `priv` before a definition, if there is one. It is only taken as
+// the keyword when a name comes after it, so `priv = ...` is a definition of
+// `priv`, which the compiler rejects since it is a reserved word.
+fn parse_priv(input: &ParseStream) -> Result<Option<tokens::Var>> {
+    let checkpoint = input.checkpoint();
+    match input.parse::<tokens::Var>() {
+        Ok(var) if var.name == PRIV_KEYWORD && input.parse::<tokens::Var>().is_ok() => {
+            input.rewind(checkpoint);
+            input.parse().map(Some)
+        },
+        _ => {
+            input.rewind(checkpoint);
+            Ok(None)
+        },
+    }
+}
+
+/// Builds an error that underlines everything that is left in the stream and
+/// shows it in the message.
+fn trailing_input_error(input: &ParseStream) -> Error {
+    input.skip_whitespace();
+    let remaining = input.get_remaining().trim_end();
+    let span = input.remaining_span().with_width(remaining.len());
+    Error::new(span, format!("unexpected trailing input `{}`", remaining))
+}
+
 impl Parser for Expr {
     fn parse<'tok>(input: &ParseStream<'tok>) -> Result<Expr> {
         input.skip_whitespace();
 
-        Ok({
-            input.parse()
-                .map(|lamb| Expr::Lambda(lamb))
-                .or_else(|err| {
-                    input.parse()
-                        .map(|appl| Expr::Appl(appl))
-                        .map_err(|appl_err| err.or(appl_err))
-                })
-                .or_else(|err| {
-                    input.parse()
-                        .and_then(|close| {
-                            input.skip_whitespace();
-
-                            // At this point, it is expected to parse the entire input
-                            if let None = input.get() {
-                                Ok(Expr::Close(close))
-                            } else {
-                                Err(Error::new(input.curr_span().start(), "unexpected trailing input"))
-                            }
-                        })
-                        .map_err(|close_err| err.or(close_err))
-                })
-                .map_err(|err| {
-                    Error::new(err.cover_span(), "expected an expression")
-                })
-        }?)
+        // Only a lambda starts with `\`, so there is no need to try the
+        // other branches.
+        if input.lookahead::<tokens::Lambda>() {
+            return input.parse().map(Expr::Lambda);
+        }
+
+        input.parse()
+            .map(Expr::Appl)
+            .or_else(|err| {
+                input.expect("expected an expression")
+                    .and_then(|close| {
+                        input.skip_whitespace();
+
+                        // At this point, it is expected to parse the entire input
+                        if input.get().is_none() {
+                            Ok(Expr::Close(close))
+                        } else {
+                            Err(trailing_input_error(input))
+                        }
+                    })
+                    // If part of the expression could be parsed, the error
+                    // from further ahead is more informative.
+                    .map_err(|close_err| err.or(close_err))
+            })
     }
 }
 
@@ -156,8 +304,10 @@ impl Parser for Lambda {
     fn parse<'tok>(input: &ParseStream<'tok>) -> Result<Lambda> {
         Ok(Lambda {
             lambda_token: input.parse()?,
-            var: input.parse()?,
-            dot_token: input.parse()?,
+            params: input.parse()?,
+            // After the parameters it can only be a lambda, so a missing dot
+            // is reported as such.
+            dot_token: input.expect("expected `.` after the lambda parameters")?,
             expr: input.parse()?,
         })
     }
@@ -172,8 +322,9 @@ impl Parser for Appl {
         };
 
         while !input.is_empty() {
+            // The group is just what has been parsed so far.
+            let hi = root.rhs.span().end;
             let rhs = input.parse()?;
-            let hi = input.curr_span().start;
             let group = tokens::Group::new(Span::new(lo, hi), tokens::Delimiter::None);
             root = Appl {
                 lhs: Close::Grouping(box Expr::Appl(root), group),
@@ -208,52 +359,40 @@ fn parse_appl_with_lhs<'tok>(input: &ParseStream<'tok>, lhs: Close, mut log: usi
 
 impl Parser for Close {
     fn parse<'tok>(input: &ParseStream<'tok>) -> Result<Close> {
-        Ok({
-            input
-                .parse_parethesized()
-                .map(|(expr, group)| {
-                    Close::Grouping(expr, tokens::Group::new(group, tokens::Delimiter::Paren))
-                })
-                .or_else(|err| {
-                    input.parse()
-                        .map(|var| Close::Var(var))
-                        .map_err(|var_err| {
-                            err.or(var_err)
-                            /*
-                            error.extend(var_err.messages);
-                            Err(error)
-                            */
-                        })
-                })
-                .or_else(|err| {
-                    input.parse()
-                        .map(|lit| Close::Literal(lit))
-                        .map_err(|lit_err| {
-                            err.or(lit_err)
-                            /*
-                            error.extend(var_err.messages);
-                            Err(error)
-                            */
-                        })
-                })
-        }?)
+        input
+            .parse_parethesized()
+            .map(|(expr, group)| {
+                Close::Grouping(expr, tokens::Group::new(group, tokens::Delimiter::Paren))
+            })
+            .or_else(|err| input.parse().map(Close::Var).map_err(|var_err| err.or(var_err)))
+            .or_else(|err| input.parse().map(Close::Literal).map_err(|lit_err| err.or(lit_err)))
+            .or_else(|err| input.parse().map(Close::Number).map_err(|num_err| err.or(num_err)))
+            .or_else(|err| input.parse().map(Close::Operator).map_err(|op_err| err.or(op_err)))
     }
 }
 
 impl Parser for VarList {
     fn parse<'tok>(input: &ParseStream<'tok>) -> Result<Self> {
-        input.skip_whitespace();
+        let (mut vars, commas) = input.parse_separated::<tokens::Var, tokens::Comma>()?;
+        let mixed = "cannot mix comma and space separated parameters";
 
-        let mut list = Vec::new();
-        while let Ok(tuple) = input.parse_once(|s| Ok((s.parse()?, s.parse()?))) {
-            list.push(tuple);
+        if commas.is_empty() {
+            while let Ok(var) = input.parse::<tokens::Var>() {
+                vars.push(var);
+            }
+        } else if let Ok(var) = input.parse::<tokens::Var>() {
+            return Err(Error::new(var.span, mixed));
         }
 
-        if list.len() > 0 {
-            Ok(VarList { list })
-        } else {
-            Err(Error::new(input.curr_span().start(), "expected variable list"))
+        // Whatever comma is left wasn't followed by a parameter.
+        if let Ok(comma) = input.parse::<tokens::Comma>() {
+            if commas.is_empty() && vars.len() > 1 {
+                return Err(Error::new(comma.span, mixed));
+            }
+            return Err(Error::new(comma.span, "expected a parameter after `,`"));
         }
+
+        Ok(VarList { vars, commas })
     }
 }
 
@@ -269,6 +408,15 @@ mod test {
         assert!(stream.is_empty(), "remaining: {}", stream.get_remaining());
     }
 
+    #[test]
+    fn test_split_stmts() {
+        assert_eq!(split_stmts("K = \\a.\\b.a ; K x y"), vec![(0, "K = \\a.\\b.a "), (13, " K x y")]);
+        assert_eq!(split_stmts("\\a. a"), vec![(0, "\\a. a")]);
+        assert_eq!(split_stmts("(a; b) \"c; \\\"d\""), vec![(0, "(a; b) \"c; \\\"d\"")]);
+        assert_eq!(split_stmts("a -- b; c\n; d"), vec![(0, "a -- b; c\n"), (11, " d")]);
+        assert_eq!(split_stmts("a;"), vec![(0, "a"), (2, "")]);
+    }
+
     #[test]
     fn test_parse_stmt() {
         let stream = ParseStream::from("\\a. a a");
@@ -276,6 +424,16 @@ mod test {
         assert!(stream.is_empty(), "remaining: {}", stream.get_remaining());
     }
 
+    #[test]
+    fn test_definition_with_bad_body() {
+        let err = Stmt::parse(&ParseStream::from("Foo = )")).unwrap_err();
+        assert_eq!(err.messages[0].message, "expected an expression after `=`");
+        assert_eq!(err.messages[0].span, Span::new(6, 7));
+
+        // Without the `=` it is still parsed as an expression.
+        assert!(matches!(Stmt::parse(&ParseStream::from("Foo Bar")), Ok(Stmt::Expr(_))));
+    }
+
     #[test]
     fn test_literal_parser() {
         let stream = ParseStream::from("\\a. a a");
@@ -283,6 +441,91 @@ mod test {
         assert!(stream.is_empty(), "remaining: {}", stream.get_remaining());
     }
 
+    #[test]
+    fn test_number() {
+        let parse = |s| tokens::Number::parse(&ParseStream::from(s)).map(|num| num.value);
+        assert_eq!(parse("42").unwrap(), 42);
+        assert_eq!(parse("0xA").unwrap(), 10);
+        assert_eq!(parse("0x_ff").unwrap(), 255);
+        assert_eq!(parse("1_000").unwrap(), 1000);
+        assert_eq!(parse("1_000_000").unwrap(), 1_000_000);
+        assert!(parse("0x").is_err());
+        assert!(parse("0xG").is_err());
+        assert!(parse("_1").is_err());
+        assert!(parse("18446744073709551616").is_err());
+
+        let stream = ParseStream::from("f 0x10 3");
+        assert!(Expr::parse(&stream).is_ok());
+        assert!(stream.is_empty(), "remaining: {}", stream.get_remaining());
+    }
+
+    #[test]
+    fn test_operators() {
+        let parse = |s| tokens::Operator::parse(&ParseStream::from(s)).map(|op| op.symbol);
+        assert_eq!(parse("+").unwrap(), "+");
+        assert_eq!(parse(" <*> a").unwrap(), "<*>");
+        assert_eq!(parse("+-- a comment").unwrap(), "+");
+        assert!(parse("= a").is_err());
+        assert!(parse("@").is_err());
+
+        match Stmt::parse(&ParseStream::from("1 + 2 * 3")).unwrap() {
+            Stmt::Expr(Expr::Appl(appl)) => assert!(matches!(appl.rhs, Close::Number(_))),
+            _ => panic!("expected an application"),
+        }
+
+        match Stmt::parse(&ParseStream::from("infixl 6 + = Plus")).unwrap() {
+            Stmt::Infix(infix) => {
+                assert_eq!(infix.keyword.name, "infixl");
+                assert_eq!(infix.precedence.value, 6);
+                assert_eq!(infix.op.symbol, "+");
+            },
+            _ => panic!("expected an operator declaration"),
+        }
+
+        let err = Stmt::parse(&ParseStream::from("infixl 6 Plus = Plus")).unwrap_err();
+        assert_eq!(err.messages[0].message, "expected an operator after the precedence");
+
+        // Without a precedence, it would be a definition, but the keywords
+        // are reserved.
+        let Err(err) = crate::compiler::compile_program("infixl = \\a. a\n\\a. a") else {
+            panic!("`infixl` should be reserved");
+        };
+        assert!(err.messages[0].message.contains("reserved keyword"), "{}", err);
+    }
+
+    #[test]
+    fn test_missing_dot() {
+        let err = Stmt::parse(&ParseStream::from("\\a a")).unwrap_err();
+        assert_eq!(err.messages[0].message, "expected `.` after the lambda parameters");
+        assert_eq!(err.messages[0].span, Span::new(4, 5));
+
+        // In a definition, it comes under the error about the body.
+        let err = Stmt::parse(&ParseStream::from("Id = \\a (a)")).unwrap_err();
+        let dot = err.messages.iter()
+            .find(|msg| msg.message == "expected `.` after the lambda parameters")
+            .expect("the missing dot is reported");
+        assert_eq!(dot.span, Span::new(8, 9));
+    }
+
+    #[test]
+    fn test_priv_macro() {
+        match Stmt::parse(&ParseStream::from("priv Id = \\a. a")) {
+            Ok(Stmt::Macro(mac)) => {
+                assert!(mac.is_private());
+                assert_eq!(mac.name.name, "Id");
+                assert_eq!(mac.span(), Span::new(0, 15));
+            },
+            other => panic!("expected a definition, got {:?}", other),
+        }
+
+        // `priv` is only the keyword when a name follows it.
+        match Stmt::parse(&ParseStream::from("priv = \\a. a")) {
+            Ok(Stmt::Macro(mac)) => assert!(!mac.is_private() && mac.name.name == "priv"),
+            other => panic!("expected a definition, got {:?}", other),
+        }
+        assert!(matches!(Stmt::parse(&ParseStream::from("priv Id")), Ok(Stmt::Expr(_))));
+    }
+
     #[test]
     fn test_paren() {
         let stream = ParseStream::from("(\\a. a a)");
@@ -290,6 +533,154 @@ mod test {
         assert!(stream.is_empty(), "remaining: {}", stream.get_remaining());
     }
 
+    #[test]
+    fn test_comma_params() {
+        let stream = ParseStream::from("\\a, b, c. a");
+        let lambda = Lambda::parse(&stream).unwrap();
+        assert_eq!(lambda.params.vars.len(), 3);
+        assert_eq!(lambda.params.commas.len(), 2);
+        assert!(stream.is_empty(), "remaining: {}", stream.get_remaining());
+    }
+
+    #[test]
+    fn test_space_params() {
+        let stream = ParseStream::from("\\a b c. a");
+        let lambda = Lambda::parse(&stream).unwrap();
+        assert_eq!(lambda.params.vars.len(), 3);
+        assert!(lambda.params.commas.is_empty());
+        assert!(stream.is_empty(), "remaining: {}", stream.get_remaining());
+    }
+
+    #[test]
+    fn test_mixed_params() {
+        for input in ["\\a, b c. a", "\\a b, c. a"].iter() {
+            let stream = ParseStream::from(*input);
+            let err = Lambda::parse(&stream).unwrap_err();
+            assert!(err.messages[0].message.contains("cannot mix"), "{}", err);
+            assert!(Stmt::parse(&ParseStream::from(*input)).is_err());
+        }
+
+        let err = Lambda::parse(&ParseStream::from("\\a, b,. a")).unwrap_err();
+        assert_eq!(err.messages[0].message, "expected a parameter after `,`");
+        assert_eq!(err.messages[0].span, Span::new(5, 6));
+    }
+
+    #[test]
+    fn test_trailing_input() {
+        let stream = ParseStream::from("\\a. a )");
+        let err = Stmt::parse(&stream).unwrap_err();
+        assert_eq!(err.messages[0].span, Span::new(6, 7));
+        assert!(err.messages[0].message.contains("`)`"), "{}", err);
+
+        let stream = ParseStream::from("a ))");
+        let err = Stmt::parse(&stream).unwrap_err();
+        assert_eq!(err.messages[0].span, Span::new(2, 4));
+
+        // Trailing blanks are left out of the span.
+        let source = "\\a. a ) b  \n";
+        let stream = ParseStream::from(source);
+        let err = Stmt::parse(&stream).unwrap_err();
+        assert_eq!(err.messages[0].span, Span::new(6, 9));
+        assert_eq!(&source[err.messages[0].span.into_range()], ") b");
+    }
+
+    #[test]
+    fn test_stmt_stream() {
+        let source = "Id = \\a. a\n\nK = \\a b. a\nId K\n";
+        let mut stream = StmtStream::new(0, source);
+
+        match stream.next() {
+            Some(Ok(Stmt::Macro(mac))) => {
+                assert_eq!(mac.name.name, "Id");
+                assert_eq!(mac.span(), Span::new(0, 10));
+            },
+            _ => panic!("expected the definition of `Id`"),
+        }
+
+        let rest: Vec<_> = stream.collect::<Result<_>>().unwrap();
+        assert_eq!(rest.len(), 2);
+        assert_eq!(rest[1].span(), Span::new(24, 28));
+    }
+
+    #[test]
+    fn test_multiline_stmt() {
+        let source = "Id = \\a. a\nK = \\a.\n    \\b. a\n\tK\nK Id\n";
+        let stmts: Vec<_> = StmtStream::new(0, source).collect::<Result<_>>().unwrap();
+        assert_eq!(stmts.len(), 3);
+        match &stmts[1] {
+            Stmt::Macro(mac) => {
+                assert_eq!(mac.name.name, "K");
+                assert_eq!(mac.span(), Span::new(11, 31));
+            },
+            _ => panic!("expected the definition of `K`"),
+        }
+
+        // A blank line ends the statement, even if the next one is indented.
+        let source = "K = \\a.\n\n    \\b. a";
+        assert!(StmtStream::new(0, source).next().unwrap().is_err());
+
+        let program = Program::parse(&ParseStream::from("F =\n  \\a. a\nF F")).unwrap();
+        assert_eq!(program.stmts.len(), 2);
+
+        // Windows line endings split and continue statements the same way.
+        let source = "Id = \\a. a\r\nK = \\a.\r\n    \\b. a\r\n\r\nK Id\r\n";
+        let stmts: Vec<_> = StmtStream::new(0, source).collect::<Result<_>>().unwrap();
+        assert_eq!(stmts.len(), 3);
+        assert_eq!(stmts[0].span(), Span::new(0, 10));
+        let Stmt::Macro(mac) = &stmts[1] else { panic!("expected the definition of `K`") };
+        assert_eq!(mac.span(), Span::new(12, 30));
+        assert_eq!(stmts[2].span(), Span::new(34, 38));
+    }
+
+    #[test]
+    fn test_stmt_stream_stops_on_error() {
+        let mut stream = StmtStream::new(0, "Id = \\a. a\n)\nK = \\a b. a");
+        assert!(stream.next().unwrap().is_ok());
+        assert!(stream.next().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_child_stream_error_span() {
+        let stream = ParseStream::from("(@)");
+        let err = Stmt::parse(&stream).unwrap_err();
+        for msg in &err.messages {
+            assert_eq!(msg.span.start, 1, "{}", err);
+        }
+
+        let stream = ParseStream::from("a (b (\\c. @))");
+        let err = Stmt::parse(&stream).unwrap_err();
+        assert!(err.messages.iter().any(|msg| msg.span.start == 10), "{}", err);
+    }
+
+    #[test]
+    fn test_expect() {
+        let stream = ParseStream::from("  )");
+        let err = stream.expect::<tokens::Var>("expected a name").unwrap_err();
+        assert_eq!(err.messages.len(), 2);
+        assert_eq!(err.messages[0].message, "expected a name");
+        assert_eq!(err.messages[0].span, Span::new(2, 3));
+        assert_eq!(err.messages[1].message, "Expected an identifier");
+
+        let stream = ParseStream::from("  abc");
+        assert_eq!(stream.expect::<tokens::Var>("expected a name").unwrap().name, "abc");
+
+        // An expression that can't even start keeps the reasons of the
+        // alternatives it tried after the context.
+        let err = Expr::parse(&ParseStream::from(" )")).unwrap_err();
+        assert_eq!(err.messages[0].message, "expected an expression");
+        assert_eq!(err.messages[0].span, Span::new(1, 2));
+        assert!(err.messages.len() > 1);
+
+        // Errors about a missing token point at the first byte after the
+        // blanks, and at the end of the input when there is nothing left.
+        let stream = ParseStream::from("a )");
+        stream.advance();
+        let err = tokens::Equal::parse(&stream).unwrap_err();
+        assert_eq!(err.messages[0].span, Span::new(2, 3));
+        let err = Stmt::parse(&ParseStream::from("Id =")).unwrap_err();
+        assert_eq!(err.cover_span().end, 5);
+    }
+
     #[test]
     fn test_var() {
         let stream = ParseStream::from("a");
@@ -297,10 +688,34 @@ mod test {
         assert!(stream.is_empty(), "remaining: {}", stream.get_remaining());
     }
 
+    #[test]
+    fn test_uncached_parse_matches_cached() {
+        let inputs = [
+            "\\a. a a",
+            "(\\a. a) (\\a. a) \"hello\"",
+            "Id = \\a. a",
+            "(\\a. \\b. a) x y",
+        ];
+
+        for input in inputs.iter() {
+            let cached = ParseStream::from(*input);
+            let uncached = ParseStream::new_uncached(Span::from(*input), input);
+            let cached_stmt = Stmt::parse(&cached).unwrap();
+            let uncached_stmt = Stmt::parse(&uncached).unwrap();
+            assert_eq!(format!("{:?}", cached_stmt), format!("{:?}", uncached_stmt));
+            assert!(uncached.is_empty(), "remaining: {}", uncached.get_remaining());
+        }
+    }
+
     #[test]
     fn test_literal() {
         let stream = ParseStream::from("\"hello world\"");
         assert!(tokens::Literal::parse(&stream).is_ok());
         assert!(stream.is_empty(), "remaining: {}", stream.get_remaining());
+
+        // `λ` and `é` take two bytes each.
+        let lit = tokens::Literal::parse(&ParseStream::from("\"λé\" x")).unwrap();
+        assert_eq!(lit.content, "λé");
+        assert_eq!(lit.span, Span::new(0, 6));
     }
 }
@@ -2,14 +2,6 @@ use crate::span::Span;
 use super::Spanned;
 use super::tokens;
 
-fn merge_spans(spans: &[Span]) -> Span {
-    let mut merged = spans[0];
-    for span in spans[1..].iter().cloned() {
-        merged = merged.merge(span);
-    }
-    merged
-}
-
 macro_rules! replace_ident { ($t:tt, $i:ident) => { $i } }
 
 // Takes struct definitions and 
@@ -29,7 +21,7 @@ macro_rules! default_ast_impls {
 
         impl Spanned for $name {
             fn span(&self) -> Span {
-                merge_spans(&[$(self.$field.span()),+])
+                Span::merge_all(vec![$(self.$field.span()),+]).unwrap()
             }
         }
 
@@ -47,7 +39,7 @@ macro_rules! default_ast_impls {
             fn span(&self) -> Span {
                 match self {
                     $($name::$variant(arg1 $(, replace_ident!($ty2, arg2))?) =>
-                        merge_spans(&[arg1.span() $(, replace_ident!($ty2, arg2).span())?]),
+                        Span::merge_all(vec![arg1.span() $(, replace_ident!($ty2, arg2).span())?]).unwrap(),
                     )+
                 }
             }
@@ -66,11 +58,15 @@ default_ast_impls! {
 
     pub enum Stmt {
         Macro(Macro),
+        Infix(Infix),
         Expr(Expr),
     }
 
-    pub struct Macro {
-        pub name: tokens::Var,
+    // Declares an operator, like `infixl 6 + = Plus`.
+    pub struct Infix {
+        pub keyword: tokens::Var,
+        pub precedence: tokens::Number,
+        pub op: tokens::Operator,
         pub eq_token: tokens::Equal,
         pub value: Expr,
     }
@@ -83,7 +79,7 @@ default_ast_impls! {
 
     pub struct Lambda {
         pub lambda_token: tokens::Lambda,
-        pub var: tokens::Var,
+        pub params: VarList,
         pub dot_token: tokens::Dot,
         pub expr: Box<Expr>,
     }
@@ -97,10 +93,55 @@ default_ast_impls! {
         Grouping(Box<Expr>, tokens::Group),
         Var(tokens::Var),
         Literal(tokens::Literal),
+        Number(tokens::Number),
+        Operator(tokens::Operator),
+    }
+}
+
+/// The definition of a macro, like `Id = \\a. a`. Starting it with `priv`
+/// keeps the macro out of what the file exports.
+#[derive(Debug, Clone)]
+pub struct Macro {
+    pub priv_token: Option<tokens::Var>,
+    pub name: tokens::Var,
+    pub eq_token: tokens::Equal,
+    pub value: Expr,
+}
+
+impl Macro {
+    pub fn new(priv_token: Option<tokens::Var>, name: tokens::Var, eq_token: tokens::Equal, value: Expr) -> Macro {
+        Macro { priv_token, name, eq_token, value }
+    }
+
+    pub fn is_private(&self) -> bool {
+        self.priv_token.is_some()
     }
+}
+
+impl Spanned for Macro {
+    fn span(&self) -> Span {
+        let start = self.priv_token.as_ref().map_or(self.name.span, |token| token.span);
+        start.merge(self.value.span())
+    }
+}
+
+/// The parameters of a lambda. They may be separated either by spaces, like
+/// `\a b c. a`, or by commas, like `\a, b, c. a`, but never by both.
+#[derive(Debug, Clone)]
+pub struct VarList {
+    pub vars: Vec<tokens::Var>,
+    pub commas: Vec<tokens::Comma>,
+}
+
+impl VarList {
+    pub fn new(vars: Vec<tokens::Var>, commas: Vec<tokens::Comma>) -> VarList {
+        VarList { vars, commas }
+    }
+}
 
-    pub struct VarList {
-        pub list: Vec<(tokens::Var, tokens::Dot)>,
+impl Spanned for VarList {
+    fn span(&self) -> Span {
+        self.vars.span()
     }
 }
 
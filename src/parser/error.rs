@@ -4,6 +4,7 @@ use std::fmt;
 use crate::span::Span;
 
 #[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct Error {
     pub messages: Vec<ErrorMessage>,
 }
@@ -18,10 +19,8 @@ impl Error {
     }
 
     pub fn cover_span(&self) -> Span {
-        self.messages.iter()
-            .map(|msg| msg.span)
-            .fold_first(|a, b| a.merge(b))
-            .unwrap()
+        Span::merge_all(self.messages.iter().map(|msg| msg.span))
+            .expect("an error has at least one message")
     }
 
     pub fn or(self, other: Error) -> Error {
@@ -35,6 +34,19 @@ impl Error {
     pub fn push<T: ToString>(&mut self, span: Span, val: T) {
         self.messages.push(ErrorMessage::new(span, val.to_string()));
     }
+
+    /// The exit code of a process that stopped because the program didn't
+    /// compile. See `RuntimeError::exit_code` for the other ones.
+    pub fn exit_code(&self) -> u8 {
+        2
+    }
+
+    /// Adds a secondary annotation to the error, pointing at something that
+    /// helps explain it, like where a name was first defined.
+    pub fn with_note<T: ToString>(mut self, span: Span, val: T) -> Error {
+        self.messages.push(ErrorMessage { kind: MessageKind::Note, ..ErrorMessage::new(span, val) });
+        self
+    }
 }
 
 impl fmt::Display for Error {
@@ -53,6 +65,8 @@ impl fmt::Debug for Error {
     }
 }
 
+impl std::error::Error for Error {}
+
 impl Extend<ErrorMessage> for Error {
     #[inline]
     fn extend<T>(&mut self, iter: T)
@@ -63,20 +77,57 @@ impl Extend<ErrorMessage> for Error {
     }
 }
 
+/// Whether a message is an error on its own, or just a note about another one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize), serde(rename_all = "lowercase"))]
+pub enum MessageKind {
+    Error,
+    Note,
+}
+
 #[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct ErrorMessage {
     pub span: Span,
     pub message: String,
+    pub kind: MessageKind,
 }
 
 impl ErrorMessage {
     fn new<T: ToString>(span: Span, val: T) -> ErrorMessage {
-        ErrorMessage { span, message: val.to_string() }
+        ErrorMessage { span, message: val.to_string(), kind: MessageKind::Error }
+    }
+
+    pub fn is_note(&self) -> bool {
+        self.kind == MessageKind::Note
     }
 }
 
 impl std::fmt::Display for ErrorMessage {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_note() {
+            write!(f, "note: ")?;
+        }
         write!(f, "{} at bytes {} to {}", self.message, self.span.start, self.span.end)
     }
 }
+
+#[cfg(all(test, feature = "serde"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_serialize() {
+        let err = Error::new(Span::new(5, 6), "identifier is already in scope")
+            .with_note(Span::new(1, 2), "first defined here");
+        let json = serde_json::to_value(&err).unwrap();
+
+        let messages = json["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0]["span"], serde_json::json!({ "start": 5, "end": 6 }));
+        assert_eq!(messages[0]["kind"], "error");
+        assert_eq!(messages[1]["span"], serde_json::json!({ "start": 1, "end": 2 }));
+        assert_eq!(messages[1]["message"], "first defined here");
+        assert_eq!(messages[1]["kind"], "note");
+    }
+}
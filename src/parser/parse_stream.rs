@@ -10,14 +10,29 @@ use super::{ Parser, Result };
 use super::error::Error;
 use super::parser_cache::{ ParserCache, ParsedType };
 
+/// How deep parentheses may be nested. Parsing recurses into each level, so
+/// this keeps deeply nested input from overflowing the stack.
+pub const MAX_NESTING_DEPTH: usize = 64;
+
+/// A position in a `ParseStream` that it can go back to, with
+/// `ParseStream::rewind`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Checkpoint {
+    position: usize,
+    original: *const u8,
+}
+
 #[derive(Clone)]
 pub struct ParseStream<'a> {
     pub scope: Span,
     curr_span: Cell<Span>,
     cache: Rc<RefCell<ParserCache>>,
+    cached: bool,
     original: &'a str,
     remaining: Cell<&'a str>,
     error: RefCell<Option<Error>>,
+    // How many enclosing delimiters this stream is inside of.
+    depth: usize,
 }
 
 impl<'a> ParseStream<'a> {
@@ -26,42 +41,53 @@ impl<'a> ParseStream<'a> {
             scope,
             curr_span: Cell::new(scope),
             cache: Rc::new(RefCell::new(ParserCache::new())),
+            cached: true,
             original: s,
             remaining: Cell::new(s),
             error: RefCell::new(None),
+            depth: 0,
         }
     }
 
-    pub fn skip_whitespace(&self) {
-        while let Some(c) = self.get() {
-            if c.is_whitespace() {
-                self.advance();
-            } else {
-                break;
-            }
+    /// Creates a stream that never memoizes parse results. Useful for
+    /// benchmarking and for inputs where backtracking is known not to occur,
+    /// since it avoids the borrow and clone-from-cache overhead.
+    pub fn new_uncached(scope: Span, s: &'a str) -> ParseStream<'a> {
+        ParseStream {
+            cached: false,
+            ..ParseStream::new(scope, s)
         }
     }
 
+    /// Skips whitespace and comments. A comment starts with `--` and goes
+    /// until the end of the line.
+    pub fn skip_whitespace(&self) {
+        let remaining = self.get_remaining();
+        let skipped = &remaining[..remaining.len() - skip_blank(remaining).len()];
+        self.advance_by(skipped.chars().count());
+    }
+
     // Advances the stream until the next valid token, that means that it will
     // automatically get rid of any and all whitespaces.
     pub fn advance(&self) {
         let mut span = self.curr_span();
 
         let mut it = self.remaining.get().chars();
-        if it.next().is_some() {
-            span.start += 1;
+        if let Some(c) = it.next() {
+            span.start += c.len_utf8();
         }
         self.remaining.set(it.as_str());
         self.curr_span.set(span);
     }
 
+    /// Advances the stream by `n` characters. The span still moves in bytes.
     pub fn advance_by(&self, n: usize) {
         let mut span = self.curr_span();
 
         let mut it = self.remaining.get().chars();
         for _ in 0..n {
-            if it.next().is_some() {
-                span.start += 1;
+            if let Some(c) = it.next() {
+                span.start += c.len_utf8();
             }
         }
         self.remaining.set(it.as_str());
@@ -79,9 +105,31 @@ impl<'a> ParseStream<'a> {
         self.remaining.set(&self.original[i - self.scope.start..]);
     }
 
+    /// Saves the current position of the stream, so that it is possible to
+    /// try parsing something and backtrack if it fails.
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            position: self.curr_span().start,
+            original: self.original.as_ptr(),
+        }
+    }
+
+    /// Goes back to a position saved with `ParseStream::checkpoint`. Values
+    /// parsed after the checkpoint are kept in cache, so parsing them again is
+    /// cheap.
+    pub fn rewind(&self, checkpoint: Checkpoint) {
+        assert!(
+            checkpoint.original == self.original.as_ptr(),
+            "checkpoint is from a different stream"
+        );
+        self.goto(checkpoint.position);
+    }
+
     pub fn goto_remaining(&self, n: usize) {
+        // Spans are in the coordinates of the whole source, while `original`
+        // may be just a slice of it, in child streams.
         let len = self.original.len();
-        self.curr_span.set(Span::new(len - n, len));
+        self.curr_span.set(Span::new(self.scope.end - n, self.scope.end));
         self.remaining.set(&self.original[len - n..]);
     }
 
@@ -92,6 +140,9 @@ impl<'a> ParseStream<'a> {
         self.get()
     }
 
+    /// Gets the line and column where `span` starts, counted from the start
+    /// of the text of this stream. `span` is in source coordinates, like
+    /// every span produced by the stream.
     pub fn get_line_column_number(&self, span: Span) -> (usize, usize) {
         for (i, line) in self.line_spans().into_iter().enumerate() {
             if line.contains(span.start()) {
@@ -102,7 +153,7 @@ impl<'a> ParseStream<'a> {
     }
 
     fn line_spans(&self) -> Vec<Span> {
-        let mut span = Span::new(0, self.original.len());
+        let mut span = self.scope;
         let mut lines = Vec::new();
 
         for line in self.original.lines() {
@@ -135,6 +186,10 @@ impl<'a> ParseStream<'a> {
         T: 'static + Clone,
         F: FnMut(&ParseStream) -> Result<T>,
     {
+        if !self.cached {
+            return parse_fn(self);
+        }
+
         let type_id = TypeId::of::<T>();
         let remaining_len = self.curr_span().start;
         let mut cache_ref = self.cache_borrow_mut()?;
@@ -186,6 +241,14 @@ impl<'a> ParseStream<'a> {
         Ok(val)
     }
 
+    /// Checks if a `T` can be parsed from the current position, without moving
+    /// the stream. Nothing is cached, so it is meant for small `T`s, like a
+    /// token that decides between grammar branches.
+    pub fn lookahead<T: Parser>(&self) -> bool {
+        let fork = ParseStream { cached: false, ..self.fork() };
+        T::parse(&fork).is_ok()
+    }
+
     /// Tries to parse a value T from the stream. If it can, it will be returned
     /// with `Ok`, if it can't it may still be able to return `Ok`, but then the
     /// `ParseStream` will have some errors in its `error` field. If there is
@@ -194,6 +257,33 @@ impl<'a> ParseStream<'a> {
         self.parse_with(T::try_parse)
     }
 
+    /// Parses a value T from the stream. If it fails, the error starts with
+    /// `context`, pointing at the current position, followed by the messages
+    /// of the underlying error.
+    pub fn expect<T: Parser>(&self, context: &str) -> Result<T> {
+        self.skip_whitespace();
+        let span = self.curr_span().start();
+        self.parse().map_err(|err| {
+            let mut wrapped = Error::new(span, context);
+            wrapped.extend(err.messages);
+            wrapped
+        })
+    }
+
+    /// Parses one or more `T`s separated by `S`s, stopping before a separator
+    /// that isn't followed by another `T`. The separators are returned as
+    /// well, since they have spans of their own.
+    pub fn parse_separated<T: Parser, S: Parser>(&self) -> Result<(Vec<T>, Vec<S>)> {
+        let mut items = vec![self.parse::<T>()?];
+        let mut separators = Vec::new();
+
+        while let Ok((sep, item)) = self.parse_once(|input| Ok((input.parse::<S>()?, input.parse::<T>()?))) {
+            separators.push(sep);
+            items.push(item);
+        }
+        Ok((items, separators))
+    }
+
     pub fn parse_once<T, F>(&self, f: F) -> Result<T>
     where
         F: Fn(&ParseStream<'a>) -> Result<T>,
@@ -206,6 +296,14 @@ impl<'a> ParseStream<'a> {
 
     pub fn parse_enclosed<T: Parser>(&self, open: &str, close: &str) -> Result<(T, Span)> {
         let (stream, span) = parse_enclosed(self, open, close)?;
+        if stream.depth > MAX_NESTING_DEPTH {
+            // Points inside the delimiters, so that this error is preferred
+            // over the ones of alternatives that stop at the delimiter.
+            return Err(Error::new(
+                stream.curr_span().start(),
+                format!("delimiters are nested too deeply, the maximum is {}", MAX_NESTING_DEPTH),
+            ));
+        }
         let val = stream.parse_with(T::parse)?;
         self.goto(span.end);
 
@@ -247,9 +345,11 @@ impl<'a> ParseStream<'a> {
             scope,
             curr_span: Cell::new(scope),
             cache: Rc::clone(&self.cache),
+            cached: self.cached,
             original: s,
             remaining: Cell::new(s),
             error: RefCell::new(None),
+            depth: self.depth + 1,
         }
     }
 
@@ -279,15 +379,20 @@ impl<'a> ParseStream<'a> {
 
     #[inline]
     pub fn is_empty(&self) -> bool {
-        self.get_remaining()
-            .chars()
-            .all(char::is_whitespace)
+        skip_blank(self.get_remaining()).is_empty()
     }
 
     #[inline]
     pub fn curr_span(&self) -> Span {
         self.curr_span.get()
     }
+
+    /// Gets the span of everything that was not consumed yet, from the
+    /// current position to the end of the scope.
+    #[inline]
+    pub fn remaining_span(&self) -> Span {
+        Span::new(self.curr_span().start, self.scope.end)
+    }
 }
 
 impl<'a> From<&'a str> for ParseStream<'a> {
@@ -331,26 +436,31 @@ fn parse_enclosed<'tok>(input: &ParseStream<'tok>, open: &str, close: &str) -> R
     assert!(open != "\"" && close != "\"");
     input.skip_whitespace();
 
-    let mut remaining = input.get_remaining();
     let start = input.curr_span().start;
 
-    match remaining.get(..1) {
-        Some(fst) if fst == open => (),
-        _         => return Err(Error::new(input.curr_span().start(), "expected a '('")),
+    // Delimiters are ASCII, so the scan can go byte by byte without ever
+    // splitting a multi-byte character.
+    let bytes = input.get_remaining().as_bytes();
+    let (open, close) = (open.as_bytes()[0], close.as_bytes()[0]);
+    if bytes.first() != Some(&open) {
+        return Err(Error::new(input.curr_span().start(), "expected a '('"));
     }
 
+    // Skips the first '('
     let mut count = 1;
-    let mut prev = &remaining[..1];
+    let mut prev = open;
     let mut str_start = None;
 
-    // Skips the first '('
-    remaining = &remaining[1..];
-
     let mut unclosed = vec![start];
 
-    while unclosed.len() > 0 && remaining.len() > 0 {
-        let c = &remaining[..1];
-        remaining = &remaining[1..];
+    while !unclosed.is_empty() && count < bytes.len() {
+        let c = bytes[count];
+
+        // Delimiters inside comments don't count.
+        if str_start.is_none() && bytes[count..].starts_with(b"--") {
+            count += bytes[count..].iter().position(|&b| b == b'\n').unwrap_or(bytes.len() - count);
+            continue;
+        }
 
         if str_start.is_none() {
             if c == open {
@@ -360,8 +470,8 @@ fn parse_enclosed<'tok>(input: &ParseStream<'tok>, open: &str, close: &str) -> R
             }
         }
 
-        if c == "\"" {
-            if str_start.is_some() && prev != "\\" {
+        if c == b'"' {
+            if str_start.is_some() && prev != b'\\' {
                 str_start = None;
             } else {
                 str_start = Some(start + count);
@@ -388,3 +498,159 @@ fn parse_enclosed<'tok>(input: &ParseStream<'tok>, open: &str, close: &str) -> R
 
     Ok((stream, (start..start + count).into()))
 }
+
+/// Strips the whitespace and comments at the start of `s`.
+pub fn skip_blank(mut s: &str) -> &str {
+    loop {
+        s = s.trim_start();
+        if !s.starts_with("--") {
+            return s;
+        }
+        s = s.find('\n').map_or("", |end| &s[end..]);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_checkpoint_rewind() {
+        use crate::parser::ast::Expr;
+        use crate::parser::Spanned;
+
+        let stream = ParseStream::from("a (\\b. b) c");
+        stream.advance_by(1);
+        let checkpoint = stream.checkpoint();
+
+        let first = stream.parse::<Expr>().unwrap();
+        assert!(stream.is_empty());
+
+        stream.rewind(checkpoint);
+        assert_eq!(stream.curr_span().start, 1);
+        assert_eq!(stream.get_remaining(), " (\\b. b) c");
+
+        let second = stream.parse::<Expr>().unwrap();
+        assert_eq!(first.span(), second.span());
+        assert_eq!(format!("{:?}", first), format!("{:?}", second));
+        assert!(stream.is_empty());
+    }
+
+    #[test]
+    fn test_skip_comments() {
+        let stream = ParseStream::from("  -- a comment\n-- another (\n  a -- )");
+        stream.skip_whitespace();
+        assert_eq!(stream.get_remaining(), "a -- )");
+        stream.advance();
+        assert!(stream.is_empty());
+
+        let stream = ParseStream::from("(a -- )\n b)");
+        let (child, span) = parse_enclosed(&stream, "(", ")").unwrap();
+        assert_eq!(span, Span::new(0, 11));
+        assert_eq!(child.get_remaining(), "a -- )\n b");
+    }
+
+    #[test]
+    fn test_lookahead() {
+        use crate::parser::ast::{ Expr, Lambda };
+
+        let stream = ParseStream::from("\\a. a");
+        assert!(stream.lookahead::<Lambda>());
+        assert_eq!(stream.curr_span().start, 0);
+        assert!(stream.parse::<Expr>().is_ok());
+        assert!(stream.is_empty());
+
+        let stream = ParseStream::from("x");
+        assert!(!stream.lookahead::<Lambda>());
+        assert!(stream.lookahead::<Expr>());
+        assert_eq!(stream.get_remaining(), "x");
+        assert!(stream.cache_borrow_mut().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_remaining_span() {
+        let stream = ParseStream::from("λa. a b");
+        assert_eq!(stream.remaining_span(), Span::new(0, 8));
+        stream.advance_by(4);
+        assert_eq!(stream.remaining_span(), Span::new(5, 8));
+
+        let stream = ParseStream::from("(a b) c");
+        let (child, _) = parse_enclosed(&stream, "(", ")").unwrap();
+        child.advance();
+        assert_eq!(child.remaining_span(), Span::new(2, 4));
+    }
+
+    #[test]
+    fn test_advance_counts_bytes() {
+        let stream = ParseStream::from("λa. a");
+        stream.advance();
+        assert_eq!(stream.curr_span().start, 2);
+        assert_eq!(stream.get(), Some('a'));
+
+        stream.goto(0);
+        stream.advance_by(2);
+        assert_eq!(stream.curr_span().start, 3);
+        assert_eq!(stream.get_remaining(), ". a");
+    }
+
+    #[test]
+    fn test_nesting_limit() {
+        use crate::parser::ast::Expr;
+
+        let nested = |n: usize| format!("{}x{}", "f (".repeat(n), ")".repeat(n));
+
+        let source = nested(MAX_NESTING_DEPTH);
+        assert!(Expr::parse(&ParseStream::from(source.as_str())).is_ok());
+
+        let source = nested(MAX_NESTING_DEPTH + 1);
+        let err = Expr::parse(&ParseStream::from(source.as_str())).unwrap_err();
+        assert!(err.messages.iter().any(|msg| msg.message.contains("nested too deeply")), "{}", err);
+
+        // Far past the limit, parsing still fails cleanly.
+        let source = nested(10_000);
+        assert!(Expr::parse(&ParseStream::from(source.as_str())).is_err());
+    }
+
+    #[test]
+    fn test_enclosed_multibyte() {
+        let stream = ParseStream::from("(λa. \"é\") b");
+        let (child, span) = parse_enclosed(&stream, "(", ")").unwrap();
+        assert_eq!(span, Span::new(0, 11));
+        assert_eq!(child.get_remaining(), "λa. \"é\"");
+    }
+
+    #[test]
+    fn test_parse_separated() {
+        use crate::parser::tokens::{ Comma, Var };
+
+        let stream = ParseStream::from("a, b ,c d");
+        let (vars, commas) = stream.parse_separated::<Var, Comma>().unwrap();
+        let names: Vec<_> = vars.iter().map(|var| var.name.as_str()).collect();
+        assert_eq!(names, vec!["a", "b", "c"]);
+        assert_eq!(commas.len(), 2);
+        assert_eq!(stream.get_remaining(), " d");
+
+        // A separator without an item after it is left in the stream.
+        let stream = ParseStream::from("a, b, .");
+        let (vars, _) = stream.parse_separated::<Var, Comma>().unwrap();
+        assert_eq!(vars.len(), 2);
+        assert_eq!(stream.get_remaining(), ", .");
+
+        assert!(ParseStream::from(", a").parse_separated::<Var, Comma>().is_err());
+    }
+
+    #[test]
+    fn test_child_stream_spans() {
+        let stream = ParseStream::from("a (b c)");
+        stream.advance_by(2);
+        let (child, span) = parse_enclosed(&stream, "(", ")").unwrap();
+        assert_eq!(span, Span::new(2, 7));
+        assert_eq!(child.curr_span(), Span::new(3, 6));
+
+        child.goto_remaining(1);
+        assert_eq!(child.get(), Some('c'));
+        assert_eq!(child.curr_span(), Span::new(5, 6));
+        // Line and column are relative to the text of the child stream.
+        assert_eq!(child.get_line_column_number(child.curr_span()), (0, 2));
+    }
+}
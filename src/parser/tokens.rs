@@ -40,8 +40,8 @@ macro_rules! define_token_rules {
                 let span = input.curr_span();
                 let patts: &[&str] = &[$($patt),+];
                 if let Some(patt) = patts.iter().find(|&p| input.starts_with(p)) {
-                    input.advance_by(patt.len());
-                    Ok($tok::new(span.with_width(1)))
+                    input.advance_by(patt.chars().count());
+                    Ok($tok::new(span.with_width(patt.len())))
                 } else {
                     Err(Error::new(span.start(), format!("Error, expected token {}", stringify!($tok))))
                 }
@@ -84,6 +84,7 @@ define_token_rules! {
     '\n'*      => pub struct Ln,
     ' '*       => pub struct Space,
     "."        => pub struct Dot,
+    ","        => pub struct Comma,
     "="        => pub struct Equal,
     "("        => pub struct LParen,
     ")"        => pub struct RParen,
@@ -122,6 +123,26 @@ pub struct Literal {
     pub content: String,
 }
 
+/// A natural number, written in decimal, like `1_000`, or in hexadecimal,
+/// like `0xA`. Underscores may be used to separate the digits.
+#[derive(Debug, Clone)]
+pub struct Number {
+    pub span: Span,
+    pub value: u64,
+}
+
+/// A symbolic operator, like `+` or `<*>`, which refers to a macro declared
+/// with `infixl`, `infixr` or `infix`.
+#[derive(Debug, Clone)]
+pub struct Operator {
+    pub span: Span,
+    pub symbol: String,
+}
+
+/// Characters operators are made of. `=`, `.`, `\\`, `$` and `:` already
+/// mean something else, and `--` starts a comment.
+pub const OPERATOR_CHARS: &str = "+-*/<>&|^~!?%";
+
 impl Group {
     pub fn new(span: Span, delim: Delimiter) -> Group {
         Group { span, delim }
@@ -142,6 +163,12 @@ impl Var {
     pub fn new(span: Span, name: String) -> Var {
         Var { span, name }
     }
+
+    /// Checks if this is the `_` wildcard, which binds a variable that can't
+    /// be referenced.
+    pub fn is_wildcard(&self) -> bool {
+        self.name == "_"
+    }
 }
 
 impl Spanned for Var {
@@ -150,6 +177,93 @@ impl Spanned for Var {
     }
 }
 
+impl Number {
+    pub fn new(span: Span, value: u64) -> Number {
+        Number { span, value }
+    }
+}
+
+impl Spanned for Number {
+    fn span(&self) -> Span {
+        self.span
+    }
+}
+
+impl Parser for Number {
+    fn parse<'tok>(input: &ParseStream<'tok>) -> Result<Number> {
+        input.skip_whitespace();
+        let span = input.curr_span();
+
+        if !input.get().is_some_and(|c| c.is_ascii_digit()) {
+            return Err(Error::new(span.start(), "Expected a number"));
+        }
+
+        let mut count = 0;
+        let radix = if input.starts_with("0x") {
+            input.advance_by(2);
+            count += 2;
+            16
+        } else {
+            10
+        };
+
+        let mut value: u64 = 0;
+        let mut digits = 0;
+        while let Some(c) = input.get() {
+            if let Some(digit) = c.to_digit(radix) {
+                value = value.checked_mul(radix as u64)
+                    .and_then(|value| value.checked_add(digit as u64))
+                    .ok_or_else(|| Error::new(span.start(), "number is too big"))?;
+                digits += 1;
+            } else if c != '_' {
+                break;
+            }
+            input.advance();
+            count += 1;
+        }
+
+        if digits == 0 {
+            Err(Error::new(span.with_width(count), "expected hexadecimal digits after `0x`"))
+        } else {
+            Ok(Number::new(span.with_width(count), value))
+        }
+    }
+}
+
+impl Operator {
+    pub fn new(span: Span, symbol: String) -> Operator {
+        Operator { span, symbol }
+    }
+}
+
+impl Spanned for Operator {
+    fn span(&self) -> Span {
+        self.span
+    }
+}
+
+impl Parser for Operator {
+    fn parse<'tok>(input: &ParseStream<'tok>) -> Result<Operator> {
+        input.skip_whitespace();
+        let span = input.curr_span();
+        let mut symbol = String::new();
+
+        while let Some(c) = input.get() {
+            if !OPERATOR_CHARS.contains(c) || input.starts_with("--") {
+                break;
+            }
+            symbol.push(c);
+            input.advance();
+        }
+
+        if symbol.is_empty() {
+            Err(Error::new(span.start(), "expected an operator"))
+        } else {
+            Ok(Operator::new(span.with_width(symbol.len()), symbol))
+        }
+    }
+}
+
 impl Literal {
     pub fn new(span: Span, content: String) -> Literal {
         Literal { span, content }
@@ -239,7 +353,13 @@ impl Parser for Var {
             }
             input.advance();
         }
-        if content.len() == 0 {
+        if content.is_empty() && input.starts_with('_') {
+            input.advance();
+            if input.get().is_some_and(|c| c.is_alphabetic()) {
+                return Err(Error::new(span.with_width(1), "identifiers can't start with `_`"));
+            }
+            Ok(Var::new(span.with_width(1), "_".to_string()))
+        } else if content.is_empty() {
             Err(Error::new(span.start(), "Expected an identifier"))
         } else {
             Ok(Var::new(span.with_width(content.len()), content))
@@ -270,9 +390,8 @@ impl Parser for Var {
 impl Parser for Literal {
     fn parse<'tok>(input: &ParseStream<'tok>) -> Result<Literal> {
         input.skip_whitespace();
-        let span = input.curr_span();
+        let start = input.curr_span().start;
         let mut content = String::new();
-        let mut count = 0;
 
         Quote::parse(input)?;
         while let Some(c) = input.get() {
@@ -289,11 +408,11 @@ impl Parser for Literal {
                 content.push(c);
             }
             input.advance();
-            count += 1;
         }
         Quote::parse(input)?;
 
-        Ok(Literal::new(span.with_width(count), content))
+        // The span covers the quotes, and is in bytes like every other.
+        Ok(Literal::new(Span::new(start, input.curr_span().start), content))
     }
 }
 
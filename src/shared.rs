@@ -0,0 +1,10 @@
+//! The reference counted pointer used for everything expressions share, like
+//! macros and literals. With the `parallel` feature it is `Arc`, so that
+//! expressions can be sent to other threads, and `Rc` otherwise, which is
+//! cheaper.
+
+#[cfg(not(feature = "parallel"))]
+pub use std::rc::Rc;
+
+#[cfg(feature = "parallel")]
+pub use std::sync::Arc as Rc;
@@ -0,0 +1,137 @@
+//! The children of an `Expr` are either owned, like with `Box`, or shared
+//! with `Rc`. Shared children are copied on write: mutating one through
+//! `DerefMut` first makes a copy of that node, whose own children are still
+//! shared. Substitution uses this to put the same argument in several places
+//! without copying it for each of them.
+
+use std::collections::HashSet;
+use std::ops::{ Deref, DerefMut };
+
+use crate::interpreter::Expr;
+use crate::shared::Rc;
+
+pub enum SubExpr {
+    Owned(Box<Expr>),
+    /// Copying a shared node to mutate it also copies its owned children,
+    /// but not its shared ones. `Expr::clone_shared` shares all of them.
+    Shared(Rc<Expr>),
+}
+
+impl SubExpr {
+    pub fn new(expr: Expr) -> SubExpr {
+        SubExpr::Owned(Box::new(expr))
+    }
+
+    /// Takes the expression out, copying it if it is shared somewhere else.
+    pub fn into_inner(self) -> Expr {
+        match self {
+            SubExpr::Owned(expr)  => *expr,
+            SubExpr::Shared(expr) => Rc::try_unwrap(expr).unwrap_or_else(|expr| expr.as_ref().clone()),
+        }
+    }
+
+    pub fn is_shared(&self) -> bool {
+        matches!(self, SubExpr::Shared(_))
+    }
+}
+
+impl Expr {
+    /// Copies the expression into one where every child is shared, so that
+    /// cloning the copy, or any part of it, only copies a single node.
+    pub fn clone_shared(&self) -> Expr {
+        let share = |expr: &SubExpr| match expr {
+            SubExpr::Shared(expr) => SubExpr::Shared(Rc::clone(expr)),
+            SubExpr::Owned(expr)  => SubExpr::Shared(Rc::new(expr.clone_shared())),
+        };
+        match self {
+            Expr::Lambda { param, expr, name } => Expr::Lambda { param: *param, expr: share(expr), name: name.clone() },
+            Expr::Appl { f, arg }              => Expr::Appl { f: share(f), arg: share(arg) },
+            other                              => other.clone(),
+        }
+    }
+
+    /// Number of allocations the children of the expression take. A shared
+    /// node is only counted once, however many times it is used.
+    pub fn allocations(&self) -> usize {
+        let mut seen = HashSet::new();
+        let mut stack = vec![self];
+        while let Some(curr) = stack.pop() {
+            let children = match curr {
+                Expr::Lambda { expr, .. } => vec![expr],
+                Expr::Appl { f, arg }     => vec![f, arg],
+                _                         => vec![],
+            };
+            for child in children {
+                if seen.insert(&**child as *const Expr) {
+                    stack.push(child);
+                }
+            }
+        }
+        seen.len()
+    }
+}
+
+impl Deref for SubExpr {
+    type Target = Expr;
+
+    fn deref(&self) -> &Expr {
+        match self {
+            SubExpr::Owned(expr)  => expr,
+            SubExpr::Shared(expr) => expr,
+        }
+    }
+}
+
+impl DerefMut for SubExpr {
+    fn deref_mut(&mut self) -> &mut Expr {
+        match self {
+            SubExpr::Owned(expr)  => expr,
+            // Copies the node, unless this is the only reference to it.
+            SubExpr::Shared(expr) => Rc::make_mut(expr),
+        }
+    }
+}
+
+impl AsRef<Expr> for SubExpr {
+    fn as_ref(&self) -> &Expr {
+        self
+    }
+}
+
+impl From<Expr> for SubExpr {
+    fn from(expr: Expr) -> SubExpr {
+        SubExpr::new(expr)
+    }
+}
+
+impl Clone for SubExpr {
+    fn clone(&self) -> SubExpr {
+        match self {
+            SubExpr::Owned(expr)  => SubExpr::Owned(expr.clone()),
+            SubExpr::Shared(expr) => SubExpr::Shared(Rc::clone(expr)),
+        }
+    }
+}
+
+impl PartialEq for SubExpr {
+    fn eq(&self, other: &SubExpr) -> bool {
+        match (self, other) {
+            (SubExpr::Shared(a), SubExpr::Shared(b)) if Rc::ptr_eq(a, b) => true,
+            _ => **self == **other,
+        }
+    }
+}
+
+impl Eq for SubExpr {}
+
+impl std::hash::Hash for SubExpr {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        (**self).hash(state);
+    }
+}
+
+impl std::fmt::Debug for SubExpr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        (**self).fmt(f)
+    }
+}
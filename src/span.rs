@@ -2,7 +2,8 @@ use std::fmt;
 
 const DUMMY_SPAN: Span = Span { start: 0, end: 0 };
 
-#[derive(PartialEq, Eq, Clone, Copy)]
+#[derive(PartialEq, Eq, Hash, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct Span {
     pub start: usize,
     pub end: usize,
@@ -11,6 +12,9 @@ pub struct Span {
 impl Span {
     #[inline]
     pub fn new(start: usize, end: usize) -> Span {
+        // Everything else, like `contains`, relies on spans never being
+        // reversed.
+        debug_assert!(start <= end, "span starts at {} but ends at {}", start, end);
         Span { start, end }
     }
 
@@ -29,6 +33,8 @@ impl Span {
         Span { start: self.end - 1, end: self.end }
     }
 
+    /// Gets the smallest span covering both spans, that is, their union plus
+    /// anything between them.
     #[inline]
     pub fn merge(&self, other: Span) -> Span {
         use std::cmp::{ min, max };
@@ -38,14 +44,44 @@ impl Span {
         }
     }
 
+    /// Merges all of the spans into the smallest span covering every one of
+    /// them, or `None` if there are no spans.
+    pub fn merge_all<I: IntoIterator<Item = Span>>(spans: I) -> Option<Span> {
+        let mut spans = spans.into_iter();
+        let first = spans.next()?;
+        Some(spans.fold(first, |merged, span| merged.merge(span)))
+    }
+
+    /// Gets the part that is covered by both spans, or `None` if they don't
+    /// overlap. Spans that only touch, like `0..2` and `2..4`, intersect in an
+    /// empty span.
+    pub fn intersection(&self, other: Span) -> Option<Span> {
+        use std::cmp::{ min, max };
+        let start = max(self.start, other.start);
+        let end = min(self.end, other.end);
+        if start <= end {
+            Some(Span::new(start, end))
+        } else {
+            None
+        }
+    }
+
     #[inline]
     pub fn width(&self) -> usize {
         self.end - self.start
     }
 
+    /// Checks if the span has no width, that is, it is just a position.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.start >= self.end
+    }
+
+    /// Gets a span with the same start and the given width. The span may be
+    /// extended past its end, which happens when the span is empty, for
+    /// example at the end of the input.
     #[inline]
     pub fn with_width(&self, width: usize) -> Span {
-        assert!(self.width() >= width);
         Span { start: self.start, end: self.start + width }
     }
 
@@ -111,3 +147,79 @@ impl Ord for Span {
     }
 }
 
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_with_width() {
+        assert_eq!(Span::new(5, 5).with_width(0), Span::new(5, 5));
+        assert_eq!(Span::new(5, 5).with_width(3), Span::new(5, 8));
+        assert_eq!(Span::new(5, 10).with_width(2), Span::new(5, 7));
+    }
+
+    #[test]
+    fn test_is_empty() {
+        assert!(Span::new(3, 3).is_empty());
+        assert!(!Span::new(3, 4).is_empty());
+        assert!(!Span::new_start(3).is_empty());
+    }
+
+    #[test]
+    fn test_merge() {
+        assert_eq!(Span::new(2, 4).merge(Span::new(6, 9)), Span::new(2, 9));
+        assert_eq!(Span::new(6, 9).merge(Span::new(2, 4)), Span::new(2, 9));
+        assert_eq!(Span::new(2, 9).merge(Span::new(3, 4)), Span::new(2, 9));
+    }
+
+    #[test]
+    fn test_merge_all() {
+        assert_eq!(Span::merge_all(Vec::new()), None);
+        assert_eq!(Span::merge_all(vec![Span::new(3, 4)]), Some(Span::new(3, 4)));
+        let spans = vec![Span::new(6, 9), Span::new(2, 4), Span::new(3, 5)];
+        assert_eq!(Span::merge_all(spans), Some(Span::new(2, 9)));
+    }
+
+    #[test]
+    fn test_intersection() {
+        // Overlapping
+        assert_eq!(Span::new(2, 6).intersection(Span::new(4, 9)), Some(Span::new(4, 6)));
+        assert_eq!(Span::new(4, 9).intersection(Span::new(2, 6)), Some(Span::new(4, 6)));
+        // Disjoint
+        assert_eq!(Span::new(2, 4).intersection(Span::new(6, 9)), None);
+        assert_eq!(Span::new(2, 4).intersection(Span::new(4, 9)), Some(Span::new(4, 4)));
+        // Nested
+        assert_eq!(Span::new(2, 9).intersection(Span::new(3, 5)), Some(Span::new(3, 5)));
+        assert_eq!(Span::new(3, 5).intersection(Span::new(2, 9)), Some(Span::new(3, 5)));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "span starts at 5 but ends at 3")]
+    fn test_reversed_span() {
+        Span::new(5, 3);
+    }
+
+    #[test]
+    fn test_width_and_contains() {
+        assert_eq!(Span::new(3, 7).width(), 4);
+        assert_eq!(Span::new(3, 3).width(), 0);
+        assert!(Span::new(3, 7).contains(Span::new(4, 5)));
+        assert!(Span::new(3, 7).contains(Span::new(3, 7)));
+        assert!(!Span::new(3, 7).contains(Span::new(6, 8)));
+    }
+
+    #[test]
+    fn test_start_end() {
+        assert_eq!(Span::new(3, 7).start(), Span::new(3, 4));
+        assert_eq!(Span::new(3, 7).end(), Span::new(6, 7));
+
+        // Both are one byte wide, even for spans that are narrower, so that
+        // errors pointing at them are always visible.
+        assert_eq!(Span::new(3, 4).start(), Span::new(3, 4));
+        assert_eq!(Span::new(3, 4).end(), Span::new(3, 4));
+        assert_eq!(Span::new(3, 3).start(), Span::new(3, 4));
+        assert_eq!(Span::new(3, 7).start(), Span::new_start(3));
+    }
+}
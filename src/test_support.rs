@@ -0,0 +1,77 @@
+//! Helpers to make failing tests on expressions easier to debug.
+
+use crate::interpreter::Expr;
+
+impl Expr {
+    /// Asserts that both expressions are alpha-equivalent. On failure, the
+    /// panic message shows both expressions and the first subterms where they
+    /// diverge.
+    #[track_caller]
+    pub fn assert_alpha_eq(&self, other: &Expr) {
+        if let Some(msg) = self.alpha_diff(other) {
+            panic!("{}", msg);
+        }
+    }
+
+    /// Describes how the two expressions differ, or `None` if they are
+    /// alpha-equivalent.
+    pub fn alpha_diff(&self, other: &Expr) -> Option<String> {
+        let left = self.pure_alpha_convert();
+        let right = other.pure_alpha_convert();
+        let (left_sub, right_sub) = first_divergence(&left, &right)?;
+
+        Some(format!(
+            "expressions are not alpha-equivalent\n  \
+              left: {}\n \
+             right: {}\n\
+             first divergence at `{}` and `{}`",
+            left, right, left_sub, right_sub,
+        ))
+    }
+}
+
+// Walks both expressions in the same order and returns the first pair of
+// subterms that don't match.
+fn first_divergence<'a>(left: &'a Expr, right: &'a Expr) -> Option<(&'a Expr, &'a Expr)> {
+    match (left, right) {
+        (Expr::Appl { f: lf, arg: larg }, Expr::Appl { f: rf, arg: rarg }) => {
+            first_divergence(lf, rf).or_else(|| first_divergence(larg, rarg))
+        },
+        (
+            Expr::Lambda { param: lparam, expr: lexpr, .. },
+            Expr::Lambda { param: rparam, expr: rexpr, .. },
+        ) if lparam == rparam => first_divergence(lexpr, rexpr),
+        _ if left == right => None,
+        _                  => Some((left, right)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::compiler::compile_program;
+
+    fn compile(input: &str) -> Expr {
+        compile_program(input).unwrap().expr
+    }
+
+    #[test]
+    fn test_alpha_eq() {
+        compile("\\a. \\b. a b").assert_alpha_eq(&compile("\\x. \\y. x y"));
+    }
+
+    #[test]
+    fn test_alpha_diff_names_divergence() {
+        let left = compile("\\a. \\b. a (b \"x\")");
+        let right = compile("\\a. \\b. a (b \"y\")");
+        let msg = left.alpha_diff(&right).unwrap();
+        assert!(msg.contains("first divergence at `x` and `y`"), "{}", msg);
+    }
+
+    #[test]
+    #[should_panic(expected = "not alpha-equivalent")]
+    fn test_assert_alpha_eq_fails() {
+        compile("\\a. \\b. a").assert_alpha_eq(&compile("\\a. \\b. b"));
+    }
+}